@@ -0,0 +1,12 @@
+use vfs_service::services::{FnService, StaticService};
+use vfs_service::{run, SingleService};
+
+fn main() {
+    let planets = StaticService::new("planets")
+        .with("mars", "Mars is red and has 2 moons")
+        .with("earth", "Earth is blue and has 1 moon");
+    let echo = FnService::new("echo", |query| vec![query.unwrap_or_default().to_string()]);
+    let svcs: Vec<Box<dyn SingleService + Send>> = vec![Box::new(planets), Box::new(echo)];
+
+    run(svcs);
+}
@@ -1,4 +1,3 @@
-use reqwest;
 use serde::Deserialize;
 use std::fmt;
 use vfs_service::SingleService;
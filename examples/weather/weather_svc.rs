@@ -1,4 +1,3 @@
-use reqwest;
 use serde::Deserialize;
 use std::fmt;
 use vfs_service::SingleService;
@@ -47,10 +46,7 @@ impl SingleService for WeatherService {
 
     fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
         dotenv().ok();
-        let zip = match query {
-            Some(q) => q,
-            None => "10002",
-        };
+        let zip = query.unwrap_or("10002");
 
         let appid = env::var("WEATHER_KEY").unwrap().to_string();
         let url = format!(
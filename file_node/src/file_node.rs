@@ -1,12 +1,9 @@
+// a regular file; its bytes live in the store's content store, keyed by ino
 #[derive(Debug, Clone)]
-pub struct FileNode {
-    pub content: Vec<u8>,
-}
+pub struct FileNode {}
 
 impl FileNode {
     pub fn new() -> FileNode {
-        FileNode {
-            content: Vec::new(),
-        }
+        FileNode {}
     }
 }
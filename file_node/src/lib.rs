@@ -1,6 +1,7 @@
 mod file_node;
 mod node_data;
+pub mod plugin;
 mod regular_dir_node;
 mod service_node;
 pub use node_data::{gen_dir_node, gen_file_node, DirNode, NodeData};
-pub use service_node::{ServiceDirNode, SingleService};
+pub use service_node::{ServiceConfig, ServiceDirNode, SingleService};
@@ -3,6 +3,9 @@ use crate::regular_dir_node::RegularDirNode;
 use crate::service_node::ServiceDirNode;
 use std::ffi::OsStr;
 
+// readdir offsets 1 and 2 are taken by "." and ".."
+pub const FIRST_COOKIE: u64 = 3;
+
 pub trait DirNode {
     fn remove(&mut self, id: &u64, name: &OsStr);
     fn add(&mut self, id: u64, name: std::ffi::OsString);
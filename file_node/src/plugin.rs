@@ -0,0 +1,332 @@
+// the C ABI of services built as separate cdylibs. a plugin exports
+// VFS_PLUGIN_ABI, the ABI_VERSION it was built against, and vfs_plugin,
+// which hands out a PluginDecl; export_service! writes both
+use std::convert::TryInto;
+use std::ffi::{c_int, c_void};
+use std::io;
+use std::time::Duration;
+
+use crate::SingleService;
+
+// bumped whenever PluginDecl or anything it passes changes
+pub const ABI_VERSION: u32 = 3;
+pub const ABI_SYMBOL: &[u8] = b"VFS_PLUGIN_ABI\0";
+pub const DECL_SYMBOL: &[u8] = b"vfs_plugin\0";
+// what max_age answers for a file kept until refreshed
+pub const NO_MAX_AGE: u64 = u64::MAX;
+// libc's, which this crate doesn't depend on
+const EIO: c_int = 5;
+
+// bytes owned by the plugin; they go back to it through free_buf
+#[repr(C)]
+pub struct PluginBuf {
+    pub ptr: *mut u8,
+    pub len: usize,
+    pub cap: usize,
+}
+
+impl PluginBuf {
+    pub fn from_vec(bytes: Vec<u8>) -> PluginBuf {
+        let mut bytes = std::mem::ManuallyDrop::new(bytes);
+        PluginBuf {
+            ptr: bytes.as_mut_ptr(),
+            len: bytes.len(),
+            cap: bytes.capacity(),
+        }
+    }
+
+    /// # Safety
+    /// self must come from from_vec in the same library
+    pub unsafe fn into_vec(self) -> Vec<u8> {
+        Vec::from_raw_parts(self.ptr, self.len, self.cap)
+    }
+
+    /// # Safety
+    /// ptr must still point at len bytes
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        if self.ptr.is_null() {
+            return &[];
+        }
+        std::slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+// what vfs_plugin returns. create makes one service; the others take what
+// it returned. a fetch answers with its lines joined by '\n', and a null
+// query is fetch_data(None)
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PluginDecl {
+    pub create: unsafe extern "C" fn() -> *mut c_void,
+    pub name: unsafe extern "C" fn(svc: *const c_void) -> PluginBuf,
+    pub fetch:
+        unsafe extern "C" fn(svc: *const c_void, query: *const u8, query_len: usize) -> PluginBuf,
+    pub free_buf: unsafe extern "C" fn(buf: PluginBuf),
+    pub destroy: unsafe extern "C" fn(svc: *mut c_void),
+    // the rest of SingleService. a plugin leaving one None gets what a
+    // service does by default
+    pub list: Option<unsafe extern "C" fn(svc: *const c_void) -> PluginBuf>,
+    // 0 when taken, an errno otherwise
+    pub write_back: Option<
+        unsafe extern "C" fn(
+            svc: *const c_void,
+            query: *const u8,
+            query_len: usize,
+            data: *const u8,
+            data_len: usize,
+        ) -> c_int,
+    >,
+    // in nanoseconds, NO_MAX_AGE for none
+    pub max_age:
+        Option<unsafe extern "C" fn(svc: *const c_void, query: *const u8, query_len: usize) -> u64>,
+    // as put by encode_pairs
+    pub attributes: Option<
+        unsafe extern "C" fn(svc: *const c_void, query: *const u8, query_len: usize) -> PluginBuf,
+    >,
+    pub dirs: Option<unsafe extern "C" fn(svc: *const c_void) -> PluginDirs>,
+}
+
+// the services of a service's dirs, made by it rather than by create, and
+// used through decl. svcs holds their pointers
+#[repr(C)]
+pub struct PluginDirs {
+    pub svcs: PluginBuf,
+    pub decl: PluginDecl,
+}
+
+// each name and value as its length, 8 bytes little endian, then its bytes
+pub fn encode_pairs(pairs: &[(String, String)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for field in pairs.iter().flat_map(|(name, value)| [name, value]) {
+        out.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        out.extend_from_slice(field.as_bytes());
+    }
+    out
+}
+
+// undoes encode_pairs, stopping at anything cut short
+pub fn decode_pairs(mut bytes: &[u8]) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    while bytes.len() >= 8 {
+        let (len, rest) = bytes.split_at(8);
+        let len = u64::from_le_bytes(len.try_into().unwrap_or_default()) as usize;
+        if rest.len() < len {
+            break;
+        }
+        let (field, rest) = rest.split_at(len);
+        fields.push(String::from_utf8_lossy(field).into_owned());
+        bytes = rest;
+    }
+
+    let mut fields = fields.into_iter();
+    let mut pairs = Vec::new();
+    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+        pairs.push((name, value));
+    }
+    pairs
+}
+
+// a service known only as a SingleService, such as one of a dir, so the
+// shims can be made for it
+pub struct DynService(pub Box<dyn SingleService + Send>);
+
+impl SingleService for DynService {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        self.0.fetch_data(query)
+    }
+
+    fn get_name(&self) -> String {
+        self.0.get_name()
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.0.list()
+    }
+
+    fn write_back(&self, query: &str, data: &[u8]) -> io::Result<()> {
+        self.0.write_back(query, data)
+    }
+
+    fn max_age(&self, query: &str) -> Option<Duration> {
+        self.0.max_age(query)
+    }
+
+    fn attributes(&self, query: &str) -> Vec<(String, String)> {
+        self.0.attributes(query)
+    }
+
+    fn dirs(&self) -> Vec<Box<dyn SingleService + Send>> {
+        self.0.dirs()
+    }
+}
+
+// the functions export_service! puts in a PluginDecl for a service type S.
+// a panic in the service answers with nothing rather than unwinding into
+// the mount
+pub mod shim {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    /// # Safety
+    /// svc must come from create for S
+    pub unsafe extern "C" fn name<S: SingleService>(svc: *const c_void) -> PluginBuf {
+        let svc = &*(svc as *const S);
+        let name = catch_unwind(AssertUnwindSafe(|| svc.get_name())).unwrap_or_default();
+        PluginBuf::from_vec(name.into_bytes())
+    }
+
+    unsafe fn text(ptr: *const u8, len: usize) -> Option<String> {
+        if ptr.is_null() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(std::slice::from_raw_parts(ptr, len)).into_owned())
+    }
+
+    /// # Safety
+    /// svc must come from create for S, and query be null or point at
+    /// query_len bytes
+    pub unsafe extern "C" fn fetch<S: SingleService>(
+        svc: *const c_void,
+        query: *const u8,
+        query_len: usize,
+    ) -> PluginBuf {
+        let svc = &*(svc as *const S);
+        let query = text(query, query_len);
+        let lines = catch_unwind(AssertUnwindSafe(|| svc.fetch_data(query.as_deref())));
+        PluginBuf::from_vec(lines.unwrap_or_default().join("\n").into_bytes())
+    }
+
+    /// # Safety
+    /// svc must come from create for S
+    pub unsafe extern "C" fn list<S: SingleService>(svc: *const c_void) -> PluginBuf {
+        let svc = &*(svc as *const S);
+        let queries = catch_unwind(AssertUnwindSafe(|| svc.list()));
+        PluginBuf::from_vec(queries.unwrap_or_default().join("\n").into_bytes())
+    }
+
+    /// # Safety
+    /// svc must come from create for S, query point at query_len bytes and
+    /// data at data_len
+    pub unsafe extern "C" fn write_back<S: SingleService>(
+        svc: *const c_void,
+        query: *const u8,
+        query_len: usize,
+        data: *const u8,
+        data_len: usize,
+    ) -> c_int {
+        let svc = &*(svc as *const S);
+        let query = text(query, query_len).unwrap_or_default();
+        let data = if data.is_null() {
+            &[][..]
+        } else {
+            std::slice::from_raw_parts(data, data_len)
+        };
+        match catch_unwind(AssertUnwindSafe(|| svc.write_back(&query, data))) {
+            Ok(Ok(())) => 0,
+            Ok(Err(e)) => e.raw_os_error().unwrap_or(EIO),
+            Err(_) => EIO,
+        }
+    }
+
+    /// # Safety
+    /// svc must come from create for S, and query point at query_len bytes
+    pub unsafe extern "C" fn max_age<S: SingleService>(
+        svc: *const c_void,
+        query: *const u8,
+        query_len: usize,
+    ) -> u64 {
+        let svc = &*(svc as *const S);
+        let query = text(query, query_len).unwrap_or_default();
+        match catch_unwind(AssertUnwindSafe(|| svc.max_age(&query))) {
+            // as long as it gets is as good as forever
+            Ok(Some(age)) => age.as_nanos().min(NO_MAX_AGE as u128 - 1) as u64,
+            _ => NO_MAX_AGE,
+        }
+    }
+
+    /// # Safety
+    /// svc must come from create for S, and query point at query_len bytes
+    pub unsafe extern "C" fn attributes<S: SingleService>(
+        svc: *const c_void,
+        query: *const u8,
+        query_len: usize,
+    ) -> PluginBuf {
+        let svc = &*(svc as *const S);
+        let query = text(query, query_len).unwrap_or_default();
+        let pairs = catch_unwind(AssertUnwindSafe(|| svc.attributes(&query)));
+        PluginBuf::from_vec(encode_pairs(&pairs.unwrap_or_default()))
+    }
+
+    /// # Safety
+    /// svc must come from create for S
+    pub unsafe extern "C" fn dirs<S: SingleService>(svc: *const c_void) -> PluginDirs {
+        let svc = &*(svc as *const S);
+        let dirs = catch_unwind(AssertUnwindSafe(|| svc.dirs())).unwrap_or_default();
+        let svcs: Vec<u8> = dirs
+            .into_iter()
+            .map(|dir| Box::into_raw(Box::new(DynService(dir))) as usize)
+            .flat_map(usize::to_ne_bytes)
+            .collect();
+        PluginDirs {
+            svcs: PluginBuf::from_vec(svcs),
+            decl: decl::<DynService>(made_elsewhere),
+        }
+    }
+
+    // create for services only ever made by another, such as dirs
+    unsafe extern "C" fn made_elsewhere() -> *mut c_void {
+        std::ptr::null_mut()
+    }
+
+    // every shim for S, with create making one
+    pub fn decl<S: SingleService>(create: unsafe extern "C" fn() -> *mut c_void) -> PluginDecl {
+        PluginDecl {
+            create,
+            name: name::<S>,
+            fetch: fetch::<S>,
+            free_buf,
+            destroy: destroy::<S>,
+            list: Some(list::<S>),
+            write_back: Some(write_back::<S>),
+            max_age: Some(max_age::<S>),
+            attributes: Some(attributes::<S>),
+            dirs: Some(dirs::<S>),
+        }
+    }
+
+    /// # Safety
+    /// buf must come from this library and not be freed before
+    pub unsafe extern "C" fn free_buf(buf: PluginBuf) {
+        drop(buf.into_vec());
+    }
+
+    /// # Safety
+    /// svc must come from create for S and not be used after
+    pub unsafe extern "C" fn destroy<S: SingleService>(svc: *mut c_void) {
+        let svc = Box::from_raw(svc as *mut S);
+        // a panicking drop leaks what it didn't get to
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(svc)));
+    }
+}
+
+// exports the plugin symbols for a SingleService type, made by calling make
+#[macro_export]
+macro_rules! export_service {
+    ($svc:ty, $make:expr) => {
+        #[no_mangle]
+        pub static VFS_PLUGIN_ABI: u32 = $crate::plugin::ABI_VERSION;
+
+        #[no_mangle]
+        pub extern "C" fn vfs_plugin() -> $crate::plugin::PluginDecl {
+            extern "C" fn create() -> *mut ::std::ffi::c_void {
+                ::std::panic::catch_unwind(|| {
+                    let svc: $svc = ($make)();
+                    Box::into_raw(Box::new(svc)) as *mut ::std::ffi::c_void
+                })
+                .unwrap_or(::std::ptr::null_mut())
+            }
+
+            $crate::plugin::shim::decl::<$svc>(create)
+        }
+    };
+}
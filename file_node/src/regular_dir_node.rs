@@ -1,4 +1,4 @@
-use crate::node_data::DirNode;
+use crate::node_data::{DirNode, FIRST_COOKIE};
 use std::collections;
 use std::ffi::{OsStr, OsString};
 
@@ -6,6 +6,9 @@ use std::ffi::{OsStr, OsString};
 pub struct RegularDirNode {
     pub children: collections::BTreeSet<u64>,
     pub name_map: collections::HashMap<OsString, u64>,
+    // readdir cookie of every name, handed out in the order names are added
+    pub cookies: collections::HashMap<OsString, u64>,
+    next_cookie: u64,
 }
 
 impl RegularDirNode {
@@ -13,18 +16,26 @@ impl RegularDirNode {
         RegularDirNode {
             children: collections::BTreeSet::new(),
             name_map: collections::HashMap::new(),
+            cookies: collections::HashMap::new(),
+            next_cookie: FIRST_COOKIE,
         }
     }
 }
 
 impl DirNode for RegularDirNode {
     fn remove(&mut self, id: &u64, name: &OsStr) {
-        self.children.remove(id);
         self.name_map.remove(name);
+        self.cookies.remove(name);
+        // a hard linked file can sit in one dir under several names
+        if !self.name_map.values().any(|other| other == id) {
+            self.children.remove(id);
+        }
     }
 
     fn add(&mut self, id: u64, name: std::ffi::OsString) {
         self.children.insert(id);
+        self.cookies.insert(name.clone(), self.next_cookie);
+        self.next_cookie += 1;
         self.name_map.insert(name, id);
     }
 }
@@ -1,42 +1,124 @@
+use crate::node_data::{DirNode, FIRST_COOKIE};
 use std::collections;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Debug, Clone)]
-pub struct BundleServiceDirNode {
-    pub children: collections::BTreeSet<u64>,
-    pub name_map: collections::HashMap<OsString, u64>,
-}
-
-pub trait SingleService {
+// services are shared by every thread serving the mount, so fetches for
+// different files can run at the same time. services with state that
+// isn't Sync, like a RefCell or a client needing &mut, keep it in a Mutex
+pub trait SingleService: Send + Sync {
     fn fetch_data(&self, query: Option<&str>) -> Vec<String>;
     fn get_name(&self) -> String;
+
+    // the queries the service knows it can answer; listing its dir fetches
+    // the ones not there yet
+    fn list(&self) -> Vec<String> {
+        vec![]
+    }
+
+    // takes what was written to the file fetched for query once it is
+    // closed. an error fails the close; by default writes stay local
+    fn write_back(&self, _query: &str, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    // how long the file fetched for query stays fresh; opening it later
+    // fetches it again. by default files are kept until refreshed
+    fn max_age(&self, _query: &str) -> Option<Duration> {
+        None
+    }
+
+    // extended attributes for the file fetched for query, asked for right
+    // after every fetch of it
+    fn attributes(&self, _query: &str) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    // services for dirs inside this one's, each named after its service;
+    // they get this service's config. asked again on every listing, and
+    // only the dirs not there yet are added
+    fn dirs(&self) -> Vec<Box<dyn SingleService + Send>> {
+        vec![]
+    }
 }
 
-impl std::fmt::Debug for dyn SingleService + 'static + Send {
+impl std::fmt::Debug for dyn SingleService + 'static {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "it worked")
     }
 }
 
+// per-service settings handed over at registration
+#[derive(Debug, Clone, Default)]
+pub struct ServiceConfig {
+    // empty lists leave the service open to everyone
+    pub allow_uids: Vec<u32>,
+    pub allow_gids: Vec<u32>,
+    // users can't write, rename or remove anything under the service;
+    // files are still fetched from the service itself
+    pub read_only: bool,
+    // how many fetches of each file to keep under .history; none when 0
+    pub history: usize,
+}
+
+impl ServiceConfig {
+    pub fn permits(&self, uid: u32, gid: u32) -> bool {
+        if self.allow_uids.is_empty() && self.allow_gids.is_empty() {
+            return true;
+        }
+
+        uid == 0 || self.allow_uids.contains(&uid) || self.allow_gids.contains(&gid)
+    }
+}
+
 #[derive(Debug)]
 pub struct ServiceDirNode {
     pub children: collections::BTreeSet<u64>,
     pub name_map: collections::HashMap<OsString, u64>,
-    pub service: Box<dyn SingleService + Send>,
+    // readdir cookie of every name, handed out in the order names are added
+    pub cookies: collections::HashMap<OsString, u64>,
+    next_cookie: u64,
+    pub service: Arc<dyn SingleService>,
+    pub config: ServiceConfig,
 }
 
 impl ServiceDirNode {
     pub fn new(service: Box<dyn SingleService + Send>) -> ServiceDirNode {
+        ServiceDirNode::with_config(service, ServiceConfig::default())
+    }
+
+    pub fn with_config(
+        service: Box<dyn SingleService + Send>,
+        config: ServiceConfig,
+    ) -> ServiceDirNode {
         ServiceDirNode {
             children: collections::BTreeSet::new(),
             name_map: collections::HashMap::new(),
-            service,
+            cookies: collections::HashMap::new(),
+            next_cookie: FIRST_COOKIE,
+            service: Arc::<dyn SingleService + Send>::from(service),
+            config,
+        }
+    }
+}
+
+impl DirNode for ServiceDirNode {
+    fn remove(&mut self, id: &u64, name: &OsStr) {
+        self.name_map.remove(name);
+        self.cookies.remove(name);
+        // a hard linked file can sit in one dir under several names
+        if !self.name_map.values().any(|other| other == id) {
+            self.children.remove(id);
         }
     }
 
-    pub fn add(&mut self, id: u64, name: std::ffi::OsString) {
+    fn add(&mut self, id: u64, name: std::ffi::OsString) {
         self.children.insert(id);
+        self.cookies.insert(name.clone(), self.next_cookie);
+        self.next_cookie += 1;
         self.name_map.insert(name, id);
     }
 }
@@ -0,0 +1,868 @@
+use libc::{c_int, EFBIG, EIO, ENOENT, ENOSPC};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{
+    Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+};
+use std::{fs, io};
+use time::Timespec;
+
+const SHARDS: u64 = 16;
+
+// files are cut into chunks of this size at fixed offsets, so equal
+// stretches of equal files land in equal chunks
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+// a panic on another thread shouldn't take the whole mount down with it
+pub(crate) fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock<T>(lock: &Mutex<T>) -> MutexGuard<'_, T> {
+    lock.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// None when someone else holds the lock
+fn try_write_lock<T>(lock: &RwLock<T>) -> Option<RwLockWriteGuard<'_, T>> {
+    match lock.try_write() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+// somewhere file bytes go when they don't fit in memory
+pub trait Spill: Send + Sync {
+    fn put(&self, ino: u64, data: &[u8]) -> io::Result<()>;
+    fn get(&self, ino: u64) -> io::Result<Vec<u8>>;
+    fn remove(&self, ino: u64);
+}
+
+// spills into one file per inode under a cache dir
+#[derive(Debug)]
+pub struct DirSpill {
+    dir: PathBuf,
+}
+
+impl DirSpill {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<DirSpill> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        Ok(DirSpill { dir })
+    }
+
+    fn path(&self, ino: u64) -> PathBuf {
+        self.dir.join(ino.to_string())
+    }
+}
+
+impl Spill for DirSpill {
+    fn put(&self, ino: u64, data: &[u8]) -> io::Result<()> {
+        fs::write(self.path(ino), data)
+    }
+
+    fn get(&self, ino: u64) -> io::Result<Vec<u8>> {
+        fs::read(self.path(ino))
+    }
+
+    fn remove(&self, ino: u64) {
+        if let Err(e) = fs::remove_file(self.path(ino)) {
+            log::error!("could not remove spilled {}: {}", ino, e);
+        }
+    }
+}
+
+// gets a service file's bytes again after they were dropped
+pub type Refetch = Arc<dyn Fn() -> Vec<u8> + Send + Sync>;
+
+// bytes shared by every file that holds them
+struct Chunk {
+    data: Arc<[u8]>,
+    refs: u64,
+}
+
+// a file's bytes as the keys of the chunks holding them, in order. all but
+// the last chunk are full
+#[derive(Debug, Default)]
+struct Chunks {
+    keys: Vec<u64>,
+    len: u64,
+}
+
+// where a file's bytes are right now; the lengths are kept so stat never
+// has to page anything in. offloaded files are counted as the bytes that
+// letting go of their chunks freed, as chunks still shared stay resident
+#[derive(Debug)]
+enum Body {
+    Memory(Chunks),
+    Spilled { len: u64, counted: u64 },
+    Dropped { len: u64, counted: u64 },
+}
+
+impl Body {
+    fn len(&self) -> u64 {
+        match self {
+            Body::Memory(chunks) => chunks.len,
+            Body::Spilled { len, .. } | Body::Dropped { len, .. } => *len,
+        }
+    }
+
+    // bytes counted as offloaded
+    fn counted(&self) -> u64 {
+        match self {
+            Body::Memory(_) => 0,
+            Body::Spilled { counted, .. } | Body::Dropped { counted, .. } => *counted,
+        }
+    }
+}
+
+struct Content {
+    body: Body,
+    mtime: Timespec,
+    // a tick of the store's clock, bumped on every use, for picking what
+    // to evict
+    last_used: AtomicU64,
+    // set while the bytes are exactly what the service returned, cleared
+    // once anybody writes to them
+    refetch: Option<Refetch>,
+}
+
+// a file's bytes held on to for another file, see freeze
+pub struct Frozen {
+    chunks: Chunks,
+    mtime: Timespec,
+}
+
+type Shard = RwLock<HashMap<u64, Arc<RwLock<Content>>>>;
+type ChunkShard = Mutex<HashMap<u64, Chunk>>;
+
+fn chunk_hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+// file bytes, kept apart from the inode table. every file has its own lock
+// and the shards are only held long enough to find it, so reads and writes
+// to different files run side by side.
+//
+// files are stored as content addressed chunks, counted by how many files
+// hold them: equal content is kept once and a copy costs nothing until it
+// is changed. only the chunks that change are written.
+//
+// chunks held in memory are kept under a budget: past it, the least
+// recently used files are evicted. service content is dropped and fetched
+// again when next read, anything else goes to the spill, if there is one
+pub struct ContentStore {
+    shards: Vec<Shard>,
+    chunks: Vec<ChunkShard>,
+    // bytes of the chunks in memory
+    resident: AtomicU64,
+    // bytes of files that are spilled or dropped
+    offloaded: AtomicU64,
+    capacity: AtomicU64,
+    budget: AtomicU64,
+    clock: AtomicU64,
+    spill: Option<Box<dyn Spill>>,
+}
+
+impl ContentStore {
+    pub fn new(capacity: u64) -> ContentStore {
+        ContentStore {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+            chunks: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            resident: AtomicU64::new(0),
+            offloaded: AtomicU64::new(0),
+            capacity: AtomicU64::new(capacity),
+            budget: AtomicU64::new(u64::MAX),
+            clock: AtomicU64::new(0),
+            spill: None,
+        }
+    }
+
+    fn shard(&self, ino: &u64) -> &Shard {
+        &self.shards[(ino % SHARDS) as usize]
+    }
+
+    fn chunk_shard(&self, key: u64) -> &ChunkShard {
+        &self.chunks[(key % SHARDS) as usize]
+    }
+
+    fn entry(&self, ino: &u64) -> Option<Arc<RwLock<Content>>> {
+        read_lock(self.shard(ino)).get(ino).cloned()
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // takes a reference to the chunk holding data, adding it if it is new.
+    // returns its key and how many bytes that added. keys are the hash of
+    // the data, moved along on the rare collision
+    fn intern(&self, data: &[u8]) -> (u64, u64) {
+        let mut key = chunk_hash(data);
+        loop {
+            let mut shard = lock(self.chunk_shard(key));
+            match shard.get_mut(&key) {
+                Some(chunk) if *chunk.data == *data => {
+                    chunk.refs += 1;
+                    return (key, 0);
+                }
+                Some(_) => key = key.wrapping_add(1),
+                None => {
+                    let chunk = Chunk {
+                        data: Arc::from(data),
+                        refs: 1,
+                    };
+                    shard.insert(key, chunk);
+                    self.resident.fetch_add(data.len() as u64, Ordering::SeqCst);
+                    return (key, data.len() as u64);
+                }
+            }
+        }
+    }
+
+    fn intern_all(&self, data: &[u8]) -> (Vec<u64>, u64) {
+        let mut added = 0;
+        let keys = data
+            .chunks(CHUNK_SIZE)
+            .map(|chunk| {
+                let (key, bytes) = self.intern(chunk);
+                added += bytes;
+                key
+            })
+            .collect();
+
+        (keys, added)
+    }
+
+    // drops a reference to a chunk, freeing it with the last one
+    fn release(&self, key: u64) {
+        let mut shard = lock(self.chunk_shard(key));
+        if let Some(chunk) = shard.get_mut(&key) {
+            chunk.refs -= 1;
+            if chunk.refs == 0 {
+                let len = chunk.data.len() as u64;
+                shard.remove(&key);
+                self.resident.fetch_sub(len, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn retain(&self, key: u64) {
+        if let Some(chunk) = lock(self.chunk_shard(key)).get_mut(&key) {
+            chunk.refs += 1;
+        }
+    }
+
+    fn release_all(&self, keys: &[u64]) {
+        for key in keys {
+            self.release(*key);
+        }
+    }
+
+    // how many bytes releasing keys would free
+    fn freeable(&self, keys: &[u64]) -> u64 {
+        let mut held: HashMap<u64, u64> = HashMap::new();
+        for key in keys {
+            *held.entry(*key).or_default() += 1;
+        }
+
+        held.iter()
+            .filter_map(|(key, count)| {
+                let shard = lock(self.chunk_shard(*key));
+                let chunk = shard.get(key)?;
+                Some(chunk.data.len() as u64).filter(|_| chunk.refs == *count)
+            })
+            .sum()
+    }
+
+    fn chunk(&self, key: u64) -> Option<Arc<[u8]>> {
+        lock(self.chunk_shard(key))
+            .get(&key)
+            .map(|chunk| chunk.data.clone())
+    }
+
+    // the bytes of chunks between from and to
+    fn bytes(&self, chunks: &Chunks, from: u64, to: u64) -> Result<Vec<u8>, c_int> {
+        let to = to.min(chunks.len) as usize;
+        let from = (from as usize).min(to);
+        let mut out = Vec::with_capacity(to - from);
+        for idx in from / CHUNK_SIZE..to.div_ceil(CHUNK_SIZE) {
+            let data = self.chunk(chunks.keys[idx]).ok_or(EIO)?;
+            let base = idx * CHUNK_SIZE;
+            let start = from.max(base) - base;
+            let end = to.min(base + data.len()) - base;
+            out.extend_from_slice(&data[start..end]);
+        }
+
+        Ok(out)
+    }
+
+    // replaces chunks from..to with keys, already interned and having
+    // added that many bytes, leaving the file new_len long. fails with
+    // nothing changed when growing past capacity
+    fn splice(
+        &self,
+        chunks: &mut Chunks,
+        from: usize,
+        to: usize,
+        keys: Vec<u64>,
+        added: u64,
+        new_len: u64,
+    ) -> Result<(), c_int> {
+        let old: Vec<u64> = chunks.keys[from..to].to_vec();
+        let freed = self.freeable(&old);
+        if added > freed && self.used().saturating_sub(freed) > self.capacity() {
+            self.release_all(&keys);
+            return Err(ENOSPC);
+        }
+
+        chunks.keys.splice(from..to, keys);
+        chunks.len = new_len;
+        self.release_all(&old);
+        Ok(())
+    }
+
+    fn write_chunks(&self, chunks: &mut Chunks, offset: u64, data: &[u8]) -> Result<(), c_int> {
+        let len = chunks.len as usize;
+        let offset = offset as usize;
+        let end = offset.checked_add(data.len()).ok_or(EFBIG)?;
+        let new_len = len.max(end);
+        // however well it dedups, growing by more than the whole capacity
+        // is refused before anything is built for it
+        if (new_len - len) as u64 > self.capacity() {
+            return Err(ENOSPC);
+        }
+
+        // rewrite whole chunks, from the one where the write (or the gap
+        // before it) starts to the one where it ends
+        let from = offset.min(len) / CHUNK_SIZE;
+        let stop = (end.div_ceil(CHUNK_SIZE) * CHUNK_SIZE).min(new_len);
+        let to = stop.min(len).div_ceil(CHUNK_SIZE);
+
+        // one chunk at a time, so a far offset doesn't build its gap in
+        // memory: the gap's whole chunks all share the one chunk of zeros
+        let mut keys = Vec::with_capacity(stop.div_ceil(CHUNK_SIZE) - from);
+        let mut added = 0;
+        let mut zeros = None;
+        for base in (from * CHUNK_SIZE..stop).step_by(CHUNK_SIZE) {
+            let top = (base + CHUNK_SIZE).min(stop);
+            if base >= len && top <= offset && top - base == CHUNK_SIZE {
+                let key = match zeros {
+                    Some(key) => {
+                        self.retain(key);
+                        key
+                    }
+                    None => {
+                        let (key, bytes) = self.intern(&[0; CHUNK_SIZE]);
+                        added += bytes;
+                        key
+                    }
+                };
+                zeros = Some(key);
+                keys.push(key);
+                continue;
+            }
+
+            let mut buf = match self.bytes(chunks, base as u64, top as u64) {
+                Ok(buf) => buf,
+                Err(e) => {
+                    self.release_all(&keys);
+                    return Err(e);
+                }
+            };
+            buf.resize(top - base, 0);
+            let (lo, hi) = (offset.max(base), end.min(top));
+            if lo < hi {
+                buf[lo - base..hi - base].copy_from_slice(&data[lo - offset..hi - offset]);
+            }
+            let (key, bytes) = self.intern(&buf);
+            keys.push(key);
+            added += bytes;
+        }
+
+        self.splice(chunks, from, to, keys, added, new_len as u64)
+    }
+
+    fn truncate_chunks(&self, chunks: &mut Chunks, size: u64) -> Result<(), c_int> {
+        if size >= chunks.len {
+            return self.write_chunks(chunks, size, &[]);
+        }
+
+        let from = size as usize / CHUNK_SIZE;
+        let tail = self.bytes(chunks, (from * CHUNK_SIZE) as u64, size)?;
+        let to = chunks.keys.len();
+        let (keys, added) = self.intern_all(&tail);
+        self.splice(chunks, from, to, keys, added, size)
+    }
+
+    // brings a file's spilled bytes back into memory; dropped ones are
+    // fetched again by with_loaded, without the file locked
+    fn load(&self, ino: &u64, content: &mut Content) -> Result<(), c_int> {
+        let data = match content.body {
+            Body::Memory(_) => return Ok(()),
+            Body::Spilled { .. } => match &self.spill {
+                Some(spill) => {
+                    let data = spill.get(*ino).map_err(|e| {
+                        log::error!("could not page in {}: {}", ino, e);
+                        EIO
+                    })?;
+                    spill.remove(*ino);
+                    data
+                }
+                None => return Err(EIO),
+            },
+            Body::Dropped { .. } => return Err(EIO),
+        };
+
+        self.install(content, data);
+        Ok(())
+    }
+
+    // gives an offloaded file data to hold in memory
+    fn install(&self, content: &mut Content, data: Vec<u8>) {
+        let (keys, _) = self.intern_all(&data);
+        self.offloaded
+            .fetch_sub(content.body.counted(), Ordering::SeqCst);
+        content.body = Body::Memory(Chunks {
+            keys,
+            len: data.len() as u64,
+        });
+    }
+
+    // whether entry is still the one ino is stored under; one replaced or
+    // removed meanwhile has had its bytes let go of already
+    fn is_current(&self, ino: &u64, entry: &Arc<RwLock<Content>>) -> bool {
+        self.entry(ino).is_some_and(|now| Arc::ptr_eq(&now, entry))
+    }
+
+    // runs f on a file's content with its bytes in memory. dropped service
+    // content is fetched again first with nothing locked, as a service can
+    // be slow, and only lands if the file is still the one that was dropped
+    fn with_loaded<T, F>(&self, ino: &u64, f: F) -> Result<T, c_int>
+    where
+        F: FnOnce(&mut Content) -> Result<T, c_int>,
+    {
+        let mut fetched: Option<(Refetch, Vec<u8>)> = None;
+        loop {
+            let entry = self.entry(ino).ok_or(ENOENT)?;
+            let mut content = write_lock(&entry);
+            if let Body::Dropped { .. } = content.body {
+                let refetch = content.refetch.clone().ok_or(EIO)?;
+                match fetched.take() {
+                    Some((by, data))
+                        if Arc::ptr_eq(&by, &refetch) && self.is_current(ino, &entry) =>
+                    {
+                        self.install(&mut content, data);
+                    }
+                    // a service may answer differently the second time
+                    _ => {
+                        drop(content);
+                        fetched = Some((refetch.clone(), refetch()));
+                        continue;
+                    }
+                }
+            }
+
+            self.load(ino, &mut content)?;
+            return f(&mut content);
+        }
+    }
+
+    // lets go of a file's chunks if there is somewhere else for its bytes
+    fn evict(&self, ino: &u64, content: &mut Content) {
+        let chunks = match &content.body {
+            Body::Memory(chunks) => chunks,
+            _ => return,
+        };
+        let (len, counted) = (chunks.len, self.freeable(&chunks.keys));
+        let body = if content.refetch.is_some() {
+            Body::Dropped { len, counted }
+        } else if let Some(spill) = &self.spill {
+            let spilled = self
+                .bytes(chunks, 0, chunks.len)
+                .map_err(io::Error::from_raw_os_error)
+                .and_then(|data| spill.put(*ino, &data));
+            if let Err(e) = spilled {
+                log::error!("could not spill {}: {}", ino, e);
+                return;
+            }
+            Body::Spilled { len, counted }
+        } else {
+            return;
+        };
+
+        self.offloaded.fetch_add(counted, Ordering::SeqCst);
+        self.release_all(&chunks.keys);
+        content.body = body;
+    }
+
+    // evicts the least recently used files until the resident bytes are
+    // within budget. files locked by someone else are in use, so skipped.
+    // a file sharing all its chunks frees nothing, so more may go
+    fn trim(&self) {
+        if self.resident() <= self.budget() {
+            return;
+        }
+
+        let mut cold: Vec<(u64, u64, Arc<RwLock<Content>>)> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                read_lock(shard)
+                    .iter()
+                    .map(|(ino, entry)| (*ino, entry.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .filter_map(|(ino, entry)| {
+                let used = match entry.try_read() {
+                    Ok(content) => content.last_used.load(Ordering::SeqCst),
+                    Err(_) => return None,
+                };
+                Some((used, ino, entry))
+            })
+            .collect();
+        cold.sort_by_key(|(used, ino, _)| (*used, *ino));
+
+        for (_, ino, entry) in cold {
+            if self.resident() <= self.budget() {
+                break;
+            }
+            if let Some(mut content) = try_write_lock(&entry) {
+                self.evict(&ino, &mut content);
+            }
+        }
+    }
+
+    fn put(&self, ino: u64, data: Vec<u8>, refetch: Option<Refetch>) {
+        let (keys, _) = self.intern_all(&data);
+        let content = Content {
+            body: Body::Memory(Chunks {
+                keys,
+                len: data.len() as u64,
+            }),
+            mtime: time::get_time(),
+            last_used: AtomicU64::new(self.tick()),
+            refetch,
+        };
+        self.replace(ino, content);
+    }
+
+    fn replace(&self, ino: u64, content: Content) {
+        let old = write_lock(self.shard(&ino)).insert(ino, Arc::new(RwLock::new(content)));
+        if let Some(old) = old {
+            self.discard(&ino, &read_lock(&old));
+        }
+
+        self.trim();
+    }
+
+    // service fetches always land, even past the capacity
+    pub fn insert(&self, ino: u64, data: Vec<u8>) {
+        self.put(ino, data, None);
+    }
+
+    // like insert, for bytes that can be fetched again instead of spilled
+    pub fn insert_fetched(&self, ino: u64, data: Vec<u8>, refetch: Refetch) {
+        self.put(ino, data, Some(refetch));
+    }
+
+    // makes to hold the bytes of from, sharing every chunk. the copy is its
+    // own from then on, so it is spilled rather than fetched again
+    pub fn copy(&self, from: &u64, to: u64) -> Result<(), c_int> {
+        let frozen = self.freeze(from)?;
+        self.thaw(to, frozen);
+        Ok(())
+    }
+
+    // takes hold of a file's bytes as they are now, sharing their chunks.
+    // service content that was dropped is fetched again first, so what is
+    // held is never the service's answer at some later time
+    pub fn freeze(&self, from: &u64) -> Result<Frozen, c_int> {
+        self.with_loaded(from, |content| match &content.body {
+            Body::Memory(chunks) => {
+                for key in &chunks.keys {
+                    self.retain(*key);
+                }
+                Ok(Frozen {
+                    chunks: Chunks {
+                        keys: chunks.keys.clone(),
+                        len: chunks.len,
+                    },
+                    mtime: content.mtime,
+                })
+            }
+            _ => Err(EIO),
+        })
+    }
+
+    // gives frozen bytes to ino
+    pub fn thaw(&self, to: u64, frozen: Frozen) {
+        let content = Content {
+            body: Body::Memory(frozen.chunks),
+            mtime: frozen.mtime,
+            last_used: AtomicU64::new(self.tick()),
+            refetch: None,
+        };
+        self.replace(to, content);
+    }
+
+    // lets go of frozen bytes no file was given
+    pub fn unfreeze(&self, frozen: Frozen) {
+        self.release_all(&frozen.chunks.keys);
+    }
+
+    fn discard(&self, ino: &u64, content: &Content) {
+        match &content.body {
+            Body::Memory(chunks) => self.release_all(&chunks.keys),
+            Body::Spilled { counted, .. } => {
+                self.offloaded.fetch_sub(*counted, Ordering::SeqCst);
+                if let Some(spill) = &self.spill {
+                    spill.remove(*ino);
+                }
+            }
+            Body::Dropped { counted, .. } => {
+                self.offloaded.fetch_sub(*counted, Ordering::SeqCst);
+            }
+        }
+    }
+
+    pub fn remove(&self, ino: &u64) {
+        if let Some(old) = write_lock(self.shard(ino)).remove(ino) {
+            self.discard(ino, &read_lock(&old));
+        }
+    }
+
+    pub fn read(&self, ino: &u64, offset: u64, size: u64) -> Result<Vec<u8>, c_int> {
+        let entry = self.entry(ino).ok_or(ENOENT)?;
+        let end = offset.saturating_add(size);
+
+        {
+            let content = read_lock(&entry);
+            content.last_used.store(self.tick(), Ordering::SeqCst);
+            if let Body::Memory(chunks) = &content.body {
+                return self.bytes(chunks, offset, end);
+            }
+        }
+
+        let data = self.with_loaded(ino, |content| match &content.body {
+            Body::Memory(chunks) => self.bytes(chunks, offset, end),
+            _ => Err(EIO),
+        })?;
+        self.trim();
+        Ok(data)
+    }
+
+    // runs change on a file's chunks in memory; its bytes stop being the
+    // service's once changed
+    fn modify<F>(&self, ino: &u64, change: F) -> Result<(), c_int>
+    where
+        F: FnOnce(&mut Chunks) -> Result<(), c_int>,
+    {
+        self.with_loaded(ino, |content| {
+            if let Body::Memory(chunks) = &mut content.body {
+                change(chunks)?;
+            }
+            content.refetch = None;
+            content.mtime = time::get_time();
+            content.last_used.store(self.tick(), Ordering::SeqCst);
+            Ok(())
+        })?;
+
+        self.trim();
+        Ok(())
+    }
+
+    pub fn write(&self, ino: &u64, offset: u64, data: &[u8]) -> Result<(), c_int> {
+        self.modify(ino, |chunks| self.write_chunks(chunks, offset, data))
+    }
+
+    pub fn truncate(&self, ino: &u64, size: u64) -> Result<(), c_int> {
+        self.modify(ino, |chunks| self.truncate_chunks(chunks, size))
+    }
+
+    // how to get a file's bytes back, if they were dropped
+    pub fn dropped(&self, ino: &u64) -> Option<Refetch> {
+        let entry = self.entry(ino)?;
+        let content = read_lock(&entry);
+        match content.body {
+            Body::Dropped { .. } => content.refetch.clone(),
+            _ => None,
+        }
+    }
+
+    // every dropped file and how to get its bytes back
+    pub fn dropped_files(&self) -> Vec<(u64, Refetch)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                read_lock(shard)
+                    .iter()
+                    .map(|(ino, entry)| (*ino, entry.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .filter_map(|(ino, entry)| {
+                let content = read_lock(&entry);
+                match content.body {
+                    Body::Dropped { .. } => Some((ino, content.refetch.clone()?)),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    // puts back what refetch got for a dropped file, for callers that fetch
+    // with their own locks let go of. a file written, refreshed or dropped
+    // from another service meanwhile keeps what it has
+    pub fn restore(&self, ino: &u64, refetch: &Refetch, data: Vec<u8>) {
+        let entry = match self.entry(ino) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let mut content = write_lock(&entry);
+        let same = content
+            .refetch
+            .as_ref()
+            .is_some_and(|now| Arc::ptr_eq(now, refetch));
+        if let Body::Dropped { .. } = content.body {
+            if same && self.is_current(ino, &entry) {
+                self.install(&mut content, data);
+                content.last_used.store(self.tick(), Ordering::SeqCst);
+            }
+        }
+    }
+
+    pub fn set_mtime(&self, ino: &u64, mtime: Timespec) -> Result<(), c_int> {
+        let entry = self.entry(ino).ok_or(ENOENT)?;
+        write_lock(&entry).mtime = mtime;
+        Ok(())
+    }
+
+    // length and last modification of a file's bytes
+    pub fn stat(&self, ino: &u64) -> Option<(u64, Timespec)> {
+        let entry = self.entry(ino)?;
+        let content = read_lock(&entry);
+        Some((content.body.len(), content.mtime))
+    }
+
+    // whether a file's bytes are in memory right now
+    pub fn is_resident(&self, ino: &u64) -> bool {
+        self.entry(ino)
+            .is_some_and(|entry| matches!(read_lock(&entry).body, Body::Memory(_)))
+    }
+
+    pub fn inos(&self) -> Vec<u64> {
+        self.shards
+            .iter()
+            .flat_map(|shard| read_lock(shard).keys().copied().collect::<Vec<_>>())
+            .collect()
+    }
+
+    // files sharing chunks are only counted once
+    pub fn used(&self) -> u64 {
+        self.resident() + self.offloaded.load(Ordering::SeqCst)
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity.load(Ordering::SeqCst)
+    }
+
+    pub fn set_capacity(&self, bytes: u64) {
+        self.capacity.store(bytes, Ordering::SeqCst);
+    }
+
+    // bytes of file content held in memory
+    pub fn resident(&self) -> u64 {
+        self.resident.load(Ordering::SeqCst)
+    }
+
+    pub fn budget(&self) -> u64 {
+        self.budget.load(Ordering::SeqCst)
+    }
+
+    pub fn set_budget(&self, bytes: u64) {
+        self.budget.store(bytes, Ordering::SeqCst);
+        self.trim();
+    }
+
+    pub fn set_spill(&mut self, spill: Box<dyn Spill>) {
+        self.spill = Some(spill);
+        self.trim();
+    }
+
+    // what's wrong with the chunk bookkeeping, if anything
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut held: HashMap<u64, u64> = HashMap::new();
+        let mut offloaded = 0;
+        for ino in self.inos() {
+            let entry = match self.entry(&ino) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let content = read_lock(&entry);
+            offloaded += content.body.counted();
+            let chunks = match &content.body {
+                Body::Memory(chunks) => chunks,
+                _ => continue,
+            };
+
+            let mut len = 0;
+            for (i, key) in chunks.keys.iter().enumerate() {
+                *held.entry(*key).or_default() += 1;
+                match self.chunk(*key) {
+                    Some(data) if data.len() != CHUNK_SIZE && i + 1 < chunks.keys.len() => {
+                        problems.push(format!("file {} has a short chunk {}", ino, key));
+                    }
+                    Some(data) => len += data.len() as u64,
+                    None => problems.push(format!("file {} holds missing chunk {}", ino, key)),
+                }
+            }
+            if len != chunks.len {
+                problems.push(format!(
+                    "file {} has {} bytes of chunks for {}",
+                    ino, len, chunks.len
+                ));
+            }
+        }
+
+        let mut bytes = 0;
+        for shard in &self.chunks {
+            for (key, chunk) in lock(shard).iter() {
+                bytes += chunk.data.len() as u64;
+                let count = held.get(key).copied().unwrap_or(0);
+                if count != chunk.refs {
+                    problems.push(format!(
+                        "chunk {} has {} refs but is held {} times",
+                        key, chunk.refs, count
+                    ));
+                }
+            }
+        }
+        if bytes != self.resident() {
+            problems.push(format!(
+                "{} bytes of chunks counted as {}",
+                bytes,
+                self.resident()
+            ));
+        }
+        if offloaded != self.offloaded.load(Ordering::SeqCst) {
+            problems.push(format!(
+                "{} offloaded bytes counted as {}",
+                offloaded,
+                self.offloaded.load(Ordering::SeqCst)
+            ));
+        }
+
+        problems
+    }
+}
@@ -1,17 +1,154 @@
+use crate::content::{ContentStore, Frozen, Refetch, Spill};
+use crate::history::{self, History};
 use crate::inode::Inode;
+use fuse::FileAttr;
+use libc::{
+    c_int, EACCES, EEXIST, EINVAL, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, EPERM, EROFS, EXDEV,
+};
 use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Arc, Mutex, PoisonError};
 use std::{collections, path};
-use time;
 
 extern crate file_node;
 
-use file_node::{gen_dir_node, gen_file_node, DirNode, NodeData, ServiceDirNode, SingleService};
+use file_node::{
+    gen_dir_node, gen_file_node, DirNode, NodeData, ServiceConfig, ServiceDirNode, SingleService,
+};
+
+const BLOCK_SIZE: u32 = 512;
+const NAME_LEN: u32 = 255;
+const MAX_INODES: u64 = 1 << 20;
+const DEFAULT_CAPACITY: u64 = 1 << 30;
+// the kernel has already applied the caller's umask to the modes it hands
+// over, so nothing more is masked unless asked for
+const DEFAULT_UMASK: u32 = 0;
+// service dirs and their files get inos hashed from their names in
+// [SERVICE_INO_BASE, 2 * SERVICE_INO_BASE), so they survive a restart.
+// regular inos count up from 2 and never get near it
+const SERVICE_INO_BASE: u64 = 1 << 61;
+// where snapshots of the tree appear, under the root
+pub const SNAPSHOTS: &str = ".snapshots";
+// where past fetches of a service's files appear, in the service dir
+pub const HISTORY: &str = ".history";
+// the diff of all kept fetches, next to them
+const CHANGES: &str = "changes";
+// where the files to inspect and drive the mount appear, under the root
+pub const CONTROL: &str = ".vfs";
+// the dir of a service's control files is named after it in this one
+pub const SERVICE_CONTROLS: &str = "services";
+
+#[derive(Debug, Clone, Copy)]
+pub struct StoreStats {
+    pub block_size: u32,
+    pub name_len: u32,
+    pub blocks: u64,
+    pub blocks_free: u64,
+    pub used_bytes: u64,
+    pub files: u64,
+    pub files_free: u64,
+}
+
+fn stamp(at: time::Timespec) -> String {
+    match time::strftime("%Y-%m-%dT%H:%M:%SZ", &time::at_utc(at)) {
+        Ok(stamp) => stamp,
+        Err(_) => at.sec.to_string(),
+    }
+}
+
+// whether a service may put name in its dir: one path component, and not
+// the dir its history is kept in
+pub fn service_name(name: &OsStr) -> bool {
+    !(name.is_empty()
+        || name == "."
+        || name == ".."
+        || name == HISTORY
+        || name.as_bytes().contains(&b'/'))
+}
+
+fn blocks_for(bytes: u64) -> u64 {
+    bytes.div_ceil(BLOCK_SIZE as u64)
+}
+
+// fnv-1a, since std's hashers aren't guaranteed stable between releases
+fn stable_hash(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for byte in part.iter().chain(&[0]) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    hash
+}
+
+// what a snapshot will hold, see plan_snapshot
+pub struct SnapshotPlan {
+    nodes: Vec<Planned>,
+}
+
+enum Planned {
+    // parent is the index of the node's dir in the plan, or None for the
+    // top of the snapshot
+    Node {
+        parent: Option<usize>,
+        name: OsString,
+        attr: Box<FileAttr>,
+        xattr: collections::HashMap<OsString, String>,
+        // the bytes of a file; dirs have none
+        content: Option<Frozen>,
+    },
+    // another name for the planned file at of
+    Link {
+        parent: Option<usize>,
+        name: OsString,
+        of: usize,
+    },
+}
 
-const UID: u32 = 1000;
-const GID: u32 = 1000;
 pub struct FileStore {
     file_table: collections::HashMap<u64, Inode>,
     ino_ctr: u64,
+    // freed inos are handed out again when this is set
+    reuse_inos: bool,
+    free_inos: collections::BTreeSet<u64>,
+    // last generation of every freed ino that may come back
+    generations: collections::HashMap<u64, u64>,
+    // how many times the kernel has been told about an ino; it can't be
+    // reused until the kernel forgets it
+    lookups: Mutex<collections::HashMap<u64, u64>>,
+    // atimes of inodes looked up since their attr was last changed, kept
+    // aside so lookups only need the store shared
+    accessed: Mutex<collections::HashMap<u64, time::Timespec>>,
+    // service files written to since they were last handed back
+    written: Mutex<collections::HashSet<u64>>,
+    // service dirs can only be rmdir'd when this is set
+    allow_service_removal: bool,
+    // file bytes, behind their own locks so they can be read and written
+    // without exclusive access to the store
+    contents: ContentStore,
+    // the dir snapshots are listed in, once made
+    snapshots: Option<u64>,
+    // every inode inside a snapshot; none of them can be changed
+    frozen: collections::HashSet<u64>,
+    // kept fetches by service dir and file name
+    history: collections::HashMap<(u64, OsString), History>,
+    // the dir control files are in, once made
+    control: Option<u64>,
+    // every control file by its path under the control dir
+    controls: collections::HashMap<u64, String>,
+    umask: u32,
+    // owner of root and of the service dirs: whoever mounted the store
+    uid: u32,
+    gid: u32,
+    read_only: bool,
+}
+
+impl Default for FileStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FileStore {
@@ -19,11 +156,28 @@ impl FileStore {
         let mut f = FileStore {
             file_table: collections::HashMap::new(),
             ino_ctr: 2,
+            reuse_inos: false,
+            free_inos: collections::BTreeSet::new(),
+            generations: collections::HashMap::new(),
+            lookups: Mutex::new(collections::HashMap::new()),
+            accessed: Mutex::new(collections::HashMap::new()),
+            written: Mutex::new(collections::HashSet::new()),
+            allow_service_removal: false,
+            contents: ContentStore::new(DEFAULT_CAPACITY),
+            snapshots: None,
+            frozen: collections::HashSet::new(),
+            history: collections::HashMap::new(),
+            control: None,
+            controls: collections::HashMap::new(),
+            umask: DEFAULT_UMASK,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            read_only: false,
         };
 
         let node_data = gen_dir_node();
         let name = OsStr::new("root");
-        let node = Inode::new(fuse::FUSE_ROOT_ID, node_data, &name, UID, GID);
+        let node = Inode::new(fuse::FUSE_ROOT_ID, node_data, name, f.uid, f.gid);
 
         f.file_table.insert(1, node);
 
@@ -32,25 +186,219 @@ impl FileStore {
 
     pub fn register_services(&mut self, svcs: Vec<Box<dyn SingleService + Send>>) {
         for svc in svcs {
-            let n = svc.get_name();
-            let name = OsStr::new(&n).clone();
-            let node = ServiceDirNode::new(svc);
-            let svc_node = NodeData::ServiceDir(node);
-            let one = 1;
+            self.register_service(svc, ServiceConfig::default());
+        }
+    }
 
-            self.add_child(&one, svc_node, OsStr::new(name));
+    pub fn register_service(&mut self, svc: Box<dyn SingleService + Send>, config: ServiceConfig) {
+        let name = svc.get_name();
+        if let Err(e) = self.add_service(svc, config) {
+            log::error!("could not register service {:?}: {}", name, e);
+        }
+    }
+
+    // adds a dir under the root for svc, named after it, along with the
+    // dirs of svc.dirs() inside it
+    pub fn add_service(
+        &mut self,
+        svc: Box<dyn SingleService + Send>,
+        config: ServiceConfig,
+    ) -> Result<u64, c_int> {
+        let dirs = svc.dirs();
+        let id = self.add_service_dir(&fuse::FUSE_ROOT_ID, svc, config.clone())?;
+        for dir in dirs {
+            let name = dir.get_name();
+            if let Err(e) = self.add_service_dir(&id, dir, config.clone()) {
+                log::error!("could not add service dir {:?}: {}", name, e);
+            }
+        }
+        Ok(id)
+    }
+
+    // adds a dir under parent for svc, named after it
+    pub fn add_service_dir(
+        &mut self,
+        parent: &u64,
+        svc: Box<dyn SingleService + Send>,
+        config: ServiceConfig,
+    ) -> Result<u64, c_int> {
+        let n = svc.get_name();
+        let name = OsStr::new(&n);
+        if !service_name(name) {
+            return Err(EINVAL);
+        }
+        let node = ServiceDirNode::with_config(svc, config);
+        let svc_node = NodeData::ServiceDir(node);
+        let (uid, gid) = (self.uid, self.gid);
+
+        let id = self.add_child(parent, svc_node, name, uid, gid, 0o755)?;
+        // anyone may fetch into a service dir; its ServiceConfig decides who.
+        // it is sticky so users can only remove what they fetched
+        if let Some(node) = self.file_table.get_mut(&id) {
+            node.attr.perm = 0o1777;
+        }
+        Ok(id)
+    }
+
+    // takes a service dir out of the tree with everything fetched into it
+    // and its history, whether or not users may rmdir it
+    pub fn remove_service(&mut self, name: &OsStr) -> Result<(), c_int> {
+        let root = fuse::FUSE_ROOT_ID;
+        let id = self.resolve_path(&root, name).ok_or(ENOENT)?;
+        if !self.is_service_dir(&id) {
+            return Err(EINVAL);
+        }
+
+        let children: Vec<OsString> = self
+            .dir_entries(&id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(_, name)| name.to_os_string())
+            .collect();
+        for child in children {
+            self.remove_tree(id, &child)?;
+        }
+        self.history.retain(|(dir, _), _| *dir != id);
+        self.remove_child(&root, name).ok_or(ENOENT)?;
+        self.remove(&id);
+        Ok(())
+    }
+
+    // the service dir governing ino, if ino is one or lives directly in one.
+    // a service's control files and their dir are governed by it too
+    fn service_for(&self, ino: &u64) -> Option<&ServiceDirNode> {
+        let node = self.get(ino)?;
+        if let NodeData::ServiceDir(dir) = &node.data {
+            return Some(dir);
+        }
+
+        match &self.get(&node.parent())?.data {
+            NodeData::ServiceDir(dir) => Some(dir),
+            _ => self.controlled_service(ino),
+        }
+    }
+
+    // the service whose controls are ino or are in it, from the name of
+    // their dir under /.vfs/services
+    fn controlled_service(&self, ino: &u64) -> Option<&ServiceDirNode> {
+        let services = self.resolve_path(&self.control?, OsStr::new(SERVICE_CONTROLS))?;
+        let node = self.get(ino)?;
+        let dir = match node.parent() {
+            parent if parent == services => *ino,
+            parent if self.get(&parent)?.parent() == services => parent,
+            _ => return None,
+        };
+        let name = self.name_in(&services, &dir)?;
+        let svc = self.resolve_path(&fuse::FUSE_ROOT_ID, name)?;
+        match &self.get(&svc)?.data {
+            NodeData::ServiceDir(dir) => Some(dir),
+            _ => None,
+        }
+    }
+
+    pub fn service_permits(&self, ino: &u64, uid: u32, gid: u32) -> bool {
+        self.service_for(ino)
+            .is_none_or(|dir| dir.config.permits(uid, gid))
+    }
+
+    pub fn check_service_access(&self, ino: &u64, uid: u32, gid: u32) -> Result<(), c_int> {
+        match self.service_for(ino) {
+            Some(dir) if !dir.config.permits(uid, gid) => {
+                log::error!(
+                    "service access denied: svc={} path={:?} uid={} gid={}",
+                    dir.service.get_name(),
+                    self.full_path(ino),
+                    uid,
+                    gid
+                );
+                Err(EACCES)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set_service_removal(&mut self, allowed: bool) {
+        self.allow_service_removal = allowed;
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    // every setting that can be changed at runtime, as (name, value)
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        let budget = match self.contents.budget() {
+            u64::MAX => "none".to_string(),
+            bytes => bytes.to_string(),
+        };
+
+        vec![
+            ("read_only", self.read_only.to_string()),
+            ("umask", format!("{:03o}", self.umask)),
+            ("capacity", self.contents.capacity().to_string()),
+            ("memory_budget", budget),
+            ("ino_reuse", self.reuse_inos.to_string()),
+            ("service_removal", self.allow_service_removal.to_string()),
+            ("snapshots", self.snapshots.is_some().to_string()),
+        ]
+    }
+
+    // (dir ino, name) of every registered service
+    pub fn service_dirs(&self) -> Vec<(u64, OsString)> {
+        self.dir_entries(&fuse::FUSE_ROOT_ID)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(id, _)| self.is_service_dir(id))
+            .map(|(id, name)| (id, name.to_os_string()))
+            .collect()
+    }
+
+    pub fn service_config(&self, ino: &u64) -> Option<&ServiceConfig> {
+        match &self.get(ino)?.data {
+            NodeData::ServiceDir(dir) => Some(&dir.config),
+            _ => None,
+        }
+    }
+
+    pub fn is_service_dir(&self, ino: &u64) -> bool {
+        match self.get(ino) {
+            Some(node) => matches!(node.data, NodeData::ServiceDir(_)),
+            None => false,
+        }
+    }
+
+    // fails with EROFS on a read only mount. for /.snapshots, which users
+    // can't otherwise modify but make and remove snapshots in
+    pub fn check_mount_writable(&self) -> Result<(), c_int> {
+        if self.read_only {
+            return Err(EROFS);
+        }
+
+        Ok(())
+    }
+
+    // fails with EROFS when users may not modify ino or its entries.
+    // control files take writes even on a read only mount
+    pub fn check_writable(&self, ino: &u64) -> Result<(), c_int> {
+        if self.controls.contains_key(ino) {
+            return Ok(());
+        }
+        if self.read_only || self.frozen.contains(ino) || self.snapshots == Some(*ino) {
+            return Err(EROFS);
+        }
+
+        match self.service_for(ino) {
+            Some(dir) if dir.config.read_only => Err(EROFS),
+            _ => Ok(()),
         }
     }
 
     pub fn _add_node(&mut self, _parent: &u64, node: &Inode, path: OsString) {
-        self.file_table
-            .entry(node.id)
-            .and_modify(|parent| match &mut parent.data {
-                NodeData::RegularDir(dir) => {
-                    dir.add(node.id, path);
-                }
-                _ => (),
-            });
+        self.file_table.entry(node.id).and_modify(|parent| {
+            if let NodeData::RegularDir(dir) = &mut parent.data {
+                dir.add(node.id, path);
+            }
+        });
     }
 
     pub fn rename(
@@ -59,84 +407,247 @@ impl FileStore {
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
-    ) -> Result<u64, u64> {
+    ) -> Result<u64, c_int> {
         log::error!("{:?} {:?} {:?} {:?}", parent, name, newparent, newname);
 
-        let mut result_id = 0;
-        match self.remove_child(parent, name) {
-            Some(id) => {
-                self.file_table.entry(id).and_modify(|node| {
-                    node.path = path::Path::new(newname).to_path_buf();
-                });
-
-                let path = newname.to_os_string();
-                self.file_table
-                    .entry(newparent)
-                    .and_modify(|par| match &mut par.data {
-                        NodeData::RegularDir(dir) => {
-                            dir.add(id, path);
-                            result_id = id;
-                        }
-                        NodeData::ServiceDir(_) => {
-                            log::error!("can't rename svc directory: {:?} {:?}", parent, name);
-                        }
-                        NodeData::File(file) => {
-                            log::error!("new parent a file and not a valid directory: {:?}", file)
-                        }
-                    });
+        let id = self.resolve_path(parent, name).ok_or(ENOENT)?;
+        // service dirs are found by their name, so they keep it
+        if self.is_fixed(&id) || self.is_service_dir(&id) {
+            log::error!("refusing to move {:?}", name);
+            return Err(EPERM);
+        }
+        match self.get(&newparent).map(|node| &node.data) {
+            Some(NodeData::RegularDir(_)) => (),
+            Some(NodeData::ServiceDir(_)) => {
+                log::error!("can't rename into svc directory: {:?} {:?}", parent, name);
+                return Err(EPERM);
             }
+            Some(NodeData::File(_)) => return Err(ENOTDIR),
+            None => return Err(ENOENT),
+        }
+        // a dir can't be moved underneath itself
+        if self.is_ancestor(&id, &newparent) {
+            return Err(EINVAL);
+        }
 
-            None => log::error!("can't delete file: {:?}", name),
+        if let Some(target) = self.resolve_path(&newparent, newname) {
+            if target == id {
+                return Ok(id);
+            }
+            let moving_dir = !matches!(self.get(&id).map(|n| &n.data), Some(NodeData::File(_)));
+            match self.get(&target).map(|node| &node.data) {
+                Some(NodeData::File(_)) if moving_dir => return Err(ENOTDIR),
+                Some(NodeData::File(_)) => self.unlink(&newparent, newname)?,
+                Some(_) if !moving_dir => return Err(EISDIR),
+                Some(_) => self.rmdir(&newparent, newname)?,
+                None => return Err(ENOENT),
+            }
         }
-        if result_id > 0 {
-            Ok(result_id)
-        } else {
-            Err(0)
+
+        self.remove_child(parent, name).ok_or(ENOENT)?;
+        self.file_table.entry(id).and_modify(|node| {
+            node.path = path::Path::new(newname).to_path_buf();
+            if let Some(p) = node.parents.iter_mut().find(|p| **p == *parent) {
+                *p = newparent;
+            }
+        });
+
+        let path = newname.to_os_string();
+        if let Some(NodeData::RegularDir(dir)) =
+            self.file_table.get_mut(&newparent).map(|par| &mut par.data)
+        {
+            dir.add(id, path);
         }
+
+        Ok(id)
     }
 
-    pub fn write(&mut self, ino: u64, data: &[u8], flags: u32, offset: i64) -> u32 {
+    // true when ino is dir or sits somewhere below it
+    fn is_ancestor(&self, dir: &u64, ino: &u64) -> bool {
+        if dir == ino {
+            return true;
+        }
+
+        self.read_dir_children(dir)
+            .is_some_and(|children| children.iter().any(|child| self.is_ancestor(child, ino)))
+    }
+
+    pub fn write(&self, ino: u64, data: &[u8], flags: u32, offset: i64) -> Result<u32, c_int> {
         let str = String::from_utf8_lossy(data).trim().to_string();
 
-        let size = std::mem::size_of_val(&str.as_bytes());
+        let size = std::mem::size_of_val(str.as_bytes());
         log::error!("size={}", size);
         log::error!("write2: {} {:?} {}", ino, data, flags);
 
-        self.file_table.entry(ino).and_modify(|f| {
-            match &mut f.data {
-                NodeData::File(file) => {
-                    let now = time::get_time();
-                    let old = &file.content;
-                    let z: Vec<u8> = old.to_vec();
-                    let mut new = z.iter().take(offset as usize).cloned().collect::<Vec<_>>();
-
-                    new.extend(data.iter().cloned().collect::<Vec<_>>());
-
-                    new.extend(
-                        z.iter()
-                            .skip(offset as usize + data.len())
-                            .collect::<Vec<_>>(),
-                    );
-                    //let z: &[u8] = new.into();
-                    file.content = new;
-                    let d: &[u8] = &file.content;
-                    let s = d.len();
-                    f.attr.size = s as u64;
-                    f.attr.ctime = now;
-                    f.attr.atime = now;
-                }
-                _ => log::error!("oops"),
+        match self.get(&ino).map(|node| &node.data) {
+            Some(NodeData::File(_)) => (),
+            Some(_) => return Err(EISDIR),
+            None => return Err(ENOENT),
+        }
+        if let Err(e) = self.contents.write(&ino, offset as u64, data) {
+            log::error!("write failed: {} {}", ino, e);
+            return Err(e);
+        }
+        self.note_written(&ino);
+
+        Ok(size as u32)
+    }
+
+    pub fn set_umask(&mut self, umask: u32) {
+        self.umask = umask & 0o777;
+    }
+
+    pub fn check_access(&self, ino: &u64, uid: u32, gid: u32, mask: u32) -> Result<(), c_int> {
+        match self.get(ino) {
+            Some(node) if node.permits(uid, gid, mask) => Ok(()),
+            Some(node) => {
+                log::error!(
+                    "access denied: ino={} uid={} mask={:o} {:?}",
+                    ino,
+                    uid,
+                    mask,
+                    self.full_path(&node.id)
+                );
+                Err(EACCES)
             }
-        });
+            None => Err(ENOENT),
+        }
+    }
+
+    // in a sticky dir only root, the dir's owner and the entry's owner may
+    // remove or rename the entry
+    pub fn check_sticky(&self, parent: &u64, name: &OsStr, uid: u32) -> Result<(), c_int> {
+        let dir = self.get(parent).ok_or(ENOENT)?;
+        if dir.attr.perm & 0o1000 == 0 || uid == 0 || uid == dir.attr.uid {
+            return Ok(());
+        }
+
+        match self.resolve_path(parent, name).and_then(|id| self.get(&id)) {
+            Some(entry) if entry.attr.uid != uid => Err(EPERM),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn chmod(&mut self, ino: &u64, uid: u32, mode: u32) -> Result<(), c_int> {
+        let node = self.file_table.get_mut(ino).ok_or(ENOENT)?;
+        if uid != 0 && uid != node.attr.uid {
+            return Err(EPERM);
+        }
+
+        node.attr.perm = (mode & 0o7777) as u16;
+        node.attr.ctime = time::get_time();
+        Ok(())
+    }
+
+    // files keep their mtime with their bytes, everything else in its attr
+    pub fn set_times(
+        &mut self,
+        ino: &u64,
+        atime: Option<time::Timespec>,
+        mtime: Option<time::Timespec>,
+    ) -> Result<(), c_int> {
+        let node = self.file_table.get_mut(ino).ok_or(ENOENT)?;
+        if let Some(at) = atime {
+            node.attr.atime = at;
+            self.accessed
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(ino);
+        }
+        if let Some(at) = mtime {
+            node.attr.mtime = at;
+            if matches!(node.data, NodeData::File(_)) {
+                self.contents.set_mtime(ino, at)?;
+            }
+        }
+        node.attr.ctime = time::get_time();
+
+        Ok(())
+    }
+
+    // only root may give a file away; owners may change the group
+    pub fn chown(
+        &mut self,
+        ino: &u64,
+        uid: u32,
+        new_uid: Option<u32>,
+        new_gid: Option<u32>,
+    ) -> Result<(), c_int> {
+        let node = self.file_table.get_mut(ino).ok_or(ENOENT)?;
+        let changes_owner = new_uid.is_some_and(|u| u != node.attr.uid);
+        if uid != 0 && (changes_owner || uid != node.attr.uid) {
+            return Err(EPERM);
+        }
+
+        if let Some(u) = new_uid {
+            node.attr.uid = u;
+        }
+        if let Some(g) = new_gid {
+            node.attr.gid = g;
+        }
+        node.attr.ctime = time::get_time();
+        Ok(())
+    }
+
+    pub fn set_capacity(&mut self, bytes: u64) {
+        self.contents.set_capacity(bytes);
+    }
+
+    // caps the file bytes held in memory; past it the least recently used
+    // are dropped or spilled
+    pub fn set_memory_budget(&mut self, bytes: u64) {
+        self.contents.set_budget(bytes);
+    }
+
+    pub fn set_spill(&mut self, spill: Box<dyn Spill>) {
+        self.contents.set_spill(spill);
+    }
+
+    pub fn resident_bytes(&self) -> u64 {
+        self.contents.resident()
+    }
+
+    pub fn is_resident(&self, ino: &u64) -> bool {
+        self.contents.is_resident(ino)
+    }
 
-        let size = size as u32;
-        size
+    // how to fetch a file whose bytes were dropped again, for callers that
+    // do it without the store held; restore puts the bytes back after
+    pub fn dropped(&self, ino: &u64) -> Option<Refetch> {
+        self.contents.dropped(ino)
+    }
+
+    pub fn restore(&self, ino: &u64, refetch: &Refetch, data: Vec<u8>) {
+        self.contents.restore(ino, refetch, data);
+    }
+
+    pub fn dropped_files(&self) -> Vec<(u64, Refetch)> {
+        self.contents.dropped_files()
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.contents.used()
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let used = self.used_bytes();
+        let blocks = blocks_for(self.contents.capacity());
+        let files = self.file_table.len() as u64;
+
+        StoreStats {
+            block_size: BLOCK_SIZE,
+            name_len: NAME_LEN,
+            blocks,
+            blocks_free: blocks.saturating_sub(blocks_for(used)),
+            used_bytes: used,
+            files: MAX_INODES,
+            files_free: MAX_INODES.saturating_sub(files),
+        }
     }
 
     pub fn remove_child(&mut self, parent: &u64, name: &OsStr) -> Option<u64> {
-        let ino = self.resolve_path(parent, name).unwrap();
-        log::error!("about to unlink: {}", ino);
-        let id = ino.clone();
+        let id = self.resolve_path(parent, name)?;
+        log::error!("about to unlink: {}", id);
 
         let mut ok = false;
         self.file_table
@@ -146,6 +657,10 @@ impl FileStore {
                     dir.remove(&id, name);
                     ok = true;
                 }
+                NodeData::ServiceDir(dir) => {
+                    dir.remove(&id, name);
+                    ok = true;
+                }
                 _ => {
                     log::error!("can't rm file {:?}", parent);
                     ok = false;
@@ -159,18 +674,121 @@ impl FileStore {
         }
     }
 
-    pub fn unlink(&mut self, parent: &u64, name: &OsStr) {
-        match self.remove_child(parent, name) {
-            Some(i) => self.remove(&i),
-            None => log::error!("{:?}", self.file_table),
+    // unlink only removes non-directory entries; dirs go through rmdir
+    pub fn unlink(&mut self, parent: &u64, name: &OsStr) -> Result<(), c_int> {
+        let id = self.resolve_path(parent, name).ok_or(ENOENT)?;
+        match self.get(&id).map(|node| &node.data) {
+            Some(NodeData::File(_)) => (),
+            Some(_) => return Err(EISDIR),
+            None => return Err(ENOENT),
         }
+
+        self.remove_child(parent, name).ok_or(ENOENT)?;
+        let remaining = match self.file_table.get_mut(&id) {
+            Some(node) => {
+                if let Some(i) = node.parents.iter().position(|p| p == parent) {
+                    node.parents.remove(i);
+                }
+                node.attr.nlink = node.parents.len() as u32;
+                node.attr.ctime = time::get_time();
+                node.parents.first().copied()
+            }
+            None => None,
+        };
+
+        match remaining {
+            // keep the node named after a link that still exists
+            Some(other) => {
+                let name = self.name_in(&other, &id).map(path::PathBuf::from);
+                if let (Some(node), Some(name)) = (self.file_table.get_mut(&id), name) {
+                    node.path = name;
+                }
+            }
+            None => self.remove(&id),
+        }
+        Ok(())
     }
 
-    pub fn create_dir(&mut self, parent: u64, name: &OsStr, _mode: u32) -> Option<&Inode> {
+    // adds another name for a file; dirs can't be hard linked
+    pub fn link(&mut self, ino: &u64, newparent: &u64, newname: &OsStr) -> Result<(), c_int> {
+        // a snapshot's files can't get names outside of it, and control
+        // files can't get any others
+        if self.frozen.contains(ino) != self.frozen.contains(newparent)
+            || self.controls.contains_key(ino)
+        {
+            return Err(EXDEV);
+        }
+        match self.get(ino).map(|node| &node.data) {
+            Some(NodeData::File(_)) => (),
+            Some(_) => return Err(EPERM),
+            None => return Err(ENOENT),
+        }
+        match self.get(newparent).map(|node| &node.data) {
+            Some(NodeData::RegularDir(_)) => (),
+            Some(NodeData::ServiceDir(_)) => return Err(EPERM),
+            Some(NodeData::File(_)) => return Err(ENOTDIR),
+            None => return Err(ENOENT),
+        }
+        if self.resolve_path(newparent, newname).is_some() {
+            return Err(EEXIST);
+        }
+
+        if let Some(NodeData::RegularDir(dir)) =
+            self.file_table.get_mut(newparent).map(|par| &mut par.data)
+        {
+            dir.add(*ino, newname.to_os_string());
+        }
+        if let Some(node) = self.file_table.get_mut(ino) {
+            node.parents.push(*newparent);
+            node.attr.nlink = node.parents.len() as u32;
+            node.attr.ctime = time::get_time();
+        }
+        Ok(())
+    }
+
+    // dirs the store keeps track of itself, which must stay where they are
+    fn is_fixed(&self, ino: &u64) -> bool {
+        self.snapshots == Some(*ino) || self.control == Some(*ino)
+    }
+
+    pub fn rmdir(&mut self, parent: &u64, name: &OsStr) -> Result<(), c_int> {
+        let id = self.resolve_path(parent, name).ok_or(ENOENT)?;
+        if self.is_fixed(&id) {
+            return Err(EPERM);
+        }
+        match self.get(&id).map(|node| &node.data) {
+            Some(NodeData::RegularDir(dir)) if !dir.children.is_empty() => return Err(ENOTEMPTY),
+            Some(NodeData::ServiceDir(_)) if !self.allow_service_removal => {
+                log::error!("refusing to remove service dir: {:?}", name);
+                return Err(EPERM);
+            }
+            Some(NodeData::ServiceDir(dir)) if !dir.children.is_empty() => return Err(ENOTEMPTY),
+            Some(NodeData::File(_)) => return Err(ENOTDIR),
+            Some(_) => (),
+            None => return Err(ENOENT),
+        }
+
+        self.remove_child(parent, name).ok_or(ENOENT)?;
+        self.remove(&id);
+        Ok(())
+    }
+
+    pub fn create_dir(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<&Inode, c_int> {
+        // a service dir only holds what its service fetched
+        if self.is_service_dir(&parent) {
+            return Err(EPERM);
+        }
         let node = gen_dir_node();
 
-        let id = self.add_child(&parent, node, name);
-        self.get(&id)
+        let id = self.add_child(&parent, node, name, uid, gid, mode)?;
+        self.get(&id).ok_or(ENOENT)
     }
 
     pub fn read_dir_children(&self, ino: &u64) -> Option<&collections::BTreeSet<u64>> {
@@ -187,38 +805,173 @@ impl FileStore {
         }
     }
 
+    // (ino, name) for every name in a dir, ordered by ino so readdir can
+    // resume from one
+    pub fn dir_entries(&self, ino: &u64) -> Option<Vec<(u64, &OsStr)>> {
+        let name_map = match &self.get(ino)?.data {
+            NodeData::RegularDir(dir) => &dir.name_map,
+            NodeData::ServiceDir(dir) => &dir.name_map,
+            NodeData::File(_) => return None,
+        };
+
+        let mut entries: Vec<(u64, &OsStr)> = name_map
+            .iter()
+            .map(|(name, id)| (*id, name.as_os_str()))
+            .collect();
+        entries.sort();
+        Some(entries)
+    }
+
+    // every entry of a dir with its readdir cookie, in cookie order. each
+    // name has its own cookie, even when hard links share an ino
+    pub fn dir_cookies(&self, ino: &u64) -> Option<Vec<(u64, u64, &OsStr)>> {
+        let (name_map, cookies) = match &self.get(ino)?.data {
+            NodeData::RegularDir(dir) => (&dir.name_map, &dir.cookies),
+            NodeData::ServiceDir(dir) => (&dir.name_map, &dir.cookies),
+            NodeData::File(_) => return None,
+        };
+
+        let mut entries: Vec<(u64, u64, &OsStr)> = name_map
+            .iter()
+            .filter_map(|(name, id)| Some((*cookies.get(name)?, *id, name.as_os_str())))
+            .collect();
+        entries.sort();
+        Some(entries)
+    }
+
     pub fn remove(&mut self, id: &u64) {
-        let node = self.file_table.get(id).unwrap();
-        match &node.data {
-            NodeData::RegularDir(dir) => {
-                let y = dir.children.clone();
-                for child in y.iter() {
-                    self.remove(child);
+        match self.file_table.remove(id) {
+            Some(node) => {
+                log::info!("removed: {} {:?}", id, node.path);
+                self.contents.remove(id);
+                self.frozen.remove(id);
+                self.controls.remove(id);
+                self.accessed().remove(id);
+                self.take_written(id);
+                if self.reuse_inos || *id >= SERVICE_INO_BASE {
+                    self.generations.insert(*id, node.generation);
+                }
+                if !self.lookups().contains_key(id) {
+                    self.release(*id);
                 }
-                self.file_table.remove(id);
             }
-            _ => log::error!("no children"),
+            None => log::error!("no such inode: {}", id),
         }
+    }
 
-        self.file_table.remove(id);
+    pub fn set_ino_reuse(&mut self, reuse: bool) {
+        self.reuse_inos = reuse;
     }
 
-    // this is troubling; see the call at self.store.read_file in fuse_system
-    // there a buf is initialized as not mutable, but can be "safely" passed as
-    // as an argument construed as a mut Vec<u8>
-    pub fn read_file(&self, ino: &u64) -> Option<Vec<u8>> {
-        match self.get(ino) {
-            Some(f) => match &f.data {
-                NodeData::File(file) => {
-                    let data = &file.content;
-                    Some(data.to_vec())
+    // the kernel was handed an entry for ino
+    pub fn note_lookup(&self, ino: &u64) {
+        *self.lookups().entry(*ino).or_insert(0) += 1;
+    }
+
+    // ino was looked up, which counts as an access
+    pub fn note_access(&self, ino: &u64) {
+        self.accessed().insert(*ino, time::get_time());
+    }
+
+    fn accessed(&self) -> std::sync::MutexGuard<'_, collections::HashMap<u64, time::Timespec>> {
+        self.accessed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lookups(&self) -> std::sync::MutexGuard<'_, collections::HashMap<u64, u64>> {
+        self.lookups.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // the kernel dropped nlookup references to ino
+    pub fn forget(&mut self, ino: &u64, nlookup: u64) {
+        let mut lookups = self.lookups();
+        let left = match lookups.get_mut(ino) {
+            Some(n) => {
+                *n = n.saturating_sub(nlookup);
+                *n
+            }
+            None => return,
+        };
+        if left == 0 {
+            lookups.remove(ino);
+            drop(lookups);
+            if !self.file_table.contains_key(ino) {
+                self.release(*ino);
+            }
+        }
+    }
+
+    // ino is gone and nothing refers to it any more
+    fn release(&mut self, ino: u64) {
+        if self.reuse_inos && ino < SERVICE_INO_BASE {
+            self.free_inos.insert(ino);
+        }
+    }
+
+    // hands out an ino and its generation. stable inos are probed from a
+    // hash so service entries keep theirs across restarts; the rest are
+    // recycled when allowed, or counted up
+    fn alloc_ino(&mut self, stable: Option<u64>) -> (u64, u64) {
+        let id = match stable {
+            Some(hash) => {
+                let mut id = SERVICE_INO_BASE + hash % SERVICE_INO_BASE;
+                let lookups = self.lookups();
+                while self.file_table.contains_key(&id) || lookups.contains_key(&id) {
+                    id = SERVICE_INO_BASE + (id + 1) % SERVICE_INO_BASE;
                 }
-                _ => None,
-            },
+                id
+            }
             None => {
-                log::error!("read failed {:?}", ino);
-                None
+                let reused = if self.reuse_inos {
+                    self.free_inos.pop_first()
+                } else {
+                    None
+                };
+                reused.unwrap_or_else(|| {
+                    self.ino_ctr += 1;
+                    self.ino_ctr - 1
+                })
             }
+        };
+
+        let generation = self.generations.remove(&id).map_or(1, |g| g + 1);
+        (id, generation)
+    }
+
+    pub fn read_file(&self, ino: &u64) -> Option<Vec<u8>> {
+        self.read(ino, 0, u64::MAX).ok()
+    }
+
+    pub fn read(&self, ino: &u64, offset: u64, size: u64) -> Result<Vec<u8>, c_int> {
+        let data = self.contents.read(ino, offset, size);
+        if let Err(e) = data {
+            log::error!("read failed {:?}: {}", ino, e);
+        }
+
+        data
+    }
+
+    // the attributes with a file's size and times taken from its content
+    pub fn attr(&self, ino: &u64) -> Option<FileAttr> {
+        let mut attr = self.get(ino)?.attr;
+        if let Some(atime) = self.accessed().get(ino) {
+            attr.atime = attr.atime.max(*atime);
+        }
+        if let Some((size, mtime)) = self.contents.stat(ino) {
+            attr.size = size;
+            attr.blocks = blocks_for(size);
+            attr.mtime = mtime;
+            attr.ctime = attr.ctime.max(mtime);
+        }
+
+        Some(attr)
+    }
+
+    // the service behind a service dir, to fetch from without holding the
+    // store
+    pub fn service(&self, ino: &u64) -> Option<Arc<dyn SingleService>> {
+        match &self.get(ino)?.data {
+            NodeData::ServiceDir(dir) => Some(dir.service.clone()),
+            _ => None,
         }
     }
 
@@ -226,6 +979,63 @@ impl FileStore {
         self.file_table.get(id)
     }
 
+    // absolute path of ino, following the first link of a hard linked file
+    pub fn full_path(&self, ino: &u64) -> Option<path::PathBuf> {
+        let mut names = Vec::new();
+        let mut cur = *ino;
+        while cur != fuse::FUSE_ROOT_ID {
+            // only a corrupt table has cycles; see check_invariants
+            if names.len() > self.file_table.len() {
+                return None;
+            }
+            let parent = *self.get(&cur)?.parents.first()?;
+            names.push(self.name_in(&parent, &cur)?);
+            cur = parent;
+        }
+
+        let mut path = path::PathBuf::from("/");
+        path.extend(names.iter().rev());
+        Some(path)
+    }
+
+    // looks up an absolute path like "/a/b/c" without permission checks or
+    // touching atimes
+    pub fn resolve<P: AsRef<path::Path>>(&self, path: P) -> Result<u64, c_int> {
+        let mut ino = fuse::FUSE_ROOT_ID;
+        for component in path.as_ref().components() {
+            let node = self.get(&ino).ok_or(ENOENT)?;
+            ino = match component {
+                path::Component::RootDir | path::Component::CurDir => continue,
+                _ if matches!(node.data, NodeData::File(_)) => return Err(ENOTDIR),
+                path::Component::ParentDir => node.parent(),
+                path::Component::Normal(name) => self.resolve_path(&ino, name).ok_or(ENOENT)?,
+                path::Component::Prefix(_) => return Err(EINVAL),
+            };
+        }
+
+        Ok(ino)
+    }
+
+    // the name ino has in dir, preferring the one it was created or renamed
+    // under when it has several
+    fn name_in(&self, dir: &u64, ino: &u64) -> Option<&OsStr> {
+        let name_map = match &self.get(dir)?.data {
+            NodeData::RegularDir(d) => &d.name_map,
+            NodeData::ServiceDir(d) => &d.name_map,
+            NodeData::File(_) => return None,
+        };
+        let own = self.get(ino)?.path.as_os_str();
+        if name_map.get(own) == Some(ino) {
+            return Some(own);
+        }
+
+        name_map
+            .iter()
+            .filter(|(_, id)| *id == ino)
+            .map(|(name, _)| name.as_os_str())
+            .min()
+    }
+
     pub fn lookup_path(&mut self, parent: &u64, name: &OsStr) -> Option<&Inode> {
         let id = self.resolve_path(parent, name)?;
 
@@ -236,35 +1046,95 @@ impl FileStore {
         self.get(&id)
     }
 
-    pub fn add_child(&mut self, parent_id: &u64, data: NodeData, name: &OsStr) -> u64 {
-        let id: u64 = (self.ino_ctr) as u64;
-        self.ino_ctr += 1;
-        // replace with uid and gid from req
-        let mut node = Inode::new(id, data, name, 1000, 1000);
-        node.id = id;
-        node.attr.ino = id;
-        match &self.file_table.get(parent_id).unwrap().data {
-            NodeData::RegularDir(_) => {
-                self.file_table.insert(id, node);
+    // adds a node as it is. nothing is fetched for it here, even in a
+    // service dir: service files come in through add_fetched, their fetch
+    // made beforehand without the store
+    pub fn add_child(
+        &mut self,
+        parent_id: &u64,
+        data: NodeData,
+        name: &OsStr,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+    ) -> Result<u64, c_int> {
+        self.insert_child(parent_id, data, name, uid, gid, mode)
+    }
+
+    // adds a file fetched from the service of parent_id; the fetch itself
+    // happens beforehand so it doesn't hold up the store
+    pub fn add_fetched(
+        &mut self,
+        parent_id: &u64,
+        name: &OsStr,
+        content: Vec<u8>,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+    ) -> Result<u64, c_int> {
+        let node = gen_file_node();
+        let id = self.insert_child(parent_id, node, name, uid, gid, mode)?;
+        match self.refetcher(parent_id, name) {
+            Some(refetch) => {
+                self.keep_version(&id, &content);
+                self.contents.insert_fetched(id, content, refetch);
             }
-            NodeData::ServiceDir(dir) => {
-                let data = dir.service.fetch_data(name.to_str());
-                let d: &[u8] = &data.join("\n").into_bytes();
-                let s = d.len();
-                node.attr.size = s as u64;
-                match &mut node.data {
-                    NodeData::File(f) => {
-                        f.content = data.join("\n").into_bytes();
-                    }
-                    _ => {
-                        log::error!("oops");
-                    }
-                }
+            None => self.contents.insert(id, content),
+        }
+
+        Ok(id)
+    }
 
-                self.file_table.insert(id, node);
+    // fetches name from the service of parent_id again, so its content can
+    // be dropped under memory pressure
+    fn refetcher(&self, parent_id: &u64, name: &OsStr) -> Option<Refetch> {
+        let svc = self.service(parent_id)?;
+        let query = name.to_str().map(String::from);
+
+        Some(Arc::new(move || {
+            svc.fetch_data(query.as_deref()).join("\n").into_bytes()
+        }))
+    }
+
+    fn insert_child(
+        &mut self,
+        parent_id: &u64,
+        data: NodeData,
+        name: &OsStr,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+    ) -> Result<u64, c_int> {
+        match self.get(parent_id).map(|node| &node.data) {
+            Some(NodeData::File(_)) => return Err(ENOTDIR),
+            Some(_) => (),
+            None => return Err(ENOENT),
+        }
+        if self.resolve_path(parent_id, name).is_some() {
+            return Err(EEXIST);
+        }
+
+        let stable = match (&self.get(parent_id).unwrap().data, &data) {
+            (NodeData::ServiceDir(dir), _) => {
+                let svc = dir.service.get_name();
+                Some(stable_hash(&[svc.as_bytes(), name.as_bytes()]))
             }
-            _ => log::error!("not a dir"),
+            (_, NodeData::ServiceDir(dir)) => {
+                Some(stable_hash(&[dir.service.get_name().as_bytes()]))
+            }
+            _ => None,
+        };
+        let (id, generation) = self.alloc_ino(stable);
+        let mut node = Inode::new(id, data, name, uid, gid);
+        node.id = id;
+        node.generation = generation;
+        node.attr.ino = id;
+        node.parents.push(*parent_id);
+        node.attr.perm = (mode & !self.umask & 0o7777) as u16;
+        if let NodeData::File(_) = node.data {
+            self.contents.insert(id, Vec::new());
         }
+        self.file_table.insert(id, node);
 
         // consider extracting to method
         // see rename above
@@ -282,30 +1152,724 @@ impl FileStore {
             });
         log::info!("new entry: {:?}", self.file_table);
 
-        id
+        Ok(id)
     }
 
-    pub fn clear_file(&mut self, ino: &u64) {
-        self.file_table
-            .entry(*ino)
-            .and_modify(|f| match &mut f.data {
-                NodeData::File(file) => {
-                    f.attr.size = 0;
-                    file.content = [].to_vec();
-                }
-                _ => log::error!("Not a File"),
-            });
+    pub fn clear_file(&self, ino: &u64) {
+        if self.contents.truncate(ino, 0).is_err() {
+            log::error!("Not a File");
+        }
+    }
+
+    pub fn truncate(&self, ino: &u64, size: u64) -> Result<(), c_int> {
+        match self.get(ino).map(|node| &node.data) {
+            Some(NodeData::File(_)) => {
+                self.contents.truncate(ino, size)?;
+                self.note_written(ino);
+                Ok(())
+            }
+            Some(_) => Err(EISDIR),
+            None => Err(ENOENT),
+        }
     }
 
-    pub fn touch_file(&mut self, parent: &u64, name: &OsStr) -> u64 {
+    pub fn touch_file(
+        &mut self,
+        parent: &u64,
+        name: &OsStr,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+    ) -> Result<u64, c_int> {
         let node = gen_file_node();
-        self.add_child(parent, node, name)
+        self.add_child(parent, node, name, uid, gid, mode)
+    }
+
+    // walks the whole table and reports every way the inode table and the
+    // dir entries disagree: dangling children, orphaned inodes, name_map
+    // entries out of step with children, and stale sizes
+    pub fn check_invariants(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        // (dir, child) -> how many names in dir point at child
+        let mut refs: collections::HashMap<(u64, u64), usize> = collections::HashMap::new();
+
+        for (ino, node) in &self.file_table {
+            if node.id != *ino || node.attr.ino != *ino {
+                problems.push(format!(
+                    "ino {} stored with id={} attr.ino={}",
+                    ino, node.id, node.attr.ino
+                ));
+            }
+            if *ino >= self.ino_ctr && *ino < SERVICE_INO_BASE {
+                problems.push(format!("ino {} not below counter {}", ino, self.ino_ctr));
+            }
+
+            let (children, name_map, cookies) = match &node.data {
+                NodeData::RegularDir(dir) => (&dir.children, &dir.name_map, &dir.cookies),
+                NodeData::ServiceDir(dir) => (&dir.children, &dir.name_map, &dir.cookies),
+                NodeData::File(_) => {
+                    if self.contents.stat(ino).is_none() {
+                        problems.push(format!("file {} has no content", ino));
+                    }
+                    continue;
+                }
+            };
+
+            if cookies.len() != name_map.len() || name_map.keys().any(|n| !cookies.contains_key(n))
+            {
+                problems.push(format!("dir {} has names without a readdir cookie", ino));
+            }
+            for (name, child) in name_map {
+                if !children.contains(child) {
+                    problems.push(format!(
+                        "dir {} names {:?} -> {} not a child",
+                        ino, name, child
+                    ));
+                }
+                *refs.entry((*ino, *child)).or_insert(0) += 1;
+                match self.get(child) {
+                    Some(c) if c.parents.len() == 1 && c.path.as_os_str() != name => problems.push(
+                        format!("ino {} listed as {:?} but named {:?}", child, name, c.path),
+                    ),
+                    Some(_) => (),
+                    None => problems.push(format!("dir {} has dangling child {}", ino, child)),
+                }
+            }
+            for child in children {
+                if !name_map.values().any(|id| id == child) {
+                    problems.push(format!("dir {} has unnamed child {}", ino, child));
+                }
+            }
+        }
+
+        // every name must be matched by a parent link and vice versa
+        let mut named: collections::HashMap<u64, usize> = collections::HashMap::new();
+        for ((_, child), n) in &refs {
+            *named.entry(*child).or_insert(0) += n;
+        }
+        for (ino, node) in &self.file_table {
+            let mut links: collections::HashMap<u64, usize> = collections::HashMap::new();
+            for parent in &node.parents {
+                *links.entry(*parent).or_insert(0) += 1;
+            }
+            for (parent, n) in &links {
+                let names = refs.get(&(*parent, *ino)).copied().unwrap_or(0);
+                if names != *n {
+                    problems.push(format!(
+                        "ino {} links to dir {} {} times but is named there {} times",
+                        ino, parent, n, names
+                    ));
+                }
+            }
+            let names = named.get(ino).copied().unwrap_or(0);
+            if names > node.parents.len() {
+                problems.push(format!("ino {} has names without a link back", ino));
+            }
+
+            match &node.data {
+                _ if names == 0 && *ino != fuse::FUSE_ROOT_ID => {
+                    problems.push(format!("orphaned inode {}", ino))
+                }
+                NodeData::File(_) if node.attr.nlink as usize != node.parents.len() => problems
+                    .push(format!(
+                        "ino {} has nlink {} for {} links",
+                        ino,
+                        node.attr.nlink,
+                        node.parents.len()
+                    )),
+                NodeData::File(_) => (),
+                _ if node.parents.len() > 1 => {
+                    problems.push(format!("dir {} is in {} dirs", ino, node.parents.len()))
+                }
+                _ => (),
+            }
+        }
+        for ((dir, name), kept) in &self.history {
+            for (version, ino) in &kept.versions {
+                if self.get(ino).is_none() {
+                    problems.push(format!("v{} of {:?} in {} is gone", version, name, dir));
+                }
+            }
+        }
+        for ino in &self.frozen {
+            if self.get(ino).is_none() {
+                problems.push(format!("frozen inode {} is gone", ino));
+            }
+        }
+        for (ino, path) in &self.controls {
+            if self.get(ino).is_none() {
+                problems.push(format!("control file {} at {:?} is gone", ino, path));
+            }
+        }
+        problems.extend(self.contents.check());
+        for ino in self.contents.inos() {
+            if !matches!(self.get(&ino).map(|n| &n.data), Some(NodeData::File(_))) {
+                problems.push(format!("content kept for {} which is not a file", ino));
+            }
+        }
+        if named.contains_key(&fuse::FUSE_ROOT_ID) {
+            problems.push("root is listed as a child".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    // makes /.snapshots if it isn't there yet
+    pub fn enable_snapshots(&mut self) -> Result<u64, c_int> {
+        if let Some(dir) = self.snapshots {
+            return Ok(dir);
+        }
+
+        let (uid, gid) = (self.uid, self.gid);
+        let name = OsStr::new(SNAPSHOTS);
+        let dir = self.insert_child(&fuse::FUSE_ROOT_ID, gen_dir_node(), name, uid, gid, 0o755)?;
+        if let Some(node) = self.file_table.get_mut(&dir) {
+            node.attr.perm = 0o755;
+        }
+        self.snapshots = Some(dir);
+        Ok(dir)
+    }
+
+    pub fn snapshots_dir(&self) -> Option<u64> {
+        self.snapshots
+    }
+
+    // copies the whole tree, read only, to /.snapshots/<name>, named after
+    // the current time when no name is given. file contents are shared
+    // with the live tree until one side changes
+    pub fn snapshot(&mut self, name: Option<&OsStr>) -> Result<u64, c_int> {
+        let plan = self.plan_snapshot()?;
+        self.finish_snapshot(plan, name)
+    }
+
+    // everything a snapshot copies, gathered with the store shared: the
+    // tree's shape and attrs, and a hold on every file's bytes. only putting
+    // it in place with finish_snapshot needs the store to itself
+    pub fn plan_snapshot(&self) -> Result<SnapshotPlan, c_int> {
+        let mut plan = SnapshotPlan { nodes: Vec::new() };
+        let mut seen = collections::HashMap::new();
+        let root = fuse::FUSE_ROOT_ID;
+        if let Err(e) = self.plan_tree(root, None, OsString::new(), &mut seen, &mut plan) {
+            self.drop_plan(plan);
+            return Err(e);
+        }
+
+        Ok(plan)
+    }
+
+    // adds src, and below it everything in it, to plan. files linked more
+    // than once are planned once and linked after
+    fn plan_tree(
+        &self,
+        src: u64,
+        parent: Option<usize>,
+        name: OsString,
+        seen: &mut collections::HashMap<u64, usize>,
+        plan: &mut SnapshotPlan,
+    ) -> Result<(), c_int> {
+        if let Some(of) = seen.get(&src).copied() {
+            plan.nodes.push(Planned::Link { parent, name, of });
+            return Ok(());
+        }
+
+        let node = self.get(&src).ok_or(ENOENT)?;
+        let mut attr = node.attr;
+        let content = match &node.data {
+            NodeData::File(_) => Some(self.contents.freeze(&src)?),
+            NodeData::RegularDir(_) => None,
+            NodeData::ServiceDir(dir) => {
+                // keep services that are closed to some users closed
+                if !(dir.config.allow_uids.is_empty() && dir.config.allow_gids.is_empty()) {
+                    attr.perm = 0o700;
+                }
+                None
+            }
+        };
+        let is_file = content.is_some();
+        seen.insert(src, plan.nodes.len());
+        plan.nodes.push(Planned::Node {
+            parent,
+            name,
+            attr: Box::new(attr),
+            xattr: node.xattr.clone(),
+            content,
+        });
+        if is_file {
+            return Ok(());
+        }
+
+        let at = plan.nodes.len() - 1;
+        let children: Vec<(u64, OsString)> = self
+            .dir_entries(&src)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(child, _)| Some(*child) != self.snapshots && Some(*child) != self.control)
+            .map(|(child, name)| (child, name.to_os_string()))
+            .collect();
+        for (child, name) in children {
+            self.plan_tree(child, Some(at), name, seen, plan)?;
+        }
+
+        Ok(())
+    }
+
+    fn drop_plan(&self, plan: SnapshotPlan) {
+        for planned in plan.nodes {
+            if let Planned::Node {
+                content: Some(frozen),
+                ..
+            } = planned
+            {
+                self.contents.unfreeze(frozen);
+            }
+        }
+    }
+
+    // puts a planned snapshot in /.snapshots as name. on failure nothing
+    // of it is left behind
+    pub fn finish_snapshot(
+        &mut self,
+        plan: SnapshotPlan,
+        name: Option<&OsStr>,
+    ) -> Result<u64, c_int> {
+        let dir = match self.enable_snapshots() {
+            Ok(dir) => dir,
+            Err(e) => {
+                self.drop_plan(plan);
+                return Err(e);
+            }
+        };
+        let name = match name {
+            Some(name) => name.to_os_string(),
+            None => self.stamp_name(&dir, time::get_time()),
+        };
+        if self.resolve_path(&dir, &name).is_some() {
+            self.drop_plan(plan);
+            return Err(EEXIST);
+        }
+
+        log::info!("snapshot {:?}", name);
+        let mut made: Vec<u64> = Vec::with_capacity(plan.nodes.len());
+        let mut nodes = plan.nodes.into_iter();
+        while let Some(planned) = nodes.next() {
+            match self.place(dir, &name, planned, &made) {
+                Ok(id) => made.push(id),
+                Err(e) => {
+                    self.drop_plan(SnapshotPlan {
+                        nodes: nodes.collect(),
+                    });
+                    if !made.is_empty() {
+                        if let Err(e) = self.remove_tree(dir, &name) {
+                            log::error!("could not remove snapshot {:?}: {}", name, e);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let id = made.first().copied().ok_or(ENOENT)?;
+        if let Some(node) = self.file_table.get_mut(&id) {
+            node.attr.crtime = time::get_time();
+        }
+        Ok(id)
+    }
+
+    // makes one planned node of a snapshot and freezes it; made holds
+    // the inos of those before it
+    fn place(
+        &mut self,
+        dir: u64,
+        top: &OsStr,
+        planned: Planned,
+        made: &[u64],
+    ) -> Result<u64, c_int> {
+        let (parent, name, attr, xattr, content) = match planned {
+            Planned::Link { parent, name, of } => {
+                let parent = parent.map_or(dir, |at| made[at]);
+                self.link(&made[of], &parent, &name)?;
+                return Ok(made[of]);
+            }
+            Planned::Node {
+                parent,
+                name,
+                attr,
+                xattr,
+                content,
+            } => (parent, name, attr, xattr, content),
+        };
+        let (parent, name) = match parent {
+            Some(at) => (made[at], name),
+            None => (dir, top.to_os_string()),
+        };
+        let data = match content {
+            Some(_) => gen_file_node(),
+            None => gen_dir_node(),
+        };
+
+        let id = match self.insert_child(&parent, data, &name, attr.uid, attr.gid, 0) {
+            Ok(id) => id,
+            Err(e) => {
+                if let Some(frozen) = content {
+                    self.contents.unfreeze(frozen);
+                }
+                return Err(e);
+            }
+        };
+        self.frozen.insert(id);
+        if let Some(node) = self.file_table.get_mut(&id) {
+            node.attr = FileAttr {
+                ino: id,
+                nlink: node.attr.nlink,
+                ..*attr
+            };
+            node.xattr = xattr;
+        }
+        if let Some(frozen) = content {
+            self.contents.thaw(id, frozen);
+        }
+
+        Ok(id)
+    }
+
+    // at as utc time to the second, with a count added when that is taken
+    // in dir
+    fn stamp_name(&self, dir: &u64, at: time::Timespec) -> OsString {
+        let stamp = stamp(at);
+
+        let mut name = OsString::from(&stamp);
+        let mut n = 1;
+        while self.resolve_path(dir, &name).is_some() {
+            n += 1;
+            name = OsString::from(format!("{}.{}", stamp, n));
+        }
+        name
+    }
+
+    // deletes /.snapshots/<name> and everything in it
+    pub fn remove_snapshot(&mut self, name: &OsStr) -> Result<(), c_int> {
+        let dir = self.snapshots.ok_or(ENOENT)?;
+        self.resolve_path(&dir, name).ok_or(ENOENT)?;
+        self.remove_tree(dir, name)
+    }
+
+    fn remove_tree(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let id = self.resolve_path(&parent, name).ok_or(ENOENT)?;
+        let service = match self.get(&id).map(|node| &node.data) {
+            Some(NodeData::File(_)) => return self.unlink(&parent, name),
+            Some(NodeData::ServiceDir(_)) => true,
+            _ => false,
+        };
+
+        let children: Vec<OsString> = self
+            .dir_entries(&id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(_, name)| name.to_os_string())
+            .collect();
+        for child in children {
+            self.remove_tree(id, &child)?;
+        }
+        if !service {
+            return self.rmdir(&parent, name);
+        }
+
+        // service dirs go whether or not users may rmdir them
+        self.history.retain(|(dir, _), _| *dir != id);
+        self.remove_child(&parent, name).ok_or(ENOENT)?;
+        self.remove(&id);
+        Ok(())
+    }
+
+    // removes the oldest snapshots until only keep are left
+    pub fn prune_snapshots(&mut self, keep: usize) -> Result<(), c_int> {
+        let dir = match self.snapshots {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let mut snapshots: Vec<(time::Timespec, u64, OsString)> = self
+            .dir_entries(&dir)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(id, name)| Some((self.get(&id)?.attr.crtime, id, name.to_os_string())))
+            .collect();
+        snapshots.sort();
+
+        let excess = snapshots.len().saturating_sub(keep);
+        for (_, _, name) in snapshots.into_iter().take(excess) {
+            self.remove_snapshot(&name)?;
+        }
+        Ok(())
+    }
+
+    // makes /.vfs if it isn't there yet
+    pub fn enable_control(&mut self) -> Result<u64, c_int> {
+        if let Some(dir) = self.control {
+            return Ok(dir);
+        }
+
+        let dir = self.frozen_dir(fuse::FUSE_ROOT_ID, OsStr::new(CONTROL), 0o755)?;
+        self.control = Some(dir);
+        Ok(dir)
+    }
+
+    pub fn control_dir(&self) -> Option<u64> {
+        self.control
+    }
+
+    // adds a control file at a path like "services/weather/ctl" under
+    // /.vfs, making the dirs on the way. the dirs are read only, so
+    // control files can't be removed or renamed by users
+    pub fn add_control(&mut self, path: &str, perm: u16) -> Result<u64, c_int> {
+        let mut dir = self.enable_control()?;
+        let (dirs, name) = path.rsplit_once('/').unwrap_or(("", path));
+        for part in dirs.split('/').filter(|part| !part.is_empty()) {
+            dir = self.frozen_dir(dir, OsStr::new(part), 0o755)?;
+        }
+
+        let (uid, gid) = (self.uid, self.gid);
+        let id = self.insert_child(&dir, gen_file_node(), OsStr::new(name), uid, gid, 0)?;
+        if let Some(node) = self.file_table.get_mut(&id) {
+            node.attr.perm = perm;
+        }
+        self.controls.insert(id, path.to_string());
+        Ok(id)
+    }
+
+    // the path under /.vfs of a control file
+    pub fn control(&self, ino: &u64) -> Option<&str> {
+        self.controls.get(ino).map(String::as_str)
+    }
+
+    pub fn is_control(&self, ino: &u64) -> bool {
+        self.controls.contains_key(ino)
+    }
+
+    // takes out a control file or a dir of them
+    pub fn remove_control(&mut self, path: &str) -> Result<(), c_int> {
+        let mut dir = self.control.ok_or(ENOENT)?;
+        let (dirs, name) = path.rsplit_once('/').unwrap_or(("", path));
+        for part in dirs.split('/').filter(|part| !part.is_empty()) {
+            dir = self.resolve_path(&dir, OsStr::new(part)).ok_or(ENOENT)?;
+        }
+
+        self.remove_tree(dir, OsStr::new(name))
+    }
+
+    // what a control file reads as from now on
+    pub fn set_control_content(&self, ino: &u64, data: Vec<u8>) {
+        if self.controls.contains_key(ino) {
+            self.contents.insert(*ino, data);
+        }
+    }
+
+    // the service dir a file was fetched from and its name there
+    fn service_file(&self, ino: &u64) -> Option<(u64, OsString)> {
+        let node = self.get(ino)?;
+        if !matches!(node.data, NodeData::File(_)) {
+            return None;
+        }
+        let dir = node.parent();
+        if !self.is_service_dir(&dir) {
+            return None;
+        }
+
+        Some((dir, self.name_in(&dir, ino)?.to_os_string()))
+    }
+
+    pub fn is_service_file(&self, ino: &u64) -> bool {
+        self.service_file(ino).is_some()
+    }
+
+    // marks a service file as written to, again for one whose write back
+    // failed
+    pub fn note_written(&self, ino: &u64) {
+        if self.is_service_file(ino) {
+            self.written
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(*ino);
+        }
+    }
+
+    // true once for every service file written to since the last call
+    pub fn take_written(&self, ino: &u64) -> bool {
+        self.written
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(ino)
+    }
+
+    // what the service said about the file it last fetched for ino, shown
+    // as the file's extended attributes
+    pub fn set_attributes(&mut self, ino: &u64, attrs: Vec<(String, String)>) -> Result<(), c_int> {
+        let node = self.file_table.get_mut(ino).ok_or(ENOENT)?;
+        node.xattr = attrs
+            .into_iter()
+            .map(|(name, value)| (OsString::from(name), value))
+            .collect();
+        Ok(())
+    }
+
+    // takes a new answer from the service for one of its files, keeping
+    // the old one in the file's history if the service keeps any
+    pub fn refresh(&mut self, ino: &u64, content: Vec<u8>) -> Result<(), c_int> {
+        let (dir, name) = self.service_file(ino).ok_or(EINVAL)?;
+        let refetch = self.refetcher(&dir, &name).ok_or(EINVAL)?;
+        self.keep_version(ino, &content);
+        self.contents.insert_fetched(*ino, content, refetch);
+        self.take_written(ino);
+        Ok(())
+    }
+
+    // a failure to keep history shouldn't fail the fetch it records
+    fn keep_version(&mut self, ino: &u64, content: &[u8]) {
+        if let Err(e) = self.record_version(ino, content) {
+            log::error!("could not keep a version of {}: {}", ino, e);
+        }
+    }
+
+    // keeps content, just fetched for a service file, as
+    // .history/<name>/<fetch time> in its service dir, dropping the oldest
+    // copies past the service's limit, and rewrites the changes file there.
+    // the copy is given its own bytes rather than sharing the file's, which
+    // may be dropped and only to be had from the service as it is later
+    fn record_version(&mut self, ino: &u64, content: &[u8]) -> Result<(), c_int> {
+        let (dir, name) = self.service_file(ino).ok_or(EINVAL)?;
+        let (keep, restricted) = match &self.get(&dir).ok_or(ENOENT)?.data {
+            NodeData::ServiceDir(d) => (
+                d.config.history,
+                !(d.config.allow_uids.is_empty() && d.config.allow_gids.is_empty()),
+            ),
+            _ => return Err(ENOTDIR),
+        };
+        if keep == 0 {
+            return Ok(());
+        }
+
+        // closed services keep their history closed
+        let perm = if restricted { 0o700 } else { 0o755 };
+        let history = self.frozen_dir(dir, OsStr::new(HISTORY), perm)?;
+        let versions = self.frozen_dir(history, &name, perm)?;
+
+        let stamp = self.stamp_name(&versions, time::get_time());
+        let (uid, gid) = (self.uid, self.gid);
+        let copy = self.insert_child(&versions, gen_file_node(), &stamp, uid, gid, 0)?;
+        self.frozen.insert(copy);
+        if let Some(node) = self.file_table.get_mut(&copy) {
+            node.attr.perm = perm & 0o444;
+        }
+        self.contents.insert(copy, content.to_vec());
+
+        let kept = self.history.entry((dir, name.clone())).or_default();
+        kept.last += 1;
+        kept.versions.push_back((kept.last, copy));
+        let mut dropped = Vec::new();
+        while kept.versions.len() > keep {
+            dropped.extend(kept.versions.pop_front().map(|(_, old)| old));
+        }
+        let newest: Vec<(u64, u64)> = kept.versions.iter().rev().take(2).rev().copied().collect();
+        // diffs against versions no longer kept are cut from the front
+        let sections = kept.changes.len() + newest.len().saturating_sub(1);
+        let stale = sections.saturating_sub(kept.versions.len().saturating_sub(1));
+        let cut = kept.changes.drain(..stale.min(kept.changes.len())).sum();
+
+        for old in dropped {
+            if let Some(old_name) = self.name_in(&versions, &old).map(OsStr::to_os_string) {
+                self.unlink(&versions, &old_name)?;
+            }
+        }
+        let added = self.write_changes(versions, &newest, cut)?;
+        if added > 0 {
+            if let Some(kept) = self.history.get_mut(&(dir, name)) {
+                kept.changes.push_back(added);
+            }
+        }
+        Ok(())
+    }
+
+    // a read only dir named name in parent, made if it isn't there
+    fn frozen_dir(&mut self, parent: u64, name: &OsStr, perm: u16) -> Result<u64, c_int> {
+        if let Some(id) = self.resolve_path(&parent, name) {
+            return Ok(id);
+        }
+
+        let (uid, gid) = (self.uid, self.gid);
+        let id = self.insert_child(&parent, gen_dir_node(), name, uid, gid, 0)?;
+        self.frozen.insert(id);
+        if let Some(node) = self.file_table.get_mut(&id) {
+            node.attr.perm = perm;
+        }
+        Ok(id)
+    }
+
+    // appends the diff between the two newest kept versions to the changes
+    // file, after cutting cut bytes of older diffs off its front. returns
+    // how many bytes were appended
+    fn write_changes(
+        &mut self,
+        versions: u64,
+        newest: &[(u64, u64)],
+        cut: u64,
+    ) -> Result<u64, c_int> {
+        let changes = match self.resolve_path(&versions, OsStr::new(CHANGES)) {
+            Some(id) => id,
+            None => {
+                let (uid, gid) = (self.uid, self.gid);
+                let name = OsStr::new(CHANGES);
+                let id = self.insert_child(&versions, gen_file_node(), name, uid, gid, 0)?;
+                self.frozen.insert(id);
+                let perm = self.get(&versions).map_or(0o755, |dir| dir.attr.perm);
+                if let Some(node) = self.file_table.get_mut(&id) {
+                    node.attr.perm = perm & 0o444;
+                }
+                self.contents.insert(id, Vec::new());
+                id
+            }
+        };
+        if cut > 0 {
+            let rest = self.contents.read(&changes, cut, u64::MAX)?;
+            self.contents.insert(changes, rest);
+        }
+
+        let (old, new) = match newest {
+            [old, new] => (old, new),
+            _ => return Ok(0),
+        };
+        let old_text = self.contents.read(&old.1, 0, u64::MAX)?;
+        let new_text = self.contents.read(&new.1, 0, u64::MAX)?;
+        let diff = history::change(
+            (
+                old.0,
+                self.name_in(&versions, &old.1).ok_or(ENOENT)?,
+                &old_text,
+            ),
+            (
+                new.0,
+                self.name_in(&versions, &new.1).ok_or(ENOENT)?,
+                &new_text,
+            ),
+        );
+        let end = self.contents.stat(&changes).map_or(0, |(len, _)| len);
+        self.contents.write(&changes, end, &diff)?;
+        Ok(diff.len() as u64)
+    }
+
+    // the kept copy a name like "10002@v3" in a service dir stands for
+    pub fn version(&self, dir: &u64, name: &OsStr) -> Option<u64> {
+        let (name, version) = history::parse_version(name)?;
+        self.history.get(&(*dir, name.to_os_string()))?.get(version)
     }
 
-    fn resolve_path(&self, parent: &u64, name: &OsStr) -> Option<u64> {
-        let parent = self.get(parent).unwrap();
+    pub fn resolve_path(&self, parent: &u64, name: &OsStr) -> Option<u64> {
+        let parent = self.get(parent)?;
         match &parent.data {
-            NodeData::RegularDir(dir) => Some(dir.name_map.get(name)?.clone()),
+            NodeData::RegularDir(dir) => Some(*dir.name_map.get(name)?),
+            NodeData::ServiceDir(dir) => Some(*dir.name_map.get(name)?),
             _ => None,
         }
     }
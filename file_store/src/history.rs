@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+// past this many line pairs the diff just replaces everything
+const MAX_DIFF: usize = 1 << 22;
+
+// the kept fetches of one service file, oldest first, as (version, ino of
+// the frozen copy). versions count up from 1 and keep their numbers as old
+// ones are dropped
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    pub versions: VecDeque<(u64, u64)>,
+    pub last: u64,
+    // byte length of each diff in the changes file, oldest first
+    pub changes: VecDeque<u64>,
+}
+
+impl History {
+    pub fn get(&self, version: u64) -> Option<u64> {
+        self.versions
+            .iter()
+            .find(|(v, _)| *v == version)
+            .map(|(_, ino)| *ino)
+    }
+}
+
+// splits "10002@v3" into ("10002", 3)
+pub(crate) fn parse_version(name: &OsStr) -> Option<(&OsStr, u64)> {
+    let bytes = name.as_bytes();
+    let at = bytes.windows(2).rposition(|pair| pair == b"@v")?;
+    let digits = std::str::from_utf8(&bytes[at + 2..]).ok()?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    Some((OsStr::from_bytes(&bytes[..at]), digits.parse().ok()?))
+}
+
+// one version as a line diff against the one before, as a section of
+// the changes file:
+//
+//   --- v1 2026-10-19T10:00:00Z
+//   +++ v2 2026-10-19T11:00:00Z
+//    same
+//   -old
+//   +new
+pub(crate) fn change(old: (u64, &OsStr, &[u8]), new: (u64, &OsStr, &[u8])) -> Vec<u8> {
+    let ((v1, at1, old), (v2, at2, new)) = (old, new);
+    let mut out = format!(
+        "--- v{} {}\n+++ v{} {}\n",
+        v1,
+        at1.to_string_lossy(),
+        v2,
+        at2.to_string_lossy()
+    );
+    let old = String::from_utf8_lossy(old);
+    let new = String::from_utf8_lossy(new);
+    line_diff(
+        &old.lines().collect::<Vec<_>>(),
+        &new.lines().collect::<Vec<_>>(),
+        &mut out,
+    );
+
+    out.into_bytes()
+}
+
+// longest common subsequence of lines, walked front to back
+fn line_diff(old: &[&str], new: &[&str], out: &mut String) {
+    let (n, m) = (old.len(), new.len());
+    if n * m > MAX_DIFF {
+        old.iter()
+            .for_each(|line| out.push_str(&format!("-{}\n", line)));
+        new.iter()
+            .for_each(|line| out.push_str(&format!("+{}\n", line)));
+        return;
+    }
+
+    // common[i][j]: lcs length of old[i..] and new[j..]
+    let mut common = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i] == new[j] {
+            out.push_str(&format!(" {}\n", old[i]));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && common[i + 1][j] >= common[i][j + 1]) {
+            out.push_str(&format!("-{}\n", old[i]));
+            i += 1;
+        } else {
+            out.push_str(&format!("+{}\n", new[j]));
+            j += 1;
+        }
+    }
+}
@@ -1,12 +1,12 @@
 use std::collections;
 use std::ffi::{OsStr, OsString};
 use std::path;
-use time;
 use time::Timespec;
 
 extern crate file_node;
 use file_node::NodeData;
 use fuse::{FileAttr, FileType};
+use libc::X_OK;
 
 #[derive(Debug)]
 pub struct Inode {
@@ -16,10 +16,16 @@ pub struct Inode {
     pub attr: FileAttr,
     pub xattr: collections::HashMap<OsString, String>,
     pub path: path::PathBuf,
+    // one entry per name linking to this inode, so a hard linked file can
+    // list the same dir twice; root has none
+    pub parents: Vec<u64>,
+    // bumped each time the ino is handed out again, so (ino, generation)
+    // never names two different files
+    pub generation: u64,
 }
 
 impl Inode {
-    pub fn new(id: u64, data: NodeData, name: &OsStr, _uid: u32, _gid: u32) -> Inode {
+    pub fn new(id: u64, data: NodeData, name: &OsStr, uid: u32, gid: u32) -> Inode {
         let ttl = Timespec::new(1, 0);
         let path = path::PathBuf::from(name);
         let kind = match data {
@@ -28,8 +34,12 @@ impl Inode {
             NodeData::ServiceDir(_) => FileType::Directory,
         };
         let mut attr = build_dummy_file(kind);
-        attr.uid = 501;
-        attr.gid = 20;
+        attr.uid = uid;
+        attr.gid = gid;
+        if kind == FileType::RegularFile {
+            attr.perm = 0o644;
+            attr.nlink = 1;
+        }
         Inode {
             id,
             attr,
@@ -37,13 +47,41 @@ impl Inode {
             data,
             ttl,
             xattr: collections::HashMap::new(),
+            parents: Vec::new(),
+            generation: 1,
         }
     }
 
+    // the dir ".." points at; dirs can't be hard linked so theirs is unique
+    pub fn parent(&self) -> u64 {
+        self.parents.first().copied().unwrap_or(self.id)
+    }
+
     pub fn access(&mut self) {
         let now = time::get_time();
         self.attr.atime = now;
     }
+
+    // mask is the access(2) style R_OK | W_OK | X_OK
+    pub fn permits(&self, uid: u32, gid: u32, mask: u32) -> bool {
+        let perm = self.attr.perm as u32;
+        if uid == 0 {
+            // root skips rw checks but still needs some x bit to exec a file
+            return mask & X_OK as u32 == 0
+                || self.attr.kind == FileType::Directory
+                || perm & 0o111 != 0;
+        }
+
+        let granted = if uid == self.attr.uid {
+            perm >> 6
+        } else if gid == self.attr.gid {
+            perm >> 3
+        } else {
+            perm
+        } & 0o7;
+
+        mask & granted == mask
+    }
 }
 
 fn build_dummy_file(kind: FileType) -> FileAttr {
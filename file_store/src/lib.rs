@@ -1,3 +1,5 @@
 pub use log;
+pub mod content;
 pub mod fstore;
+mod history;
 mod inode;
@@ -0,0 +1,49 @@
+//! A service plugin that answers every query with the query itself. Build
+//! it with `cargo build -p echo_plugin` and point `--plugins` at the dir
+//! the library lands in.
+use std::time::Duration;
+
+use file_node::SingleService;
+
+pub struct Echo;
+
+impl SingleService for Echo {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        vec![format!("echo {}", query.unwrap_or_default())]
+    }
+
+    fn get_name(&self) -> String {
+        "echo".to_string()
+    }
+
+    fn list(&self) -> Vec<String> {
+        vec!["hello".to_string()]
+    }
+
+    fn max_age(&self, _query: &str) -> Option<Duration> {
+        Some(Duration::from_secs(60))
+    }
+
+    fn attributes(&self, query: &str) -> Vec<(String, String)> {
+        vec![("user.echoed".to_string(), query.to_string())]
+    }
+
+    fn dirs(&self) -> Vec<Box<dyn SingleService + Send>> {
+        vec![Box::new(Loud)]
+    }
+}
+
+// echoes in capitals, in the dir loud
+pub struct Loud;
+
+impl SingleService for Loud {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        vec![format!("ECHO {}", query.unwrap_or_default().to_uppercase())]
+    }
+
+    fn get_name(&self) -> String {
+        "loud".to_string()
+    }
+}
+
+file_node::export_service!(Echo, || Echo);
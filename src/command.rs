@@ -0,0 +1,230 @@
+// a service backed by any executable, one json object per line on its
+// stdin and stdout:
+//
+//   {"op":"fetch","query":"10002"}                query is null for none
+//   {"op":"list"}
+//   {"op":"write","query":"10002","data":"..."}
+//   {"lines":["first","second"]} or {"error":"what went wrong"}
+//
+// the command is started for every request unless it is persistent
+use std::ffi::OsString;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{mpsc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use file_node::SingleService;
+use libc::EINVAL;
+use serde_json::{Map, Value};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn request(op: &str, query: Option<&str>, data: Option<&str>) -> Value {
+    let mut request = Map::new();
+    request.insert("op".to_string(), Value::String(op.to_string()));
+    if op != "list" {
+        let query = query.map_or(Value::Null, |query| Value::String(query.to_string()));
+        request.insert("query".to_string(), query);
+    }
+    if let Some(data) = data {
+        request.insert("data".to_string(), Value::String(data.to_string()));
+    }
+    Value::Object(request)
+}
+
+// a running command and the lines it has written so far
+struct Worker {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    lines: mpsc::Receiver<String>,
+}
+
+impl Worker {
+    // sends request and waits for the line answering it. last closes stdin
+    // after the request, for commands that take just the one
+    fn ask(&mut self, request: &str, last: bool, timeout: Duration) -> io::Result<String> {
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        writeln!(stdin, "{}", request)?;
+        stdin.flush()?;
+        if last {
+            self.stdin.take();
+        }
+
+        match self.lines.recv_timeout(timeout) {
+            Ok(line) => Ok(line),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "no answer in time"))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "exited without answering",
+            )),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.stdin.take();
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub struct CommandService {
+    name: String,
+    program: OsString,
+    args: Vec<OsString>,
+    timeout: Duration,
+    persistent: bool,
+    worker: Mutex<Option<Worker>>,
+}
+
+impl CommandService {
+    pub fn new<S: Into<OsString>>(name: &str, program: S) -> CommandService {
+        CommandService {
+            name: name.to_string(),
+            program: program.into(),
+            args: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+            persistent: false,
+            worker: Mutex::new(None),
+        }
+    }
+
+    pub fn arg<S: Into<OsString>>(mut self, arg: S) -> CommandService {
+        self.args.push(arg.into());
+        self
+    }
+
+    // how long an answer may take; a command that takes longer is killed
+    pub fn timeout(mut self, timeout: Duration) -> CommandService {
+        self.timeout = timeout;
+        self
+    }
+
+    // keeps one process running for every request
+    pub fn persistent(mut self) -> CommandService {
+        self.persistent = true;
+        self
+    }
+
+    fn start(&self) -> io::Result<Worker> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or(io::ErrorKind::BrokenPipe)?;
+
+        let (send, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line.map(|line| send.send(line)) {
+                    Ok(Ok(())) => (),
+                    _ => break,
+                }
+            }
+        });
+
+        Ok(Worker {
+            stdin: child.stdin.take(),
+            child,
+            lines,
+        })
+    }
+
+    // one request and its answer. a command that dies before answering an
+    // idempotent request is started again once and asked again; one that
+    // doesn't answer in time is not. a write may have been done before the
+    // command died, so it is never sent twice
+    fn ask(&self, request: Value, idempotent: bool) -> io::Result<Value> {
+        let request = serde_json::to_string(&request).map_err(io::Error::other)?;
+        let mut worker = self.worker.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // a command that died since it last answered has seen nothing of this
+        if let Some(running) = worker.as_mut() {
+            if !matches!(running.child.try_wait(), Ok(None)) {
+                worker.take();
+            }
+        }
+
+        let mut may_retry = idempotent;
+        let line = loop {
+            let running = match worker.as_mut() {
+                Some(running) => running,
+                None => worker.insert(self.start()?),
+            };
+            let asked = running.ask(&request, !self.persistent, self.timeout);
+            if asked.is_err() || !self.persistent {
+                worker.take();
+            }
+            match asked {
+                Ok(line) => break line,
+                Err(e) if e.kind() != io::ErrorKind::TimedOut && may_retry => {
+                    log::warn!("{} died ({}), starting it again", self.name, e);
+                    may_retry = false;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        let answer: Value = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", line, e))
+        })?;
+        match answer.get("error").and_then(Value::as_str) {
+            Some(error) => Err(io::Error::other(error.to_string())),
+            None => Ok(answer),
+        }
+    }
+
+    fn lines(&self, request: Value) -> Vec<String> {
+        let answer = match self.ask(request, true) {
+            Ok(answer) => answer,
+            Err(e) => {
+                log::error!("{} failed: {}", self.name, e);
+                return vec![];
+            }
+        };
+
+        answer
+            .get("lines")
+            .and_then(Value::as_array)
+            .map(|lines| {
+                lines
+                    .iter()
+                    .map(|line| match line.as_str() {
+                        Some(line) => line.to_string(),
+                        None => line.to_string(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl SingleService for CommandService {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        self.lines(request("fetch", query, None))
+    }
+
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.lines(request("list", None, None))
+    }
+
+    // requests are JSON, so only text can be written back
+    fn write_back(&self, query: &str, data: &[u8]) -> io::Result<()> {
+        let data = std::str::from_utf8(data).map_err(|_| io::Error::from_raw_os_error(EINVAL))?;
+        self.ask(request("write", Some(query), Some(data)), false)
+            .map(|_| ())
+    }
+}
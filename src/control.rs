@@ -0,0 +1,281 @@
+// the files under /.vfs that show and drive a running mount:
+//
+//   status                  one line per service
+//   stats                   usage of the store
+//   config                  current settings
+//   ctl                     snapshot [name], read-only on|off, load <plugin>
+//   services/<svc>/status   one service in detail
+//   services/<svc>/ctl      refresh, clear
+//
+// reading a ctl file lists its commands; writing runs them, one per line
+use std::ffi::{OsStr, OsString};
+
+use file_store::fstore::FileStore;
+use libc::{c_int, EEXIST, EINVAL, ENOENT, EPERM};
+
+use crate::fuse_system::Fs;
+use crate::ops::Caller;
+
+const CTL: &[&str] = &[
+    "snapshot [name]",
+    "read-only on|off",
+    #[cfg(feature = "plugins")]
+    "load <plugin>",
+];
+const SERVICE_CTL: &[&str] = &["refresh", "clear"];
+
+// a line written to a ctl file
+#[derive(Debug)]
+enum Command<'a> {
+    Snapshot(Option<&'a str>),
+    ReadOnly(bool),
+    #[cfg(feature = "plugins")]
+    Load(&'a str),
+    Refresh(&'a str),
+    Clear(&'a str),
+}
+
+// what line asks of the control file at target, a path split on '/'
+fn parse<'a>(target: &[&'a str], line: &'a str) -> Result<Command<'a>, c_int> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match (target, words.as_slice()) {
+        (["ctl"], ["snapshot"]) => Ok(Command::Snapshot(None)),
+        (["ctl"], ["snapshot", name]) => Ok(Command::Snapshot(Some(name))),
+        (["ctl"], ["read-only", on @ ("on" | "off")]) => Ok(Command::ReadOnly(*on == "on")),
+        #[cfg(feature = "plugins")]
+        (["ctl"], ["load", path]) => Ok(Command::Load(path)),
+        (["services", name, "ctl"], ["refresh"]) => Ok(Command::Refresh(name)),
+        (["services", name, "ctl"], ["clear"]) => Ok(Command::Clear(name)),
+        ([.., "ctl"], _) => {
+            log::error!("unknown command for {}: {:?}", target.join("/"), line);
+            Err(EINVAL)
+        }
+        _ => Err(EPERM),
+    }
+}
+
+// adds the control files of the mount and of every service it has
+pub(crate) fn enable(store: &mut FileStore) -> Result<(), c_int> {
+    if store.control_dir().is_some() {
+        return Ok(());
+    }
+
+    for path in ["status", "stats", "config"] {
+        store.add_control(path, 0o444)?;
+    }
+    // commands change the mount for everyone, so only its owner sends them
+    store.add_control("ctl", 0o600)?;
+    for (_, name) in store.service_dirs() {
+        add_service(store, &name.to_string_lossy());
+    }
+    Ok(())
+}
+
+pub(crate) fn add_service(store: &mut FileStore, name: &str) {
+    for (file, perm) in [("status", 0o444), ("ctl", 0o600)] {
+        let path = format!("services/{}/{}", name, file);
+        match store.add_control(&path, perm) {
+            Ok(_) | Err(EEXIST) => (),
+            Err(e) => log::error!("could not add {}: {}", path, e),
+        }
+    }
+}
+
+pub(crate) fn remove_service(store: &mut FileStore, name: &str) {
+    match store.remove_control(&format!("services/{}", name)) {
+        Ok(_) | Err(ENOENT) => (),
+        Err(e) => log::error!("could not remove controls of {}: {}", name, e),
+    }
+}
+
+fn service_dir(store: &FileStore, name: &str) -> Result<u64, c_int> {
+    store
+        .service_dirs()
+        .into_iter()
+        .find(|(_, dir)| dir == OsStr::new(name))
+        .map(|(id, _)| id)
+        .ok_or(ENOENT)
+}
+
+// the files fetched into a service dir, leaving out its history
+fn service_files(store: &FileStore, dir: &u64) -> Vec<(u64, OsString)> {
+    store
+        .dir_entries(dir)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(id, _)| store.is_service_file(id))
+        .map(|(id, name)| (id, name.to_os_string()))
+        .collect()
+}
+
+fn service_status(store: &FileStore, dir: &u64) -> Vec<(&'static str, String)> {
+    let mut status = vec![("files", service_files(store, dir).len().to_string())];
+    if let Some(config) = store.service_config(dir) {
+        let list = |ids: &[u32]| {
+            ids.iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let open = config.allow_uids.is_empty() && config.allow_gids.is_empty();
+        status.push(("read_only", config.read_only.to_string()));
+        status.push(("history", config.history.to_string()));
+        status.push(("access", if open { "open" } else { "restricted" }.into()));
+        if !open {
+            status.push(("allow_uids", list(&config.allow_uids)));
+            status.push(("allow_gids", list(&config.allow_gids)));
+        }
+    }
+
+    status
+}
+
+fn stats(fs: &Fs, store: &FileStore) -> Vec<(&'static str, String)> {
+    let stats = store.stats();
+    let snapshots = store
+        .snapshots_dir()
+        .and_then(|dir| store.dir_entries(&dir))
+        .map_or(0, |entries| entries.len());
+
+    vec![
+        ("inodes", (stats.files - stats.files_free).to_string()),
+        ("used_bytes", stats.used_bytes.to_string()),
+        (
+            "free_bytes",
+            (stats.blocks_free * stats.block_size as u64).to_string(),
+        ),
+        ("resident_bytes", store.resident_bytes().to_string()),
+        ("services", store.service_dirs().len().to_string()),
+        ("snapshots", snapshots.to_string()),
+        ("threads", fs.threads.to_string()),
+    ]
+}
+
+fn lines<S: AsRef<str>>(lines: impl IntoIterator<Item = S>) -> Vec<u8> {
+    lines
+        .into_iter()
+        .map(|line| format!("{}\n", line.as_ref()))
+        .collect::<String>()
+        .into_bytes()
+}
+
+fn pairs(pairs: Vec<(&'static str, String)>) -> Vec<u8> {
+    lines(
+        pairs
+            .iter()
+            .map(|(key, value)| format!("{} {}", key, value)),
+    )
+}
+
+// what the control file at path reads as right now to req. services closed
+// to req are left out, as they are from its listings
+fn text(fs: &Fs, store: &FileStore, req: &dyn Caller, path: &str) -> Vec<u8> {
+    let permits = |dir: &u64| store.service_permits(dir, req.uid(), req.gid());
+    match path.split('/').collect::<Vec<_>>().as_slice() {
+        ["status"] => lines(
+            store
+                .service_dirs()
+                .iter()
+                .filter(|(dir, _)| permits(dir))
+                .map(|(dir, name)| {
+                    let fields: Vec<String> = service_status(store, dir)
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect();
+                    format!("{} {}", name.to_string_lossy(), fields.join(" "))
+                }),
+        ),
+        ["stats"] => pairs(stats(fs, store)),
+        ["config"] => {
+            let mut config = store.settings();
+            config.push(("threads", fs.threads.to_string()));
+            pairs(config)
+        }
+        ["ctl"] => lines(CTL),
+        ["services", name, "status"] => match service_dir(store, name) {
+            Ok(dir) if permits(&dir) => pairs(service_status(store, &dir)),
+            _ => vec![],
+        },
+        ["services", _, "ctl"] => lines(SERVICE_CTL),
+        _ => vec![],
+    }
+}
+
+impl Fs {
+    // control files are worked out again whenever they are looked at
+    pub(crate) fn fill_control(&self, store: &FileStore, req: &dyn Caller, ino: u64) {
+        if let Some(path) = store.control(&ino) {
+            store.set_control_content(&ino, text(self, store, req, path));
+        }
+    }
+
+    // runs each line written to the control file at path as a command.
+    // every line is checked before any runs, so a bad one runs nothing
+    pub(crate) fn run_control(
+        &self,
+        req: &dyn Caller,
+        path: &str,
+        data: &[u8],
+    ) -> Result<(), c_int> {
+        let target: Vec<&str> = path.split('/').collect();
+        let text = String::from_utf8_lossy(data);
+        let commands = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| parse(&target, line))
+            .collect::<Result<Vec<_>, c_int>>()?;
+
+        for command in commands {
+            log::info!("control {}: {:?}", path, command);
+            match command {
+                Command::Snapshot(name) => self.take_snapshot(name.map(OsStr::new)).map(|_| ()),
+                Command::ReadOnly(on) => {
+                    self.store_mut().set_read_only(on);
+                    Ok(())
+                }
+                #[cfg(feature = "plugins")]
+                Command::Load(path) => self.load_plugin(path),
+                Command::Refresh(name) => self.refresh_service(req, name),
+                Command::Clear(name) => self.clear_service(name),
+            }?;
+        }
+
+        Ok(())
+    }
+
+    // adds the service of the plugin at path
+    #[cfg(feature = "plugins")]
+    fn load_plugin(&self, path: &str) -> Result<(), c_int> {
+        let plugin = crate::plugins::PluginService::load(path).map_err(|e| {
+            log::error!("could not load plugin {}: {}", path, e);
+            EINVAL
+        })?;
+
+        self.services()
+            .add(Box::new(plugin), file_node::ServiceConfig::default())
+    }
+
+    // fetches every file of a service again
+    fn refresh_service(&self, req: &dyn Caller, name: &str) -> Result<(), c_int> {
+        let files = {
+            let store = self.store();
+            service_files(&store, &service_dir(&store, name)?)
+        };
+
+        files
+            .into_iter()
+            .try_for_each(|(ino, _)| self.refresh_file(req, ino).map(|_| ()))
+    }
+
+    // lets go of every file fetched from a service; its history stays
+    fn clear_service(&self, name: &str) -> Result<(), c_int> {
+        let mut store = self.store_mut();
+        let dir = service_dir(&store, name)?;
+        for (_, file) in service_files(&store, &dir) {
+            store.unlink(&dir, &file)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+// a service running a command for each of its files, its output the
+// file's content. a {} argument becomes the file's name; names starting
+// with - are refused rather than passed. how the last run went is in the
+// xattrs user.exec.status (exit code, signal N, timeout or failed) and
+// user.exec.stderr
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::io::Read;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use file_node::SingleService;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+// how often a running command is checked on
+const POLL: Duration = Duration::from_millis(10);
+
+// a command and how long to wait for and keep its output
+#[derive(Clone, Debug)]
+pub struct Exec {
+    program: OsString,
+    args: Vec<OsString>,
+    timeout: Duration,
+    ttl: Option<Duration>,
+}
+
+impl Exec {
+    pub fn new<S: Into<OsString>>(program: S) -> Exec {
+        Exec {
+            program: program.into(),
+            args: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+            ttl: None,
+        }
+    }
+
+    pub fn arg<S: Into<OsString>>(mut self, arg: S) -> Exec {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<S: Into<OsString> + Clone>(self, args: &[S]) -> Exec {
+        args.iter().cloned().fold(self, Exec::arg)
+    }
+
+    // how long the command may run before it is killed
+    pub fn timeout(mut self, timeout: Duration) -> Exec {
+        self.timeout = timeout;
+        self
+    }
+
+    // how long its output is good for; opening the file after that runs
+    // the command again. without one it only runs again on refresh
+    pub fn ttl(mut self, ttl: Duration) -> Exec {
+        self.ttl = Some(ttl);
+        self
+    }
+}
+
+// the outcome of running a command once
+struct Run {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    status: String,
+    at: Instant,
+}
+
+// everything r has to give, on a thread of its own so stdout and stderr
+// can't fill up waiting on each other
+fn read_all<R: Read + Send + 'static>(r: Option<R>) -> mpsc::Receiver<Vec<u8>> {
+    let (send, out) = mpsc::channel();
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut r) = r {
+            let _ = r.read_to_end(&mut bytes);
+        }
+        let _ = send.send(bytes);
+    });
+    out
+}
+
+fn run(exec: &Exec, name: &str) -> Run {
+    let at = Instant::now();
+    // a name standing in for {} could otherwise pass itself off as an option
+    let substitutes = exec.args.iter().any(|arg| arg == "{}");
+    if substitutes && name.starts_with('-') {
+        return Run {
+            stdout: vec![],
+            stderr: format!("refusing to pass {:?} to {:?}", name, exec.program).into_bytes(),
+            status: "failed".to_string(),
+            at,
+        };
+    }
+
+    let args = exec.args.iter().map(|arg| match arg.to_str() {
+        Some("{}") => OsString::from(name),
+        _ => arg.clone(),
+    });
+    let spawned = Command::new(&exec.program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            return Run {
+                stdout: vec![],
+                stderr: format!("can't run {:?}: {}", exec.program, e).into_bytes(),
+                status: "failed".to_string(),
+                at,
+            }
+        }
+    };
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+
+    let deadline = at + exec.timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => match (status.code(), status.signal()) {
+                (Some(code), _) => break code.to_string(),
+                (None, Some(signal)) => break format!("signal {}", signal),
+                (None, None) => break "failed".to_string(),
+            },
+            Ok(None) if Instant::now() < deadline => thread::sleep(POLL),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break "timeout".to_string();
+            }
+            Err(e) => {
+                log::error!("lost track of {:?}: {}", exec.program, e);
+                break "failed".to_string();
+            }
+        }
+    };
+
+    // anything it started may still hold the pipes open, so output that
+    // doesn't come by the deadline is given up on
+    let wait = deadline.saturating_duration_since(Instant::now()).max(POLL);
+    Run {
+        stdout: stdout.recv_timeout(wait).unwrap_or_default(),
+        stderr: stderr.recv_timeout(wait).unwrap_or_default(),
+        status,
+        at,
+    }
+}
+
+pub struct ExecService {
+    name: String,
+    files: BTreeMap<String, Exec>,
+    others: Option<Exec>,
+    // the last run for each file
+    runs: Mutex<HashMap<String, Run>>,
+}
+
+impl ExecService {
+    pub fn new(name: &str) -> ExecService {
+        ExecService {
+            name: name.to_string(),
+            files: BTreeMap::new(),
+            others: None,
+            runs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // runs exec for the file called name; these files are listed
+    pub fn file(mut self, name: &str, exec: Exec) -> ExecService {
+        self.files.insert(name.to_string(), exec);
+        self
+    }
+
+    // runs exec for any other name
+    pub fn others(mut self, exec: Exec) -> ExecService {
+        self.others = Some(exec);
+        self
+    }
+
+    fn exec(&self, name: &str) -> Option<&Exec> {
+        self.files.get(name).or(self.others.as_ref())
+    }
+}
+
+impl SingleService for ExecService {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        let (name, exec) = match query.and_then(|name| Some((name, self.exec(name)?))) {
+            Some(found) => found,
+            None => return vec![],
+        };
+
+        let mut runs = self.runs.lock().unwrap_or_else(PoisonError::into_inner);
+        let fresh = runs
+            .get(name)
+            .is_some_and(|last| exec.ttl.is_some_and(|ttl| last.at.elapsed() < ttl));
+        if !fresh {
+            // commands can be slow, so other files don't wait on this one
+            drop(runs);
+            let ran = run(exec, name);
+            if ran.status != "0" {
+                log::error!("{}/{} exited with {}", self.name, name, ran.status);
+            }
+            runs = self.runs.lock().unwrap_or_else(PoisonError::into_inner);
+            runs.insert(name.to_string(), ran);
+        }
+
+        let stdout = String::from_utf8_lossy(&runs[name].stdout).into_owned();
+        match stdout.strip_suffix('\n').unwrap_or(&stdout) {
+            "" => vec![],
+            out => out.split('\n').map(String::from).collect(),
+        }
+    }
+
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.files.keys().cloned().collect()
+    }
+
+    fn max_age(&self, query: &str) -> Option<Duration> {
+        self.exec(query)?.ttl
+    }
+
+    fn attributes(&self, query: &str) -> Vec<(String, String)> {
+        let runs = self.runs.lock().unwrap_or_else(PoisonError::into_inner);
+        match runs.get(query) {
+            Some(last) => vec![
+                ("user.exec.status".to_string(), last.status.clone()),
+                (
+                    "user.exec.stderr".to_string(),
+                    String::from_utf8_lossy(&last.stderr).into_owned(),
+                ),
+            ],
+            None => vec![],
+        }
+    }
+}
@@ -1,27 +1,46 @@
 use fuse::{
-    FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyOpen, ReplyWrite, Request,
+    Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
 };
+use libc::{c_int, ERANGE};
 use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::Duration;
 use time::Timespec;
 
-use std::path;
-
 extern crate file_store;
+use file_store::content::DirSpill;
 use file_store::fstore::FileStore;
 
-use file_node::SingleService;
+use file_node::{ServiceConfig, SingleService};
+
+use crate::control;
+use crate::handle::ServiceHandle;
+use crate::ops::Creds;
+use crate::workers::Workers;
 
-use libc::{ENOENT, ENOTDIR};
+const FOPEN_DIRECT_IO: u32 = 1;
 
+// the Filesystem callbacks below only translate between fuse and the
+// operations in ops, which hold the actual logic
 pub struct Fs {
-    store: FileStore,
+    pub(crate) store: Arc<RwLock<FileStore>>,
+    // reads, writes and creates run here when set, on the session thread
+    // otherwise
+    workers: Option<Workers>,
+    // how many of them, kept for the copies jobs run with
+    pub(crate) threads: usize,
 }
 
 impl Fs {
     pub fn new(svcs: Vec<Box<dyn SingleService + Send>>) -> Fs {
         let mut fs = Fs {
-            store: FileStore::new(),
+            store: Arc::new(RwLock::new(FileStore::new())),
+            workers: None,
+            threads: 1,
         };
 
         fs.register_services(svcs);
@@ -29,8 +48,163 @@ impl Fs {
         fs
     }
 
+    // a panic on a worker shouldn't take the whole mount down with it
+    pub(crate) fn store(&self) -> RwLockReadGuard<'_, FileStore> {
+        self.store.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn store_mut(&self) -> RwLockWriteGuard<'_, FileStore> {
+        self.store.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
     fn register_services(&mut self, svcs: Vec<Box<dyn SingleService + Send>>) {
-        self.store.register_services(svcs);
+        self.store_mut().register_services(svcs);
+    }
+
+    pub fn register_service(&mut self, svc: Box<dyn SingleService + Send>, config: ServiceConfig) {
+        let name = svc.get_name();
+        if let Err(e) = self.services().add(svc, config) {
+            log::error!("could not register service {:?}: {}", name, e);
+        }
+    }
+
+    // changes the services of the mount from any thread, even once the Fs
+    // has been handed to fuse
+    pub fn services(&self) -> ServiceHandle {
+        ServiceHandle::new(&self.store)
+    }
+
+    pub fn set_service_removal(&mut self, allowed: bool) {
+        self.store_mut().set_service_removal(allowed);
+    }
+
+    pub fn set_capacity(&mut self, bytes: u64) {
+        self.store_mut().set_capacity(bytes);
+    }
+
+    pub fn set_umask(&mut self, umask: u32) {
+        self.store_mut().set_umask(umask);
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.store_mut().set_read_only(read_only);
+    }
+
+    // recycle inode numbers once the kernel has forgotten them
+    pub fn set_ino_reuse(&mut self, reuse: bool) {
+        self.store_mut().set_ino_reuse(reuse);
+    }
+
+    // caps the file content kept in memory, see set_spill_dir
+    pub fn set_memory_budget(&mut self, bytes: u64) {
+        self.store_mut().set_memory_budget(bytes);
+    }
+
+    // where content past the memory budget goes; without one only service
+    // content, which can be fetched again, is let go of
+    pub fn set_spill_dir<P: AsRef<Path>>(&mut self, dir: P) -> io::Result<()> {
+        let spill = DirSpill::new(dir.as_ref())?;
+        self.store_mut().set_spill(Box::new(spill));
+        Ok(())
+    }
+
+    // adds /.snapshots; making a dir in it snapshots the tree and removing
+    // one deletes that snapshot
+    pub fn enable_snapshots(&mut self) {
+        if let Err(e) = self.store_mut().enable_snapshots() {
+            log::error!("could not add snapshots: {}", e);
+        }
+    }
+
+    // adds /.vfs, the files to watch and drive the mount through; see
+    // control
+    pub fn enable_control(&mut self) {
+        if let Err(e) = control::enable(&mut self.store_mut()) {
+            log::error!("could not add control files: {}", e);
+        }
+    }
+
+    pub(crate) fn take_snapshot(&self, name: Option<&OsStr>) -> Result<u64, c_int> {
+        take_snapshot(&self.store, name)
+    }
+
+    // snapshots the tree every interval, keeping only the newest keep of
+    // them when given. stops once the Fs is gone
+    pub fn schedule_snapshots(&mut self, every: Duration, keep: Option<usize>) {
+        self.enable_snapshots();
+        let store = Arc::downgrade(&self.store);
+        thread::spawn(move || loop {
+            thread::sleep(every);
+            let store = match store.upgrade() {
+                Some(store) => store,
+                None => break,
+            };
+            if let Err(e) = take_snapshot(&store, None) {
+                log::error!("scheduled snapshot failed: {}", e);
+            }
+            let mut store = store.write().unwrap_or_else(PoisonError::into_inner);
+            if let Some(keep) = keep {
+                if let Err(e) = store.prune_snapshots(keep) {
+                    log::error!("could not prune snapshots: {}", e);
+                }
+            }
+        });
+    }
+
+    // how many threads serve reads, writes and service fetches; one keeps
+    // everything on the session thread
+    pub fn set_threads(&mut self, count: usize) {
+        self.threads = count.max(1);
+        self.workers = if count > 1 {
+            Some(Workers::new(count))
+        } else {
+            None
+        };
+    }
+
+    // runs job on a worker, or right away without any
+    fn spawn<F: FnOnce(&Fs) + Send + 'static>(&self, job: F) {
+        match &self.workers {
+            Some(workers) => {
+                let fs = Fs {
+                    store: self.store.clone(),
+                    workers: None,
+                    threads: self.threads,
+                };
+                workers.run(move || job(&fs));
+            }
+            None => job(self),
+        }
+    }
+}
+
+// a snapshot is planned with the store shared, so only putting it in place
+// holds up everything else. dropped service files are fetched again before,
+// with the store let go of, so planning doesn't wait on services
+fn take_snapshot(store: &RwLock<FileStore>, name: Option<&OsStr>) -> Result<u64, c_int> {
+    let shared = || store.read().unwrap_or_else(PoisonError::into_inner);
+    let dropped = shared().dropped_files();
+    for (ino, refetch) in dropped {
+        let data = refetch();
+        shared().restore(&ino, &refetch, data);
+    }
+
+    let plan = shared().plan_snapshot()?;
+    store
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .finish_snapshot(plan, name)
+}
+
+// a size of 0 asks how big the value is, anything else how much room
+// there is for it
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
     }
 }
 
@@ -41,112 +215,108 @@ impl Filesystem for Fs {
         Ok(())
     }
 
-    fn access(&mut self, _req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
-        log::error!("access: {} {}", ino, mask);
-        reply.ok();
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        match self.check_access(req, ino, mask) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        log::error!("called lookup");
-        match self.store.lookup_path(&parent, name) {
-            Some(file) => {
-                log::error!("found file: {:?}", file);
-                let _data = &file.data;
-
-                // the generation final arg needs to be the id.
-                // seems similar to fh wtf
-                reply.entry(&file.ttl, &file.attr, file.id);
-            }
-            None => {
-                log::error!("no file found in lookup: {:?} {:?}", name, parent);
-                reply.error(ENOENT);
-            }
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_entry(req, parent, name) {
+            Ok(entry) => reply.entry(&entry.ttl, &entry.attr, entry.generation),
+            Err(e) => reply.error(e),
         }
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        log::error!("unlink {} {:?}", parent, name);
-        self.store.unlink(&parent, name);
-        reply.ok();
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove_dir(req, parent, name) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn rename(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
         reply: ReplyEmpty,
     ) {
-        match self.store.rename(&parent, name, newparent, newname) {
+        match self.rename_entry(req, parent, name, newparent, newname) {
             Ok(_) => reply.ok(),
-            Err(_) => reply.error(ENOENT),
+            Err(e) => reply.error(e),
         }
     }
 
-    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
-        log::info!("creating a dir");
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.forget_entry(ino, nlookup);
+    }
 
-        let node = self.store.create_dir(parent, name, mode);
-        let ttl = Timespec::new(1, 0);
-        match node {
-            Some(dir) => {
-                reply.entry(&ttl, &dir.attr, dir.id);
-            }
-            _ => reply.error(ENOENT),
+    fn link(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        match self.link_entry(req, ino, newparent, newname) {
+            Ok(entry) => reply.entry(&entry.ttl, &entry.attr, entry.generation),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        match self.make_dir(req, parent, name, mode) {
+            Ok(entry) => reply.entry(&entry.ttl, &entry.attr, entry.generation),
+            Err(e) => reply.error(e),
         }
     }
 
     fn create(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         mode: u32,
-        flags: u32,
+        _flags: u32,
         reply: ReplyCreate,
     ) {
-        let _now = time::now().to_timespec();
-        log::error!("create: {}, {:?}, {}, {}", parent, name, mode, flags);
-        let id = self.store.touch_file(&parent, name);
-        match self.store.get(&id) {
-            Some(f) => {
-                let file = f.attr;
-                let ttl = Timespec::new(1, 0);
-                log::error!("got through create");
-                reply.created(&ttl, &file, id, id, flags);
-            }
-            None => {
-                log::error!("not a valid parent");
-                reply.error(ENOTDIR);
-            }
-        }
+        let (creds, name) = (Creds::from(req), name.to_os_string());
+        self.spawn(
+            move |fs| match fs.create_file(&creds, parent, &name, mode) {
+                Ok(entry) => {
+                    let fh = entry.attr.ino;
+                    // control files can't be created, so no FOPEN_* flags are needed
+                    reply.created(&entry.ttl, &entry.attr, entry.generation, fh, 0);
+                }
+                Err(e) => reply.error(e),
+            },
+        );
     }
 
     fn read(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         _fh: u64,
         offset: i64,
-        _size: u32,
+        size: u32,
         reply: ReplyData,
     ) {
-        match self.store.read_file(&ino) {
-            Some(data) => {
-                // need to also restrict length of response by size bytes
-                let d = &data[offset as usize..];
-
-                reply.data(&d)
-            }
-            None => reply.error(ENOENT),
-        }
+        let creds = Creds::from(req);
+        self.spawn(move |fs| match fs.read_data(&creds, ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        });
     }
 
     fn write(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         fh: u64,
         offset: i64,
@@ -154,58 +324,47 @@ impl Filesystem for Fs {
         flags: u32,
         reply: ReplyWrite,
     ) {
-        log::error!("write: {} {} {} {:?} {}", ino, fh, offset, data, flags);
-        let w_size = std::mem::size_of_val(data) as u32;
-        log::error!("write size: {}", w_size as u32);
-        let size = self.store.write(ino, data, flags, offset);
-        log::error!("size={}", size);
-        // must return exact same size as data that was requested to be written
-        // or else stupid io invalid arg error or something happens
-        // really stupid
-        reply.written(w_size)
+        log::error!("write fh: {}", fh);
+        let (creds, data) = (Creds::from(req), data.to_vec());
+        self.spawn(
+            move |fs| match fs.write_data(&creds, ino, offset, &data, flags) {
+                Ok(size) => reply.written(size),
+                Err(e) => reply.error(e),
+            },
+        );
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let stats = self.stat_fs();
+        reply.statfs(
+            stats.blocks,
+            stats.blocks_free,
+            stats.blocks_free,
+            stats.files,
+            stats.files_free,
+            stats.block_size,
+            stats.name_len,
+            stats.block_size,
+        );
     }
 
     fn readdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        log::error!("readdir: {}, {}, {}", ino, fh, offset);
-        match self.store.read_dir_children(&ino) {
-            Some(children) => {
-                let mut idx: u64 = 0;
-                let offset = offset as u64;
-                if offset > 2 {
-                    idx = offset - 2;
-                }
-
-                let len = children.len() as u64;
-                if offset < len + 1 as u64 {
-                    reply.add(1, 0, FileType::Directory, &path::Path::new("."));
-                    reply.add(1, 1, FileType::Directory, &path::Path::new(".."));
-                    let mut ctr = 2 + offset as i64;
-                    for id in children.range(idx..) {
-                        match self.store.get(&id) {
-                            Some(f) => {
-                                reply.add(f.id, ctr, f.attr.kind, &f.path);
-                                ctr += 1;
-                                log::error!("{:?}", f);
-                            }
-                            None => log::error!(
-                                "dangling child reference: parent={} child={}",
-                                &ino,
-                                &id
-                            ),
-                        }
-                    }
-                }
-                reply.ok();
-            }
-            None => reply.error(ENOENT),
-        }
+        log::error!("readdir fh: {}", fh);
+        // listing a service dir can fetch
+        let creds = Creds::from(req);
+        self.spawn(
+            move |fs| match fs.read_dir(&creds, ino, offset, &mut reply) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(e),
+            },
+        );
     }
 
     /*
@@ -227,7 +386,7 @@ impl Filesystem for Fs {
 
     fn setattr(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         mode: Option<u32>,
         gid: Option<u32>,
@@ -261,16 +420,36 @@ impl Filesystem for Fs {
             flags
         );
         let now = Timespec::new(1, 0);
-        if let Some(_) = size {
-            self.store.clear_file(&ino);
+        // touching a service file fetches it again
+        if mtime.is_some() && self.store().is_service_file(&ino) {
+            let creds = Creds::from(req);
+            self.spawn(move |fs| {
+                match fs
+                    .refresh_file(&creds, ino)
+                    .and_then(|_| fs.set_attr(&creds, ino, mode, uid, gid, size))
+                {
+                    Ok(attr) => reply.attr(&now, &attr),
+                    Err(e) => reply.error(e),
+                }
+            });
+            return;
+        }
+        let set = self
+            .set_attr(req, ino, mode, uid, gid, size)
+            .and_then(|_| self.set_times(req, ino, atime, mtime));
+        match set {
+            Ok(attr) => reply.attr(&now, &attr),
+            Err(e) => reply.error(e),
         }
-        let file = self.store.get(&ino).unwrap();
-        reply.attr(&now, &file.attr);
     }
 
-    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+    fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         log::error!("flush: {}, {}, {}", ino, fh, lock_owner);
-        reply.ok();
+        let creds = Creds::from(req);
+        self.spawn(move |fs| match fs.flush_file(&creds, ino) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        });
     }
 
     fn release(
@@ -292,28 +471,49 @@ impl Filesystem for Fs {
         log::error!("opendir: {}, {}", ino, flags);
     }
     */
-    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
-        log::error!("open called {:?} {:?}", ino, flags);
-        reply.opened(ino, flags);
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        // control files change size between reads, so the page cache and
+        // the size the kernel last saw are no use for them. flags are
+        // open(2)'s, not FOPEN_* ones, so other files are opened with none
+        let open_flags = if self.store().is_control(&ino) {
+            FOPEN_DIRECT_IO
+        } else {
+            0
+        };
+        // a stale service file is fetched again on open
+        let creds = Creds::from(req);
+        self.spawn(move |fs| match fs.open_file(&creds, ino, flags) {
+            Ok(fh) => reply.opened(fh, open_flags),
+            Err(e) => reply.error(e),
+        });
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        match self.store.get(&ino) {
-            Some(file) => {
-                let ttl = Timespec::new(1, 0);
-                log::info!("found filez: {:?} {:?}", file.attr, ttl);
-                reply.attr(&ttl, &file.attr);
-            }
-            None => {
-                log::error!("none found! {:?}", ino,);
-                reply.error(ENOENT);
-            }
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        match self.get_xattr(req, ino, name) {
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(e) => reply.error(e),
         }
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        log::error!("unlink {} {:?}", parent, name);
-        self.store.unlink(&parent, name);
-        reply.ok();
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        match self.list_xattr(req, ino) {
+            Ok(names) => reply_xattr(reply, size, &names),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        let ttl = Timespec::new(1, 0);
+        match self.get_attr(req, ino) {
+            Ok(attr) => reply.attr(&ttl, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove_file(req, parent, name) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 }
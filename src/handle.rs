@@ -0,0 +1,101 @@
+// adds, replaces and removes services while the mount is up. fuse 0.3
+// can't make the kernel drop its cache, so a removed dir lingers for the
+// 1s its entries are good for; a service added in its place gets a new ino
+// or generation, so nothing cached for the old one is taken for it
+use std::ffi::OsStr;
+use std::sync::{Arc, PoisonError, RwLock, Weak};
+
+use file_node::{ServiceConfig, SingleService};
+use file_store::fstore::{self, FileStore};
+use libc::{c_int, EINVAL, ENODEV, ENOENT};
+
+use crate::control;
+
+// a cheap, thread safe reference to the services of a mount; once the
+// mount is gone every call fails with ENODEV
+#[derive(Clone)]
+pub struct ServiceHandle {
+    store: Weak<RwLock<FileStore>>,
+}
+
+impl ServiceHandle {
+    pub(crate) fn new(store: &Arc<RwLock<FileStore>>) -> ServiceHandle {
+        ServiceHandle {
+            store: Arc::downgrade(store),
+        }
+    }
+
+    fn with_store<T>(&self, f: impl FnOnce(&mut FileStore) -> T) -> Result<T, c_int> {
+        let store = self.store.upgrade().ok_or(ENODEV)?;
+        let mut store = store.write().unwrap_or_else(PoisonError::into_inner);
+        Ok(f(&mut store))
+    }
+
+    // fails with EEXIST when the name is taken
+    pub fn add(
+        &self,
+        svc: Box<dyn SingleService + Send>,
+        config: ServiceConfig,
+    ) -> Result<(), c_int> {
+        self.with_store(|store| add(store, svc, config))?
+    }
+
+    // puts svc in place of the service with its name, or adds it. what the
+    // old one fetched goes with it. the swap is made under one hold of the
+    // store, so nobody sees the name missing in between, and whatever would
+    // make the add fail is refused before the old one is taken out: a bad
+    // name here, anything but a service dir holding it in remove
+    pub fn replace(
+        &self,
+        svc: Box<dyn SingleService + Send>,
+        config: ServiceConfig,
+    ) -> Result<(), c_int> {
+        let name = svc.get_name();
+        if !fstore::service_name(OsStr::new(&name)) {
+            return Err(EINVAL);
+        }
+
+        self.with_store(|store| {
+            match remove(store, &name) {
+                Ok(_) | Err(ENOENT) => (),
+                Err(e) => return Err(e),
+            }
+            add(store, svc, config)
+        })?
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), c_int> {
+        self.with_store(|store| remove(store, name))?
+    }
+
+    pub fn names(&self) -> Result<Vec<String>, c_int> {
+        self.with_store(|store| {
+            store
+                .service_dirs()
+                .into_iter()
+                .map(|(_, name)| name.to_string_lossy().into_owned())
+                .collect()
+        })
+    }
+}
+
+fn add(
+    store: &mut FileStore,
+    svc: Box<dyn SingleService + Send>,
+    config: ServiceConfig,
+) -> Result<(), c_int> {
+    let name = svc.get_name();
+    store.add_service(svc, config)?;
+    if store.control_dir().is_some() {
+        control::add_service(store, &name);
+    }
+    log::info!("added service {}", name);
+    Ok(())
+}
+
+fn remove(store: &mut FileStore, name: &str) -> Result<(), c_int> {
+    store.remove_service(OsStr::new(name))?;
+    control::remove_service(store, name);
+    log::info!("removed service {}", name);
+    Ok(())
+}
@@ -0,0 +1,302 @@
+// drives Fs in-process by path, so filesystem semantics and services can
+// be tested without /dev/fuse
+use fuse::{FileAttr, FileType, FUSE_ROOT_ID};
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path};
+
+use file_node::{ServiceConfig, SingleService};
+use file_store::fstore::StoreStats;
+use libc::{c_int, EINVAL, ENOENT, O_RDONLY, O_WRONLY};
+
+use crate::fuse_system::Fs;
+use crate::ops::{Creds, DirFiller, Entry};
+
+// the uid/gid a fuse::Request would carry
+pub type MockRequest = Creds;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub offset: i64,
+    pub kind: FileType,
+    pub name: OsString,
+}
+
+// collects what a fuse::ReplyDirectory would send; limit caps the number of
+// entries accepted per call, like a kernel buffer filling up
+#[derive(Debug, Default)]
+pub struct MockReplyDirectory {
+    pub entries: Vec<DirEntry>,
+    pub limit: Option<usize>,
+}
+
+impl DirFiller for MockReplyDirectory {
+    fn add(&mut self, ino: u64, offset: i64, kind: FileType, name: &OsStr) -> bool {
+        if self.limit.is_some_and(|limit| self.entries.len() >= limit) {
+            return true;
+        }
+
+        self.entries.push(DirEntry {
+            ino,
+            offset,
+            kind,
+            name: name.to_os_string(),
+        });
+        self.limit.is_some_and(|limit| self.entries.len() >= limit)
+    }
+}
+
+pub struct Harness {
+    pub fs: Fs,
+    pub req: MockRequest,
+    // entries per readdir call, None for unbounded
+    pub readdir_limit: Option<usize>,
+}
+
+impl Harness {
+    pub fn new(svcs: Vec<Box<dyn SingleService + Send>>) -> Harness {
+        Harness {
+            fs: Fs::new(svcs),
+            req: MockRequest {
+                uid: unsafe { libc::getuid() },
+                gid: unsafe { libc::getgid() },
+            },
+            readdir_limit: None,
+        }
+    }
+
+    pub fn with_service(svc: Box<dyn SingleService + Send>, config: ServiceConfig) -> Harness {
+        let mut h = Harness::new(vec![]);
+        h.fs.register_service(svc, config);
+        h
+    }
+
+    // subsequent calls are made as this user
+    pub fn as_user(&mut self, uid: u32, gid: u32) -> &mut Harness {
+        self.req = MockRequest { uid, gid };
+        self
+    }
+
+    pub fn resolve(&mut self, path: &str) -> Result<u64, c_int> {
+        let mut ino = FUSE_ROOT_ID;
+        for name in components(path)? {
+            ino = self.fs.lookup_entry(&self.req, ino, &name)?.attr.ino;
+        }
+
+        Ok(ino)
+    }
+
+    pub fn lookup(&mut self, path: &str) -> Result<Entry, c_int> {
+        let (parent, name) = self.split(path)?;
+        self.fs.lookup_entry(&self.req, parent, &name)
+    }
+
+    pub fn getattr(&mut self, path: &str) -> Result<FileAttr, c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.get_attr(&self.req, ino)
+    }
+
+    pub fn access(&mut self, path: &str, mask: u32) -> Result<(), c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.check_access(&self.req, ino, mask)
+    }
+
+    pub fn mkdir(&mut self, path: &str, mode: u32) -> Result<FileAttr, c_int> {
+        let (parent, name) = self.split(path)?;
+        Ok(self.fs.make_dir(&self.req, parent, &name, mode)?.attr)
+    }
+
+    pub fn create(&mut self, path: &str, mode: u32) -> Result<FileAttr, c_int> {
+        let (parent, name) = self.split(path)?;
+        Ok(self.fs.create_file(&self.req, parent, &name, mode)?.attr)
+    }
+
+    // like `echo -n data > path`: creates the file if needed, then replaces
+    // its contents
+    pub fn write(&mut self, path: &str, data: &[u8]) -> Result<u32, c_int> {
+        let ino = match self.resolve(path) {
+            Ok(ino) => {
+                self.fs.open_file(&self.req, ino, O_WRONLY as u32)?;
+                self.fs
+                    .set_attr(&self.req, ino, None, None, None, Some(0))?;
+                ino
+            }
+            Err(ENOENT) => self.create(path, 0o644)?.ino,
+            Err(e) => return Err(e),
+        };
+
+        let written = self
+            .fs
+            .write_data(&self.req, ino, 0, data, O_WRONLY as u32)?;
+        self.fs.flush_file(&self.req, ino)?;
+        Ok(written)
+    }
+
+    // what closing path does
+    pub fn flush(&mut self, path: &str) -> Result<(), c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.flush_file(&self.req, ino)
+    }
+
+    pub fn write_at(&mut self, path: &str, offset: i64, data: &[u8]) -> Result<u32, c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.open_file(&self.req, ino, O_WRONLY as u32)?;
+        self.fs
+            .write_data(&self.req, ino, offset, data, O_WRONLY as u32)
+    }
+
+    pub fn read(&mut self, path: &str) -> Result<Vec<u8>, c_int> {
+        // opening can fetch the file again, so its size comes after
+        let ino = self.resolve(path)?;
+        self.fs.open_file(&self.req, ino, O_RDONLY as u32)?;
+        let size = self.fs.get_attr(&self.req, ino)?.size;
+        self.fs.read_data(&self.req, ino, 0, size as u32)
+    }
+
+    pub fn read_at(&mut self, path: &str, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.open_file(&self.req, ino, O_RDONLY as u32)?;
+        self.fs.read_data(&self.req, ino, offset, size)
+    }
+
+    pub fn truncate(&mut self, path: &str, size: u64) -> Result<FileAttr, c_int> {
+        let ino = self.resolve(path)?;
+        self.fs
+            .set_attr(&self.req, ino, None, None, None, Some(size))
+    }
+
+    // like touch(1) on anything but a service file
+    pub fn touch(&mut self, path: &str) -> Result<FileAttr, c_int> {
+        let ino = self.resolve(path)?;
+        let now = Some(time::get_time());
+        self.fs.set_times(&self.req, ino, now, now)
+    }
+
+    // like touch(1) on a service file: fetches it again
+    pub fn refresh(&mut self, path: &str) -> Result<FileAttr, c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.refresh_file(&self.req, ino)
+    }
+
+    pub fn getxattr(&mut self, path: &str, name: &str) -> Result<Vec<u8>, c_int> {
+        let ino = self.resolve(path)?;
+        self.fs.get_xattr(&self.req, ino, OsStr::new(name))
+    }
+
+    pub fn listxattr(&mut self, path: &str) -> Result<Vec<String>, c_int> {
+        let ino = self.resolve(path)?;
+        let names = self.fs.list_xattr(&self.req, ino)?;
+        Ok(names
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect())
+    }
+
+    pub fn chmod(&mut self, path: &str, mode: u32) -> Result<FileAttr, c_int> {
+        let ino = self.resolve(path)?;
+        self.fs
+            .set_attr(&self.req, ino, Some(mode), None, None, None)
+    }
+
+    // every entry, "." and ".." included, paging the way the kernel does
+    pub fn readdir_entries(&mut self, path: &str) -> Result<Vec<DirEntry>, c_int> {
+        let ino = self.resolve(path)?;
+        let mut entries: Vec<DirEntry> = Vec::new();
+        let mut offset = 0;
+        loop {
+            let mut reply = MockReplyDirectory {
+                entries: Vec::new(),
+                limit: self.readdir_limit,
+            };
+            self.fs.read_dir(&self.req, ino, offset, &mut reply)?;
+            let last = match reply.entries.last() {
+                Some(entry) => entry.offset,
+                None => break,
+            };
+            entries.extend(reply.entries);
+            // an offset that doesn't move forward would loop forever
+            if last <= offset {
+                break;
+            }
+            offset = last;
+        }
+
+        Ok(entries)
+    }
+
+    pub fn readdir(&mut self, path: &str) -> Result<Vec<String>, c_int> {
+        let names = self
+            .readdir_entries(path)?
+            .into_iter()
+            .map(|entry| entry.name.to_string_lossy().into_owned())
+            .filter(|name| name != "." && name != "..")
+            .collect();
+
+        Ok(names)
+    }
+
+    pub fn unlink(&mut self, path: &str) -> Result<(), c_int> {
+        let (parent, name) = self.split(path)?;
+        self.fs.remove_file(&self.req, parent, &name)
+    }
+
+    pub fn rmdir(&mut self, path: &str) -> Result<(), c_int> {
+        let (parent, name) = self.split(path)?;
+        self.fs.remove_dir(&self.req, parent, &name)
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), c_int> {
+        let (parent, name) = self.split(from)?;
+        let (newparent, newname) = self.split(to)?;
+        self.fs
+            .rename_entry(&self.req, parent, &name, newparent, &newname)
+    }
+
+    pub fn link(&mut self, from: &str, to: &str) -> Result<FileAttr, c_int> {
+        let ino = self.resolve(from)?;
+        let (newparent, newname) = self.split(to)?;
+        Ok(self
+            .fs
+            .link_entry(&self.req, ino, newparent, &newname)?
+            .attr)
+    }
+
+    // where the store thinks ino lives, for checking renames and links
+    pub fn path_of(&self, ino: u64) -> Option<String> {
+        let path = self.fs.store().full_path(&ino)?;
+        Some(path.to_string_lossy().into_owned())
+    }
+
+    // see FileStore::check_invariants
+    pub fn check_invariants(&self) -> Result<(), Vec<String>> {
+        self.fs.store().check_invariants()
+    }
+
+    pub fn statfs(&mut self) -> StoreStats {
+        self.fs.stat_fs()
+    }
+
+    // resolves everything but the last component
+    fn split(&mut self, path: &str) -> Result<(u64, OsString), c_int> {
+        let mut names = components(path)?;
+        let name = names.pop().ok_or(EINVAL)?;
+        let mut parent = FUSE_ROOT_ID;
+        for dir in names {
+            parent = self.fs.lookup_entry(&self.req, parent, &dir)?.attr.ino;
+        }
+
+        Ok((parent, name))
+    }
+}
+
+fn components(path: &str) -> Result<Vec<OsString>, c_int> {
+    Path::new(path)
+        .components()
+        .filter_map(|c| match c {
+            Component::RootDir | Component::CurDir => None,
+            Component::Normal(name) => Some(Ok(name.to_os_string())),
+            _ => Some(Err(EINVAL)),
+        })
+        .collect()
+}
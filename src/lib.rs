@@ -1,27 +1,157 @@
-use std::{env, io};
+use std::path::Path;
+use std::time::Duration;
+use std::{env, io, thread};
 
+pub mod command;
+mod control;
+pub mod exec;
 pub mod fuse_system;
+pub mod handle;
+pub mod harness;
+pub mod ops;
+#[cfg(feature = "plugins")]
+pub mod plugins;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod services;
+#[cfg(feature = "sql")]
+pub mod sql;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+mod workers;
 //pub use fuse_system::{Fs};
 extern crate file_node;
 
-pub use file_node::{ServiceDirNode, SingleService};
+pub use file_node::{ServiceConfig, ServiceDirNode, SingleService};
+pub use handle::ServiceHandle;
 
-pub fn run(svcs: Vec<Box<dyn SingleService + Send>>) {
-    unsafe {
-        let fs = fuse_system::Fs::new(svcs);
+// the value following flag among the arguments after the mountpoint
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    let i = args.iter().position(|arg| arg == flag)?;
+    args.get(i + 1).cloned()
+}
+
+// args are the flags given after the mountpoint: --read-only to refuse all user modifications,
+// --threads N to set how many requests are served at once,
+// --memory BYTES with an optional --spill-dir DIR to bound the file
+// content held in memory, and --snapshots or --snapshot-every SECS (with
+// --snapshot-keep N) to add /.snapshots. --no-control leaves out /.vfs,
+// with the plugins feature --plugins DIR adds the services of every
+// plugin in DIR, with the wasm feature --wasm DIR one sandboxed service
+// for every module in DIR, with the scripting feature --scripts DIR one
+// service for every rhai script in DIR, and with the sql feature --sql DB
+// serves the sqlite database DB, with the named queries of
+// --sql-queries FILE
+fn build_fs(svcs: Vec<Box<dyn SingleService + Send>>, args: &[String]) -> fuse_system::Fs {
+    let mut fs = fuse_system::Fs::new(svcs);
+    if !args.iter().any(|arg| arg == "--no-control") {
+        fs.enable_control();
+    }
+    if args.iter().any(|arg| arg == "--read-only") {
+        fs.set_read_only(true);
+    }
+
+    // one thread per core unless told otherwise
+    let threads = flag_value(args, "--threads")
+        .and_then(|n| n.parse().ok())
+        .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1);
+    fs.set_threads(threads);
+
+    if let Some(dir) = flag_value(args, "--spill-dir") {
+        if let Err(e) = fs.set_spill_dir(&dir) {
+            log::error!("can't spill to {}: {}", dir, e);
+        }
+    }
+    if let Some(bytes) = flag_value(args, "--memory").and_then(|n| n.parse().ok()) {
+        fs.set_memory_budget(bytes);
+    }
+
+    #[cfg(feature = "plugins")]
+    if let Some(dir) = flag_value(args, "--plugins") {
+        match plugins::load_dir(&dir) {
+            Ok(loaded) => loaded
+                .into_iter()
+                .for_each(|plugin| fs.register_service(Box::new(plugin), ServiceConfig::default())),
+            Err(e) => log::error!("can't load plugins from {}: {}", dir, e),
+        }
+    }
+
+    #[cfg(feature = "wasm")]
+    if let Some(dir) = flag_value(args, "--wasm") {
+        match wasm::load_dir(&dir) {
+            Ok(loaded) => loaded
+                .into_iter()
+                .for_each(|svc| fs.register_service(Box::new(svc), ServiceConfig::default())),
+            Err(e) => log::error!("can't load wasm services from {}: {}", dir, e),
+        }
+    }
 
-        let mnt = match env::args().nth(1) {
-            Some(path) => path,
-            None => "./test_dir".to_string(),
-        };
+    #[cfg(feature = "scripting")]
+    if let Some(dir) = flag_value(args, "--scripts") {
+        match script::load_dir(&dir) {
+            Ok(loaded) => loaded
+                .into_iter()
+                .for_each(|svc| fs.register_service(Box::new(svc), ServiceConfig::default())),
+            Err(e) => log::error!("can't load scripts from {}: {}", dir, e),
+        }
+    }
 
-        println!("{}", mnt);
-        let _sys = fuse::spawn_mount(fs, &mnt, &[]).unwrap();
-        let mut str = String::new();
+    #[cfg(feature = "sql")]
+    if let Some(db) = flag_value(args, "--sql") {
+        let name = Path::new(&db)
+            .file_stem()
+            .map_or("sql".into(), |stem| stem.to_string_lossy());
+        let svc = sql::SqlService::open(&name, &db).and_then(|svc| {
+            match flag_value(args, "--sql-queries") {
+                Some(queries) => svc.queries_from(queries),
+                None => Ok(svc),
+            }
+        });
+        match svc {
+            Ok(svc) => fs.register_service(Box::new(svc), ServiceConfig::default()),
+            Err(e) => log::error!("can't serve {}: {}", db, e),
+        }
+    }
 
-        io::stdin().read_line(&mut str).expect("invalid input");
-        println!("all done!");
+    let keep = flag_value(args, "--snapshot-keep").and_then(|n| n.parse().ok());
+    match flag_value(args, "--snapshot-every").and_then(|n| n.parse().ok()) {
+        Some(secs) => fs.schedule_snapshots(Duration::from_secs(secs), keep),
+        None if args.iter().any(|arg| arg == "--snapshots") => fs.enable_snapshots(),
+        None => (),
     }
+
+    fs
+}
+
+// mounts on a background thread, set up by the flags in args. services
+// can be changed through the handle for as long as the session is kept;
+// dropping it unmounts
+pub fn spawn<P: AsRef<Path>>(
+    svcs: Vec<Box<dyn SingleService + Send>>,
+    mountpoint: &P,
+    args: &[String],
+) -> io::Result<(fuse::BackgroundSession<'static>, ServiceHandle)> {
+    let fs = build_fs(svcs, args);
+    let services = fs.services();
+    let session = unsafe { fuse::spawn_mount(fs, mountpoint, &[])? };
+
+    Ok((session, services))
+}
+
+pub fn run(svcs: Vec<Box<dyn SingleService + Send>>) {
+    let mnt = match env::args().nth(1) {
+        Some(path) => path,
+        None => "./test_dir".to_string(),
+    };
+
+    println!("{}", mnt);
+    let args: Vec<String> = env::args().skip(2).collect();
+    let (_sys, _services) = spawn(svcs, &mnt, &args).unwrap();
+    let mut str = String::new();
+
+    io::stdin().read_line(&mut str).expect("invalid input");
+    println!("all done!");
 }
 
 pub fn init(svc: Vec<Box<dyn SingleService + Send>>) {
@@ -32,10 +162,11 @@ pub fn init(svc: Vec<Box<dyn SingleService + Send>>) {
         None => "./test_dir".to_string(),
     };
 
-    let fs = fuse_system::Fs::new(svc);
+    let args: Vec<String> = env::args().skip(2).collect();
+    let fs = build_fs(svc, &args);
 
     println!("{}", mnt);
-    let _sys = fuse::mount(fs, &mnt, &[]).unwrap();
+    fuse::mount(fs, &mnt, &[]).unwrap();
     let mut str = String::new();
 
     io::stdin().read_line(&mut str).expect("invalid input");
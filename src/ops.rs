@@ -0,0 +1,719 @@
+use fuse::{FileAttr, FileType, ReplyDirectory, Request, FUSE_ROOT_ID};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::Arc;
+use time::Timespec;
+
+use file_node::SingleService;
+use file_store::fstore::{self, FileStore, StoreStats};
+use libc::{
+    c_int, EEXIST, EINVAL, EIO, ENODATA, ENOENT, ENOTDIR, ESTALE, O_ACCMODE, O_RDONLY, O_TRUNC,
+    O_WRONLY, R_OK, W_OK, X_OK,
+};
+
+use crate::control;
+use crate::fuse_system::Fs;
+
+// how many new names of a service one listing fetches
+const LIST_FETCHES: usize = 32;
+
+// the parts of a fuse::Request the operations need, so they can be driven
+// without a kernel mount (see harness)
+pub trait Caller {
+    fn uid(&self) -> u32;
+    fn gid(&self) -> u32;
+}
+
+impl Caller for Request<'_> {
+    fn uid(&self) -> u32 {
+        Request::uid(self)
+    }
+
+    fn gid(&self) -> u32 {
+        Request::gid(self)
+    }
+}
+
+// a caller copied out of its request, for work finished on another thread
+#[derive(Debug, Clone, Copy)]
+pub struct Creds {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Caller for Creds {
+    fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    fn gid(&self) -> u32 {
+        self.gid
+    }
+}
+
+impl From<&Request<'_>> for Creds {
+    fn from(req: &Request<'_>) -> Creds {
+        Creds {
+            uid: req.uid(),
+            gid: req.gid(),
+        }
+    }
+}
+
+// receives readdir entries; add returns true once the buffer is full
+pub trait DirFiller {
+    fn add(&mut self, ino: u64, offset: i64, kind: FileType, name: &OsStr) -> bool;
+}
+
+impl DirFiller for ReplyDirectory {
+    fn add(&mut self, ino: u64, offset: i64, kind: FileType, name: &OsStr) -> bool {
+        ReplyDirectory::add(self, ino, offset, kind, name)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub ttl: Timespec,
+    pub attr: FileAttr,
+    pub generation: u64,
+}
+
+// maps open(2) flags onto the access(2) mask they require
+fn open_mask(flags: u32) -> u32 {
+    let flags = flags as i32;
+    let mut mask = match flags & O_ACCMODE {
+        O_RDONLY => R_OK,
+        O_WRONLY => W_OK,
+        _ => R_OK | W_OK,
+    };
+    if flags & O_TRUNC != 0 {
+        mask |= W_OK;
+    }
+
+    mask as u32
+}
+
+fn check(store: &FileStore, req: &dyn Caller, ino: u64, mask: u32) -> Result<(), c_int> {
+    store.check_access(&ino, req.uid(), req.gid(), mask)
+}
+
+fn check_service(store: &FileStore, req: &dyn Caller, ino: u64) -> Result<(), c_int> {
+    store.check_service_access(&ino, req.uid(), req.gid())
+}
+
+// fetches run without the store, so the service dir they were for may
+// have been replaced by the time they land; then what they got is the
+// old service's and goes nowhere
+fn same_service(
+    store: &FileStore,
+    dir: u64,
+    service: &Arc<dyn SingleService>,
+) -> Result<(), c_int> {
+    match store.service(&dir) {
+        Some(now) if Arc::ptr_eq(&now, service) => Ok(()),
+        _ => Err(ESTALE),
+    }
+}
+
+// the service's answer to query and what it says about it; fetches can be
+// slow, so this runs without the store
+fn fetch(
+    service: &Arc<dyn SingleService>,
+    query: Option<&str>,
+) -> (Vec<u8>, Vec<(String, String)>) {
+    let content = service.fetch_data(query).join("\n").into_bytes();
+    let attrs = query.map(|query| service.attributes(query));
+    (content, attrs.unwrap_or_default())
+}
+
+// the entry the kernel is told about, counted so the ino isn't reused
+// while the kernel still knows it
+fn entry(store: &FileStore, ino: u64) -> Result<Entry, c_int> {
+    let node = store.get(&ino).ok_or(ENOENT)?;
+    let attr = store.attr(&ino).ok_or(ENOENT)?;
+    store.note_lookup(&ino);
+
+    Ok(Entry {
+        ttl: node.ttl,
+        attr,
+        generation: node.generation,
+    })
+}
+
+// every operation takes the store lock it needs for as long as it needs it:
+// shared for lookups, reads and writes of file data, exclusive for changes
+// to the tree. file data has its own per-file locks in the store
+impl Fs {
+    pub fn check_access(&self, req: &dyn Caller, ino: u64, mask: u32) -> Result<(), c_int> {
+        log::error!("access: {} {}", ino, mask);
+        check(&self.store(), req, ino, mask)
+    }
+
+    pub fn lookup_entry(
+        &self,
+        req: &dyn Caller,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<Entry, c_int> {
+        log::error!("called lookup");
+        let store = self.store();
+        check(&store, req, parent, X_OK as u32).and(check_service(&store, req, parent))?;
+
+        // "name@v3" in a service dir is the third kept fetch of name
+        match store
+            .resolve_path(&parent, name)
+            .or_else(|| store.version(&parent, name))
+        {
+            Some(id) => {
+                check_service(&store, req, id)?;
+                store.note_access(&id);
+                entry(&store, id)
+            }
+            None => {
+                log::error!("no file found in lookup: {:?} {:?}", name, parent);
+                Err(ENOENT)
+            }
+        }
+    }
+
+    pub fn get_attr(&self, req: &dyn Caller, ino: u64) -> Result<FileAttr, c_int> {
+        let store = self.store();
+        self.fill_control(&store, req, ino);
+        match store.attr(&ino) {
+            Some(attr) => {
+                log::info!("found filez: {:?}", attr);
+                Ok(attr)
+            }
+            None => {
+                log::error!("none found! {:?}", ino,);
+                Err(ENOENT)
+            }
+        }
+    }
+
+    pub fn set_attr(
+        &self,
+        req: &dyn Caller,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+    ) -> Result<FileAttr, c_int> {
+        if size.is_some() {
+            self.restore_dropped(ino);
+        }
+        let mut store = self.store_mut();
+        if mode.is_some() || uid.is_some() || gid.is_some() || size.is_some() {
+            store.check_writable(&ino)?;
+        }
+        if let Some(m) = mode {
+            store.chmod(&ino, req.uid(), m)?;
+        }
+        if uid.is_some() || gid.is_some() {
+            store.chown(&ino, req.uid(), uid, gid)?;
+        }
+        if let Some(s) = size {
+            check(&store, req, ino, W_OK as u32)?;
+            store.truncate(&ino, s)?;
+        }
+
+        store.attr(&ino).ok_or(ENOENT)
+    }
+
+    pub fn set_times(
+        &self,
+        req: &dyn Caller,
+        ino: u64,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
+    ) -> Result<FileAttr, c_int> {
+        let mut store = self.store_mut();
+        if atime.is_some() || mtime.is_some() {
+            store.check_writable(&ino)?;
+            // owners may set any time, anyone else needs to be able to write
+            let owner = store.attr(&ino).ok_or(ENOENT)?.uid;
+            if req.uid() != 0 && req.uid() != owner {
+                check(&store, req, ino, W_OK as u32)?;
+            }
+            store.set_times(&ino, atime, mtime)?;
+        }
+
+        store.attr(&ino).ok_or(ENOENT)
+    }
+
+    pub fn make_dir(
+        &self,
+        req: &dyn Caller,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+    ) -> Result<Entry, c_int> {
+        log::info!("creating a dir");
+        // a dir made in /.snapshots is a snapshot of everything else
+        let snapshot = {
+            let store = self.store();
+            let snapshot = store.snapshots_dir() == Some(parent);
+            if snapshot {
+                store.check_mount_writable().and(check(
+                    &store,
+                    req,
+                    parent,
+                    (W_OK | X_OK) as u32,
+                ))?;
+            }
+            snapshot
+        };
+        if snapshot {
+            let id = self.take_snapshot(Some(name))?;
+            return entry(&self.store(), id);
+        }
+        let mut store = self.store_mut();
+        store
+            .check_writable(&parent)
+            .and(check(&store, req, parent, (W_OK | X_OK) as u32))?;
+
+        let id = store
+            .create_dir(parent, name, mode, req.uid(), req.gid())?
+            .id;
+        entry(&store, id)
+    }
+
+    pub fn create_file(
+        &self,
+        req: &dyn Caller,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+    ) -> Result<Entry, c_int> {
+        let service = {
+            let store = self.store();
+            log::debug!(
+                "create: {:?}, {:?}, {}",
+                store.full_path(&parent),
+                name,
+                mode
+            );
+            // creating a file in a service dir is a fetch, so it is allowed
+            // even when the mount or the service is read only
+            let writable = if store.is_service_dir(&parent) {
+                Ok(())
+            } else {
+                store.check_writable(&parent)
+            };
+            writable
+                .and(check(&store, req, parent, (W_OK | X_OK) as u32))
+                .and(check_service(&store, req, parent))?;
+
+            store.service(&parent)
+        };
+
+        // fetches can be slow, so none of the store is held while they run
+        let fetched = service.as_ref().map(|svc| fetch(svc, name.to_str()));
+
+        let mut store = self.store_mut();
+        if let Some(service) = &service {
+            same_service(&store, parent, service)?;
+        }
+        let id = match fetched {
+            Some((content, attrs)) => {
+                let id = store.add_fetched(&parent, name, content, req.uid(), req.gid(), mode)?;
+                store.set_attributes(&id, attrs)?;
+                id
+            }
+            None => store.touch_file(&parent, name, req.uid(), req.gid(), mode)?,
+        };
+        match entry(&store, id) {
+            Ok(entry) => {
+                log::error!("got through create");
+                Ok(entry)
+            }
+            Err(_) => {
+                log::error!("not a valid parent");
+                Err(ENOTDIR)
+            }
+        }
+    }
+
+    // fetches a service file again; its old content goes to its history.
+    // like touching it, that is for its owner or those who can write it
+    pub fn refresh_file(&self, req: &dyn Caller, ino: u64) -> Result<FileAttr, c_int> {
+        {
+            let store = self.store();
+            check_service(&store, req, ino)?;
+            let owner = store.attr(&ino).ok_or(ENOENT)?.uid;
+            if req.uid() != 0 && req.uid() != owner {
+                check(&store, req, ino, W_OK as u32)?;
+            }
+        }
+
+        self.refetch(ino)
+    }
+
+    // refresh_file for whoever is already allowed it
+    fn refetch(&self, ino: u64) -> Result<FileAttr, c_int> {
+        let (service, query) = {
+            let store = self.store();
+            if !store.is_service_file(&ino) {
+                return Err(EINVAL);
+            }
+            let node = store.get(&ino).ok_or(ENOENT)?;
+            let service = store.service(&node.parent()).ok_or(EINVAL)?;
+            (service, node.path.clone())
+        };
+        log::info!("refresh {} {:?}", ino, query);
+
+        let (content, attrs) = fetch(&service, query.to_str());
+
+        let mut store = self.store_mut();
+        same_service(&store, store.get(&ino).ok_or(ESTALE)?.parent(), &service)?;
+        store.refresh(&ino, content)?;
+        store.set_attributes(&ino, attrs)?;
+        store.attr(&ino).ok_or(ENOENT)
+    }
+
+    // whether ino is a service file older than its service lets it get
+    fn is_stale(&self, ino: u64) -> bool {
+        let store = self.store();
+        let (node, attr) = match (store.get(&ino), store.attr(&ino)) {
+            (Some(node), Some(attr)) if store.is_service_file(&ino) => (node, attr),
+            _ => return false,
+        };
+        let max_age = match (store.service(&node.parent()), node.path.to_str()) {
+            (Some(service), Some(query)) => service.max_age(query),
+            _ => None,
+        };
+
+        max_age.is_some_and(|age| {
+            let age = time::Duration::from_std(age).unwrap_or_else(|_| time::Duration::max_value());
+            time::get_time() - attr.mtime >= age
+        })
+    }
+
+    pub fn open_file(&self, req: &dyn Caller, ino: u64, flags: u32) -> Result<u64, c_int> {
+        log::error!("open called {:?} {:?}", ino, flags);
+        let mask = open_mask(flags);
+        {
+            let store = self.store();
+            if mask & W_OK as u32 != 0 {
+                store.check_writable(&ino)?;
+            }
+            check(&store, req, ino, mask).and(check_service(&store, req, ino))?;
+        }
+        // only those who may open it get it fetched again
+        if self.is_stale(ino) {
+            self.refetch(ino)?;
+        }
+        let store = self.store();
+        self.fill_control(&store, req, ino);
+
+        Ok(ino)
+    }
+
+    pub fn read_data(
+        &self,
+        req: &dyn Caller,
+        ino: u64,
+        offset: i64,
+        size: u32,
+    ) -> Result<Vec<u8>, c_int> {
+        check_service(&self.store(), req, ino)?;
+        self.restore_dropped(ino);
+
+        let store = self.store();
+        if offset <= 0 {
+            self.fill_control(&store, req, ino);
+        }
+        store.read(&ino, offset.max(0) as u64, size as u64)
+    }
+
+    // as in create_file, dropped bytes are fetched again without the store,
+    // before anything needing them takes it
+    fn restore_dropped(&self, ino: u64) {
+        let refetch = self.store().dropped(&ino);
+        if let Some(refetch) = refetch {
+            let data = refetch();
+            self.store().restore(&ino, &refetch, data);
+        }
+    }
+
+    pub fn write_data(
+        &self,
+        req: &dyn Caller,
+        ino: u64,
+        offset: i64,
+        data: &[u8],
+        flags: u32,
+    ) -> Result<u32, c_int> {
+        log::error!("write: {} {} {:?} {}", ino, offset, data, flags);
+        self.restore_dropped(ino);
+        let store = self.store();
+        // writing to a control file runs it instead of storing anything
+        if let Some(path) = store.control(&ino).map(String::from) {
+            drop(store);
+            self.run_control(req, &path, data)?;
+            return Ok(data.len() as u32);
+        }
+        store.check_writable(&ino)?;
+
+        let w_size = std::mem::size_of_val(data) as u32;
+        log::error!("write size: {}", w_size as u32);
+        let size = store.write(ino, data, flags, offset)?;
+        log::error!("size={}", size);
+        // must return exact same size as data that was requested to be written
+        // or else stupid io invalid arg error or something happens
+        // really stupid
+        Ok(w_size)
+    }
+
+    // fetches what the service of dir lists that isn't in it yet, up to
+    // LIST_FETCHES of them a listing, and adds the dirs it has that aren't.
+    // only for those who may list dir
+    fn fetch_listed(&self, req: &dyn Caller, dir: u64) -> Result<(), c_int> {
+        let (service, config) = {
+            let store = self.store();
+            let allowed = check(&store, req, dir, R_OK as u32)
+                .and(check_service(&store, req, dir))
+                .is_ok();
+            match (store.service(&dir), store.service_config(&dir)) {
+                (Some(svc), Some(config)) if allowed => (svc, config.clone()),
+                _ => return Ok(()),
+            }
+        };
+
+        for sub in service.dirs() {
+            let name = sub.get_name();
+            let mut store = self.store_mut();
+            same_service(&store, dir, &service)?;
+            if store.resolve_path(&dir, OsStr::new(&name)).is_some() {
+                continue;
+            }
+            match store.add_service_dir(&dir, sub, config.clone()) {
+                Ok(_) => (),
+                Err(EINVAL) => log::error!("{} has a bad dir {:?}", service.get_name(), name),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // as in create_file, fetches run without the store
+        let mut fetches = 0;
+        for name in service.list() {
+            let name = OsStr::new(&name);
+            if !fstore::service_name(name) {
+                log::error!("{} lists a bad name {:?}", service.get_name(), name);
+                continue;
+            }
+            if self.store().resolve_path(&dir, name).is_some() {
+                continue;
+            }
+            // the rest come with later listings
+            if fetches == LIST_FETCHES {
+                break;
+            }
+            fetches += 1;
+            let (content, attrs) = fetch(&service, name.to_str());
+            let mut store = self.store_mut();
+            same_service(&store, dir, &service)?;
+            match store.add_fetched(&dir, name, content, req.uid(), req.gid(), 0o644) {
+                Ok(id) => store.set_attributes(&id, attrs)?,
+                Err(EEXIST) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    // hands what was written to a service file back to its service
+    pub fn flush_file(&self, req: &dyn Caller, ino: u64) -> Result<(), c_int> {
+        // what was written stays to be handed back until the service has
+        // taken it, so a caller turned away or a failed write back leaves it
+        // for the next flush. it is taken up front so two flushes don't
+        // both send it
+        self.restore_dropped(ino);
+        let (service, query, data) = {
+            let store = self.store();
+            check_service(&store, req, ino)?;
+            if !store.take_written(&ino) {
+                return Ok(());
+            }
+            let node = store.get(&ino).ok_or(ENOENT)?;
+            let service = store.service(&node.parent()).ok_or(EINVAL)?;
+            let data = store.read(&ino, 0, u64::MAX).inspect_err(|_| {
+                store.note_written(&ino);
+            })?;
+            (service, node.path.to_string_lossy().into_owned(), data)
+        };
+        log::info!("write back {} {:?}", ino, query);
+
+        service.write_back(&query, &data).map_err(|e| {
+            log::error!("{} refused {:?}: {}", service.get_name(), query, e);
+            self.store().note_written(&ino);
+            e.raw_os_error().unwrap_or(EIO)
+        })
+    }
+
+    pub fn get_xattr(&self, req: &dyn Caller, ino: u64, name: &OsStr) -> Result<Vec<u8>, c_int> {
+        let store = self.store();
+        check_service(&store, req, ino)?;
+        let node = store.get(&ino).ok_or(ENOENT)?;
+        let value = node.xattr.get(name).ok_or(ENODATA)?;
+        Ok(value.as_bytes().to_vec())
+    }
+
+    // the names of ino's extended attributes, each ending in a nul
+    pub fn list_xattr(&self, req: &dyn Caller, ino: u64) -> Result<Vec<u8>, c_int> {
+        let store = self.store();
+        check_service(&store, req, ino)?;
+        let node = store.get(&ino).ok_or(ENOENT)?;
+        let mut names: Vec<_> = node.xattr.keys().collect();
+        names.sort();
+
+        Ok(names.into_iter().fold(Vec::new(), |mut list, name| {
+            list.extend_from_slice(name.as_bytes());
+            list.push(0);
+            list
+        }))
+    }
+
+    pub fn read_dir(
+        &self,
+        req: &dyn Caller,
+        ino: u64,
+        offset: i64,
+        reply: &mut dyn DirFiller,
+    ) -> Result<(), c_int> {
+        log::error!("readdir: {}, {}", ino, offset);
+        if offset == 0 {
+            self.fetch_listed(req, ino)?;
+        }
+        let store = self.store();
+        check(&store, req, ino, R_OK as u32).and(check_service(&store, req, ino))?;
+
+        let parent = store.get(&ino).ok_or(ENOENT)?.parent();
+        let entries = store.dir_cookies(&ino).ok_or(ENOTDIR)?;
+
+        // offsets are cookies naming where to resume: "." is followed by 1,
+        // ".." by 2 and every name by the cookie its dir gave it. cookies
+        // only grow, so one stays valid however the dir changes between calls
+        if offset < 1 && reply.add(ino, 1, FileType::Directory, OsStr::new(".")) {
+            return Ok(());
+        }
+        if offset < 2 && reply.add(parent, 2, FileType::Directory, OsStr::new("..")) {
+            return Ok(());
+        }
+
+        let after = offset.max(2) as u64;
+        for (cookie, id, name) in entries.into_iter().filter(|(cookie, _, _)| *cookie > after) {
+            if !store.service_permits(&id, req.uid(), req.gid()) {
+                continue;
+            }
+
+            match store.get(&id) {
+                Some(f) => {
+                    if reply.add(id, cookie as i64, f.attr.kind, name) {
+                        break;
+                    }
+                }
+                None => log::error!("dangling child reference: parent={} child={}", &ino, &id),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_dir(&self, req: &dyn Caller, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let mut store = self.store_mut();
+        log::error!("rmdir {:?} {:?}", store.full_path(&parent), name);
+        if store.snapshots_dir() == Some(parent) {
+            store
+                .check_mount_writable()
+                .and(check(&store, req, parent, (W_OK | X_OK) as u32))?;
+            return store.remove_snapshot(name);
+        }
+        store
+            .check_writable(&parent)
+            .and(check(&store, req, parent, (W_OK | X_OK) as u32))
+            .and(store.check_sticky(&parent, name, req.uid()))?;
+
+        // only services under the root have control files
+        let service = parent == FUSE_ROOT_ID
+            && store
+                .resolve_path(&parent, name)
+                .is_some_and(|id| store.is_service_dir(&id));
+        store.rmdir(&parent, name)?;
+        if service {
+            control::remove_service(&mut store, &name.to_string_lossy());
+        }
+        Ok(())
+    }
+
+    pub fn remove_file(&self, req: &dyn Caller, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let mut store = self.store_mut();
+        log::error!("unlink {:?} {:?}", store.full_path(&parent), name);
+        store
+            .check_writable(&parent)
+            .and(check(&store, req, parent, (W_OK | X_OK) as u32))
+            .and(store.check_sticky(&parent, name, req.uid()))?;
+
+        store.unlink(&parent, name)
+    }
+
+    pub fn rename_entry(
+        &self,
+        req: &dyn Caller,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<(), c_int> {
+        let mut store = self.store_mut();
+        let mask = (W_OK | X_OK) as u32;
+        store
+            .check_writable(&parent)
+            .and(store.check_writable(&newparent))
+            .and(check(&store, req, parent, mask))
+            .and(check(&store, req, newparent, mask))
+            .and(store.check_sticky(&parent, name, req.uid()))
+            .and(store.check_sticky(&newparent, newname, req.uid()))?;
+
+        store.rename(&parent, name, newparent, newname)?;
+        Ok(())
+    }
+
+    pub fn link_entry(
+        &self,
+        req: &dyn Caller,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<Entry, c_int> {
+        let mut store = self.store_mut();
+        log::error!(
+            "link {:?} to {} {:?}",
+            store.full_path(&ino),
+            newparent,
+            newname
+        );
+        store.check_writable(&newparent).and(check(
+            &store,
+            req,
+            newparent,
+            (W_OK | X_OK) as u32,
+        ))?;
+
+        store.link(&ino, &newparent, newname)?;
+        entry(&store, ino)
+    }
+
+    pub fn forget_entry(&self, ino: u64, nlookup: u64) {
+        log::info!("forget: {} {}", ino, nlookup);
+        self.store_mut().forget(&ino, nlookup);
+    }
+
+    pub fn stat_fs(&self) -> StoreStats {
+        let stats = self.store().stats();
+        log::info!("statfs: {:?}", stats);
+        stats
+    }
+}
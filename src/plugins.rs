@@ -0,0 +1,196 @@
+// services loaded from cdylib plugins, see file_node::plugin for the abi
+use std::convert::TryInto;
+use std::ffi::{c_void, OsStr};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use file_node::plugin::{self, PluginBuf, PluginDecl, NO_MAX_AGE};
+use file_node::plugin::{ABI_SYMBOL, ABI_VERSION, DECL_SYMBOL};
+use file_node::SingleService;
+use libloading::Library;
+
+// one service made by a plugin. the library stays loaded for as long as
+// the service, or any of its dirs, does
+pub struct PluginService {
+    name: String,
+    svc: *mut c_void,
+    decl: PluginDecl,
+    lib: Arc<Library>,
+}
+
+// export_service! only builds plugins for SingleService types, which are
+// Send + Sync
+unsafe impl Send for PluginService {}
+unsafe impl Sync for PluginService {}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl PluginService {
+    // loads the plugin at path and makes its service, refusing plugins
+    // built against another ABI version
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<PluginService> {
+        let path = path.as_ref();
+        let lib = unsafe { Library::new(path) }.map_err(|e| invalid(e.to_string()))?;
+
+        let decl = unsafe {
+            let version = lib
+                .get::<*const u32>(ABI_SYMBOL)
+                .map_err(|e| invalid(format!("{:?} is not a plugin: {}", path, e)))?;
+            if **version != ABI_VERSION {
+                return Err(invalid(format!(
+                    "{:?} is built for plugin ABI {}, not {}",
+                    path, **version, ABI_VERSION
+                )));
+            }
+            let decl = lib
+                .get::<extern "C" fn() -> PluginDecl>(DECL_SYMBOL)
+                .map_err(|e| invalid(format!("{:?} is not a plugin: {}", path, e)))?;
+            decl()
+        };
+
+        let svc = unsafe { (decl.create)() };
+        if svc.is_null() {
+            return Err(invalid(format!("{:?} could not make its service", path)));
+        }
+        let plugin = PluginService::new(svc, decl, Arc::new(lib));
+        log::info!("loaded plugin {} from {:?}", plugin.name, path);
+
+        Ok(plugin)
+    }
+
+    // takes over svc, made by the plugin in lib for decl
+    fn new(svc: *mut c_void, decl: PluginDecl, lib: Arc<Library>) -> PluginService {
+        let mut plugin = PluginService {
+            name: String::new(),
+            svc,
+            decl,
+            lib,
+        };
+        let name = plugin.take(unsafe { (plugin.decl.name)(svc) });
+        plugin.name = String::from_utf8_lossy(&name).into_owned();
+        plugin
+    }
+
+    // copies out bytes the plugin handed over and gives them back to it
+    fn take(&self, buf: PluginBuf) -> Vec<u8> {
+        let bytes = unsafe { buf.as_slice() }.to_vec();
+        unsafe { (self.decl.free_buf)(buf) };
+        bytes
+    }
+
+    fn lines(&self, buf: PluginBuf) -> Vec<String> {
+        let answer = self.take(buf);
+        if answer.is_empty() {
+            return vec![];
+        }
+        String::from_utf8_lossy(&answer)
+            .split('\n')
+            .map(String::from)
+            .collect()
+    }
+}
+
+impl SingleService for PluginService {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        let (ptr, len) = match query {
+            Some(query) => (query.as_ptr(), query.len()),
+            None => (std::ptr::null(), 0),
+        };
+        self.lines(unsafe { (self.decl.fetch)(self.svc, ptr, len) })
+    }
+
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    // the rest are the plugin's when it has them, and a service's defaults
+    // when it doesn't
+    fn list(&self) -> Vec<String> {
+        match self.decl.list {
+            Some(list) => self.lines(unsafe { list(self.svc) }),
+            None => vec![],
+        }
+    }
+
+    fn write_back(&self, query: &str, data: &[u8]) -> io::Result<()> {
+        let write_back = match self.decl.write_back {
+            Some(write_back) => write_back,
+            None => return Ok(()),
+        };
+        let errno = unsafe {
+            write_back(
+                self.svc,
+                query.as_ptr(),
+                query.len(),
+                data.as_ptr(),
+                data.len(),
+            )
+        };
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+
+        Ok(())
+    }
+
+    fn max_age(&self, query: &str) -> Option<Duration> {
+        let max_age = self.decl.max_age?;
+        let nanos = unsafe { max_age(self.svc, query.as_ptr(), query.len()) };
+        (nanos != NO_MAX_AGE).then_some(Duration::from_nanos(nanos))
+    }
+
+    fn attributes(&self, query: &str) -> Vec<(String, String)> {
+        match self.decl.attributes {
+            Some(attributes) => {
+                let buf = unsafe { attributes(self.svc, query.as_ptr(), query.len()) };
+                plugin::decode_pairs(&self.take(buf))
+            }
+            None => vec![],
+        }
+    }
+
+    // each dir's service is the plugin's too, so it keeps the library
+    fn dirs(&self) -> Vec<Box<dyn SingleService + Send>> {
+        let dirs = match self.decl.dirs {
+            Some(dirs) => unsafe { dirs(self.svc) },
+            None => return vec![],
+        };
+        let decl = dirs.decl;
+        self.take(dirs.svcs)
+            .chunks_exact(std::mem::size_of::<usize>())
+            .map(|ptr| {
+                let ptr = usize::from_ne_bytes(ptr.try_into().unwrap_or_default());
+                let dir = PluginService::new(ptr as *mut c_void, decl, self.lib.clone());
+                Box::new(dir) as Box<dyn SingleService + Send>
+            })
+            .collect()
+    }
+}
+
+impl Drop for PluginService {
+    fn drop(&mut self) {
+        unsafe { (self.decl.destroy)(self.svc) };
+    }
+}
+
+// every plugin in dir; ones that don't load are logged and left out
+pub fn load_dir<P: AsRef<Path>>(dir: P) -> io::Result<Vec<PluginService>> {
+    let mut plugins = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some(OsStr::new(std::env::consts::DLL_EXTENSION)) {
+            continue;
+        }
+        match PluginService::load(&path) {
+            Ok(plugin) => plugins.push(plugin),
+            Err(e) => log::error!("skipping plugin {:?}: {}", path, e),
+        }
+    }
+
+    Ok(plugins)
+}
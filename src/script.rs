@@ -0,0 +1,194 @@
+// services written as rhai scripts, which define fetch(query) and
+// optionally list(), answering with a string of lines, an array of them or
+// (). scripts also get http_get(url) and env(name). the script is compiled
+// again when its file changes, and the last good one serves until it does
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::SystemTime;
+
+use file_node::SingleService;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Scope, AST};
+
+// enough for any glue, not for a script that never stops
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+// a compiled script and the file it came from, as it was then
+struct Script {
+    ast: Arc<AST>,
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+pub struct ScriptService {
+    name: String,
+    path: PathBuf,
+    engine: Engine,
+    script: RwLock<Script>,
+}
+
+fn reqwest_get(url: &str) -> Result<String, String> {
+    reqwest::get(url)
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|mut resp| resp.text())
+        .map_err(|e| e.to_string())
+}
+
+fn stat(path: &Path) -> io::Result<(Option<SystemTime>, u64)> {
+    let meta = fs::metadata(path)?;
+    Ok((meta.modified().ok(), meta.len()))
+}
+
+// an answer as lines: a string is split, an array gives one line per item
+fn lines(answer: Dynamic) -> Vec<String> {
+    if answer.is_unit() {
+        return vec![];
+    }
+    if answer.is_array() {
+        let items: Array = answer.cast();
+        return items
+            .into_iter()
+            .map(|item| match item.into_string() {
+                Ok(line) => line,
+                Err(kind) => kind.to_string(),
+            })
+            .collect();
+    }
+
+    match answer.to_string() {
+        text if text.is_empty() => vec![],
+        text => text.split('\n').map(String::from).collect(),
+    }
+}
+
+impl ScriptService {
+    // the script at path, failing when it can't be read or compiled
+    pub fn load<P: AsRef<Path>>(name: &str, path: P) -> io::Result<ScriptService> {
+        ScriptService::load_with(name, path, reqwest_get)
+    }
+
+    // like load, with get doing the script's http_get calls
+    pub fn load_with<P, F>(name: &str, path: P, get: F) -> io::Result<ScriptService>
+    where
+        P: AsRef<Path>,
+        F: Fn(&str) -> Result<String, String> + Send + Sync + 'static,
+    {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.register_fn(
+            "http_get",
+            move |url: &str| -> Result<String, Box<EvalAltResult>> {
+                get(url).map_err(|e| format!("http_get {}: {}", url, e).into())
+            },
+        );
+        engine.register_fn("env", |name: &str| match std::env::var(name) {
+            Ok(value) => Dynamic::from(value),
+            Err(_) => Dynamic::UNIT,
+        });
+
+        let path = path.as_ref().to_path_buf();
+        let (modified, len) = stat(&path)?;
+        let ast = engine
+            .compile_file(path.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        Ok(ScriptService {
+            name: name.to_string(),
+            path,
+            engine,
+            script: RwLock::new(Script {
+                ast: Arc::new(ast),
+                modified,
+                len,
+            }),
+        })
+    }
+
+    // the current script, compiled again first if its file changed
+    fn script(&self) -> Arc<AST> {
+        let changed = stat(&self.path).ok().filter(|&(modified, len)| {
+            let script = self.script.read().unwrap_or_else(PoisonError::into_inner);
+            (modified, len) != (script.modified, script.len)
+        });
+
+        let (modified, len) = match changed {
+            Some(changed) => changed,
+            None => {
+                let script = self.script.read().unwrap_or_else(PoisonError::into_inner);
+                return script.ast.clone();
+            }
+        };
+
+        let mut script = self.script.write().unwrap_or_else(PoisonError::into_inner);
+        // another call may have got here first
+        if (modified, len) != (script.modified, script.len) {
+            // marked seen even when broken, so it's only logged once
+            script.modified = modified;
+            script.len = len;
+            match self.engine.compile_file(self.path.clone()) {
+                Ok(ast) => {
+                    log::info!("reloaded script {:?}", self.path);
+                    script.ast = Arc::new(ast);
+                }
+                Err(e) => log::error!("keeping the last good {:?}: {}", self.path, e),
+            }
+        }
+        script.ast.clone()
+    }
+
+    fn call(&self, func: &str, args: Vec<Dynamic>) -> Vec<String> {
+        let ast = self.script();
+        let takes = args.len();
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == func && f.params.len() == takes)
+        {
+            return vec![];
+        }
+
+        match self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &ast, func, args)
+        {
+            Ok(answer) => lines(answer),
+            Err(e) => {
+                log::error!("script {} failed in {}: {}", self.name, func, e);
+                vec![]
+            }
+        }
+    }
+}
+
+impl SingleService for ScriptService {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        let query = query.map_or(Dynamic::UNIT, |query| Dynamic::from(query.to_string()));
+        self.call("fetch", vec![query])
+    }
+
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.call("list", vec![])
+    }
+}
+
+// a service for every .rhai file in dir, named after the file
+pub fn load_dir<P: AsRef<Path>>(dir: P) -> io::Result<Vec<ScriptService>> {
+    let mut services = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) if path.extension().is_some_and(|ext| ext == "rhai") => name.to_string(),
+            _ => continue,
+        };
+        match ScriptService::load(&name, &path) {
+            Ok(svc) => services.push(svc),
+            Err(e) => log::error!("skipping script {:?}: {}", path, e),
+        }
+    }
+
+    Ok(services)
+}
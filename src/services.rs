@@ -0,0 +1,264 @@
+// services that need no network: fixed answers, closures, and a wrapper
+// that records a real service to disk once and replays it after
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use file_node::SingleService;
+
+// answers queries from a fixed map; a missing query yields no lines
+pub struct StaticService {
+    name: String,
+    data: HashMap<String, Vec<String>>,
+}
+
+impl StaticService {
+    pub fn new(name: &str) -> StaticService {
+        StaticService {
+            name: name.to_string(),
+            data: HashMap::new(),
+        }
+    }
+
+    // the empty query is what fetch_data(None) returns
+    pub fn with(mut self, query: &str, content: &str) -> StaticService {
+        let lines = content.lines().map(|line| line.to_string()).collect();
+        self.data.insert(query.to_string(), lines);
+        self
+    }
+}
+
+impl SingleService for StaticService {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        match self.data.get(query.unwrap_or("")) {
+            Some(lines) => lines.clone(),
+            None => {
+                log::error!("no static data for {:?} in {}", query, self.name);
+                vec![]
+            }
+        }
+    }
+
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+type FetchFn = dyn Fn(Option<&str>) -> Vec<String> + Send + Sync;
+
+pub struct FnService {
+    name: String,
+    fetch: Box<FetchFn>,
+}
+
+impl FnService {
+    pub fn new<F>(name: &str, fetch: F) -> FnService
+    where
+        F: Fn(Option<&str>) -> Vec<String> + Send + Sync + 'static,
+    {
+        FnService {
+            name: name.to_string(),
+            fetch: Box::new(fetch),
+        }
+    }
+}
+
+impl SingleService for FnService {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        (self.fetch)(query)
+    }
+
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplayMode {
+    // always call the inner service and overwrite the recording
+    Record,
+    // only play back recordings; fetches never reach the inner service
+    Replay,
+    // play back when a recording exists, record otherwise
+    Auto,
+}
+
+// the longest query whose hex name, with .json, fits in NAME_MAX
+const MAX_HEX_QUERY: usize = 120;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(hex: &str) -> Option<String> {
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+// fnv-1a, stable between releases unlike std's hashers
+fn fnv(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// each query is kept in its own json file under dir
+pub struct RecordReplayService {
+    inner: Box<dyn SingleService + Send>,
+    dir: PathBuf,
+    mode: ReplayMode,
+}
+
+impl RecordReplayService {
+    pub fn new<P: AsRef<Path>>(
+        inner: Box<dyn SingleService + Send>,
+        dir: P,
+        mode: ReplayMode,
+    ) -> RecordReplayService {
+        RecordReplayService {
+            inner,
+            dir: dir.as_ref().to_path_buf(),
+            mode,
+        }
+    }
+
+    fn recording(&self, query: Option<&str>) -> PathBuf {
+        // hex keeps arbitrary queries safe to use as file names, but doubles
+        // their length, so long ones are named after their hash instead
+        let name = match query {
+            Some(q) if q.len() <= MAX_HEX_QUERY => hex(q.as_bytes()),
+            Some(q) => format!("_hash-{:016x}", fnv(q.as_bytes())),
+            None => "_none".to_string(),
+        };
+
+        self.dir.join(format!("{}.json", name))
+    }
+
+    fn load(&self, query: Option<&str>) -> Option<Vec<String>> {
+        let raw = fs::read_to_string(self.recording(query)).ok()?;
+        let parsed = match query {
+            // hashed recordings keep their query, in case two hashes collide
+            Some(q) if q.len() > MAX_HEX_QUERY => serde_json::from_str(&raw)
+                .map(|(kept, lines): (String, Vec<String>)| (kept == q).then_some(lines)),
+            _ => serde_json::from_str(&raw).map(Some),
+        };
+        match parsed {
+            Ok(lines) => lines,
+            Err(e) => {
+                log::error!("bad recording for {:?}: {}", query, e);
+                None
+            }
+        }
+    }
+
+    // the inner service, unless only recordings are played back
+    fn live(&self) -> Option<&(dyn SingleService + Send)> {
+        match self.mode {
+            ReplayMode::Replay => None,
+            _ => Some(&*self.inner),
+        }
+    }
+
+    // the dirs recorded under dir, played back without the inner service
+    fn recorded_dirs(&self) -> Vec<Box<dyn SingleService + Send>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+
+        entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| {
+                let name = unhex(entry.file_name().to_str()?)?;
+                let inner = FnService::new(&name, |_| vec![]);
+                Some(Box::new(RecordReplayService::new(
+                    Box::new(inner),
+                    entry.path(),
+                    ReplayMode::Replay,
+                )) as Box<dyn SingleService + Send>)
+            })
+            .collect()
+    }
+
+    fn record(&self, query: Option<&str>) -> Vec<String> {
+        let lines = self.inner.fetch_data(query);
+        let saved = fs::create_dir_all(&self.dir).and_then(|_| {
+            let raw = match query {
+                Some(q) if q.len() > MAX_HEX_QUERY => serde_json::to_string(&(q, &lines)),
+                _ => serde_json::to_string(&lines),
+            };
+            fs::write(self.recording(query), raw.unwrap_or_default())
+        });
+        if let Err(e) = saved {
+            log::error!("could not record {:?}: {}", query, e);
+        }
+
+        lines
+    }
+}
+
+impl SingleService for RecordReplayService {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        match self.mode {
+            ReplayMode::Record => self.record(query),
+            ReplayMode::Replay => self.load(query).unwrap_or_else(|| {
+                log::error!("no recording for {:?} in {:?}", query, self.dir);
+                vec![]
+            }),
+            ReplayMode::Auto => match self.load(query) {
+                Some(lines) => lines,
+                None => self.record(query),
+            },
+        }
+    }
+
+    fn get_name(&self) -> String {
+        self.inner.get_name()
+    }
+
+    // only fetches are recorded; everything else is the inner service's,
+    // and left at what a service does by default when replaying
+    fn list(&self) -> Vec<String> {
+        self.live().map_or_else(Vec::new, |inner| inner.list())
+    }
+
+    fn write_back(&self, query: &str, data: &[u8]) -> io::Result<()> {
+        match self.live() {
+            Some(inner) => inner.write_back(query, data),
+            None => Ok(()),
+        }
+    }
+
+    fn max_age(&self, query: &str) -> Option<Duration> {
+        self.live().and_then(|inner| inner.max_age(query))
+    }
+
+    fn attributes(&self, query: &str) -> Vec<(String, String)> {
+        self.live()
+            .map_or_else(Vec::new, |inner| inner.attributes(query))
+    }
+
+    // each dir is recorded under a dir of its own, named like a query.
+    // replaying finds them there
+    fn dirs(&self) -> Vec<Box<dyn SingleService + Send>> {
+        let inner = match self.live() {
+            Some(inner) => inner,
+            None => return self.recorded_dirs(),
+        };
+        inner
+            .dirs()
+            .into_iter()
+            .map(|dir| {
+                let path = self.dir.join(hex(dir.get_name().as_bytes()));
+                Box::new(RecordReplayService::new(dir, path, self.mode))
+                    as Box<dyn SingleService + Send>
+            })
+            .collect()
+    }
+}
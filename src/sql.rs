@@ -0,0 +1,303 @@
+// a sqlite database, opened read only, with a dir per table and a file per
+// row named by rowid, plus the named queries given with query:
+//
+//   /db/users/1.json          row 1 of users
+//   /db/users/all.csv         all of users
+//   /db/by_city:Oslo,3.csv    by_city with Oslo for ?1 and 3 for ?2
+//
+// files are json unless named .csv
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use file_node::SingleService;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params_from_iter, Connection, OpenFlags};
+use serde_json::{Map, Value};
+
+type Db = Arc<Mutex<Connection>>;
+
+// how many rows of a table its dir lists
+const LISTED_ROWS: usize = 100;
+
+enum Format {
+    Json,
+    Csv,
+}
+
+// the name without its extension, and what to render it as
+fn format(name: &str) -> (&str, Format) {
+    if let Some(base) = name.strip_suffix(".csv") {
+        return (base, Format::Csv);
+    }
+    (name.strip_suffix(".json").unwrap_or(name), Format::Json)
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn json(value: &SqlValue) -> Value {
+    match value {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(n) => Value::from(*n),
+        SqlValue::Real(f) => Value::from(*f),
+        SqlValue::Text(text) => Value::String(text.clone()),
+        SqlValue::Blob(bytes) => Value::String(hex(bytes)),
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn csv(value: &SqlValue) -> String {
+    match value {
+        SqlValue::Null => String::new(),
+        SqlValue::Integer(n) => n.to_string(),
+        SqlValue::Real(f) => f.to_string(),
+        SqlValue::Text(text) => csv_field(text),
+        SqlValue::Blob(bytes) => hex(bytes),
+    }
+}
+
+// the columns and rows sql gives with params bound to ?1, ?2, ...
+fn select(
+    db: &Db,
+    sql: &str,
+    params: &[&str],
+) -> rusqlite::Result<(Vec<String>, Vec<Vec<SqlValue>>)> {
+    let conn = db.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut stmt = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let width = columns.len();
+
+    let rows = stmt
+        .query_map(params_from_iter(params), |row| {
+            (0..width).map(|i| row.get::<_, SqlValue>(i)).collect()
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok((columns, rows))
+}
+
+// rows as a json array, or one object when just one row is asked for, or
+// as csv with a header line
+fn render(columns: &[String], rows: &[Vec<SqlValue>], format: Format, one: bool) -> Vec<String> {
+    match format {
+        Format::Csv => {
+            let header = columns.iter().map(|c| csv_field(c)).collect::<Vec<_>>();
+            let lines = rows
+                .iter()
+                .map(|row| row.iter().map(csv).collect::<Vec<_>>().join(","));
+            std::iter::once(header.join(",")).chain(lines).collect()
+        }
+        Format::Json => {
+            let mut objects = rows.iter().map(|row| {
+                let fields = columns.iter().cloned().zip(row.iter().map(json));
+                Value::Object(fields.collect::<Map<String, Value>>())
+            });
+            let value = if one {
+                objects.next().unwrap_or(Value::Null)
+            } else {
+                Value::Array(objects.collect())
+            };
+            match serde_json::to_string_pretty(&value) {
+                Ok(text) => text.split('\n').map(String::from).collect(),
+                Err(e) => {
+                    log::error!("can't render rows: {}", e);
+                    vec![]
+                }
+            }
+        }
+    }
+}
+
+pub struct SqlService {
+    name: String,
+    db: Db,
+    queries: BTreeMap<String, String>,
+}
+
+impl SqlService {
+    pub fn open<P: AsRef<Path>>(name: &str, path: P) -> io::Result<SqlService> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+
+        Ok(SqlService {
+            name: name.to_string(),
+            db: Arc::new(Mutex::new(conn)),
+            queries: BTreeMap::new(),
+        })
+    }
+
+    // makes sql fetchable as name; its ?1, ?2, ... come from the file name
+    pub fn query(mut self, name: &str, sql: &str) -> SqlService {
+        self.queries.insert(name.to_string(), sql.to_string());
+        self
+    }
+
+    // the queries in a file of `name = sql` lines; # starts a comment
+    pub fn queries_from<P: AsRef<Path>>(self, path: P) -> io::Result<SqlService> {
+        let text = fs::read_to_string(path)?;
+        let queries = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                line.split_once('=')
+                    .map(|(name, sql)| (name.trim(), sql.trim()))
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, format!("not a query: {}", line))
+                    })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(queries
+            .into_iter()
+            .fold(self, |svc, (name, sql)| svc.query(name, sql)))
+    }
+
+    fn tables(&self) -> rusqlite::Result<Vec<String>> {
+        let sql = "SELECT name FROM sqlite_master \
+                   WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name";
+        let (_, rows) = select(&self.db, sql, &[])?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| match row.into_iter().next() {
+                Some(SqlValue::Text(name)) => Some(name),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn takes_params(&self, sql: &str) -> bool {
+        let conn = self.db.lock().unwrap_or_else(PoisonError::into_inner);
+        conn.prepare(sql)
+            .map_or(true, |stmt| stmt.parameter_count() > 0)
+    }
+}
+
+impl SingleService for SqlService {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        let (name, format) = match query {
+            Some(query) => format(query),
+            None => return vec![],
+        };
+        let (name, params) = match name.split_once(':') {
+            Some((name, params)) => (name, params.split(',').collect()),
+            None => (name, vec![]),
+        };
+        let sql = match self.queries.get(name) {
+            Some(sql) => sql,
+            None => return vec![],
+        };
+
+        match select(&self.db, sql, &params) {
+            Ok((columns, rows)) => render(&columns, &rows, format, false),
+            Err(e) => {
+                log::error!("{} query {} failed: {}", self.name, name, e);
+                vec![]
+            }
+        }
+    }
+
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.queries
+            .iter()
+            .filter(|(_, sql)| !self.takes_params(sql))
+            .map(|(name, _)| format!("{}.json", name))
+            .collect()
+    }
+
+    fn dirs(&self) -> Vec<Box<dyn SingleService + Send>> {
+        match self.tables() {
+            Ok(tables) => tables
+                .into_iter()
+                .map(|table| {
+                    Box::new(TableService {
+                        table,
+                        db: self.db.clone(),
+                    }) as Box<dyn SingleService + Send>
+                })
+                .collect(),
+            Err(e) => {
+                log::error!("can't list the tables of {}: {}", self.name, e);
+                vec![]
+            }
+        }
+    }
+}
+
+// the rows of one table
+struct TableService {
+    table: String,
+    db: Db,
+}
+
+impl SingleService for TableService {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        let (row, format) = match query {
+            Some(query) => format(query),
+            None => return vec![],
+        };
+        let table = quote_ident(&self.table);
+        let selected = match row {
+            "all" => select(&self.db, &format!("SELECT * FROM {}", table), &[])
+                .map(|(columns, rows)| (columns, rows, false)),
+            row if row.parse::<i64>().is_ok() => {
+                let sql = format!("SELECT * FROM {} WHERE rowid = ?1", table);
+                select(&self.db, &sql, &[row]).map(|(columns, rows)| (columns, rows, true))
+            }
+            _ => return vec![],
+        };
+
+        match selected {
+            Ok((_, rows, true)) if rows.is_empty() => vec![],
+            Ok((columns, rows, one)) => render(&columns, &rows, format, one),
+            Err(e) => {
+                log::error!("reading {} failed: {}", self.table, e);
+                vec![]
+            }
+        }
+    }
+
+    fn get_name(&self) -> String {
+        self.table.clone()
+    }
+
+    // the first rows only; the others, and all.json, are there when asked for
+    fn list(&self) -> Vec<String> {
+        let sql = format!(
+            "SELECT rowid FROM {} ORDER BY rowid LIMIT {}",
+            quote_ident(&self.table),
+            LISTED_ROWS
+        );
+        match select(&self.db, &sql, &[]) {
+            Ok((_, rows)) => rows
+                .into_iter()
+                .filter_map(|row| match row.first() {
+                    Some(SqlValue::Integer(id)) => Some(format!("{}.json", id)),
+                    _ => None,
+                })
+                .collect(),
+            Err(e) => {
+                log::error!("listing {} failed: {}", self.table, e);
+                vec![]
+            }
+        }
+    }
+}
@@ -0,0 +1,331 @@
+// services run as wasm modules, each fetch in a fresh instance with fuel
+// and memory limits. a module exports memory and
+//
+//   alloc(len: i32) -> i32             room for len bytes the host fills in
+//   fetch(ptr: i32, len: i32) -> i64   the answer to the query at ptr, no
+//                                      query when len is -1
+//
+// answers are lines at ptr << 32 | len. the one import it may have is
+// vfs.http_get(ptr, len) -> i64, which answers the same way, or -1 when the
+// url wasn't granted, the request failed or the body is over the limit
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use file_node::SingleService;
+use reqwest::{RedirectPolicy, Url};
+use wasmi::{Caller, Config, Engine, Extern, Linker, Memory, Module, Store};
+use wasmi::{StoreLimits, StoreLimitsBuilder};
+
+// enough for a few million instructions
+const DEFAULT_FUEL: u64 = 10_000_000;
+const DEFAULT_MEMORY: usize = 16 << 20;
+// past this much reqwest_get stops reading
+const MAX_BODY: u64 = 64 << 20;
+// as many as reqwest follows by default
+const MAX_REDIRECTS: usize = 10;
+
+pub type HttpGet = Arc<dyn Fn(&str) -> Result<Vec<u8>, String> + Send + Sync>;
+
+// the urls a module may get and what gets them
+#[derive(Clone)]
+struct Http {
+    allowed: Vec<Grant>,
+    get: HttpGet,
+}
+
+// a granted url prefix: the same scheme, host and port, and a path at or
+// under this one
+#[derive(Clone, Debug)]
+struct Grant {
+    scheme: String,
+    host: String,
+    port: Option<u16>,
+    path: String,
+}
+
+impl Grant {
+    fn parse(prefix: &str) -> Option<Grant> {
+        let url = Url::parse(prefix).ok()?;
+        let mut path = url.path().to_string();
+        if !path.ends_with('/') {
+            path.push('/');
+        }
+        Some(Grant {
+            scheme: url.scheme().to_string(),
+            host: url.host_str()?.to_string(),
+            port: url.port_or_known_default(),
+            path,
+        })
+    }
+
+    fn covers(&self, url: &Url) -> bool {
+        let path = url.path();
+        url.scheme() == self.scheme
+            && url.host_str() == Some(self.host.as_str())
+            && url.port_or_known_default() == self.port
+            && url.username().is_empty()
+            && url.password().is_none()
+            && (path.starts_with(&self.path) || path == self.path.trim_end_matches('/'))
+    }
+}
+
+struct Host {
+    limits: StoreLimits,
+    http: Option<Http>,
+    // no answer to http_get is bigger than the module's memory
+    max_body: usize,
+}
+
+pub struct WasmService {
+    name: String,
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+    memory: usize,
+    http: Option<Http>,
+}
+
+fn invalid<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// a result packed as ptr << 32 | len
+fn unpack(packed: i64) -> (usize, usize) {
+    ((packed as u64 >> 32) as usize, packed as u32 as usize)
+}
+
+fn memory(caller: &Caller<'_, Host>) -> Option<Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => Some(memory),
+        _ => None,
+    }
+}
+
+// copies bytes into memory the module allocated for them
+fn hand_over(caller: &mut Caller<'_, Host>, bytes: &[u8]) -> Result<i64, wasmi::Error> {
+    let alloc = caller
+        .get_export("alloc")
+        .and_then(Extern::into_func)
+        .ok_or_else(|| wasmi::Error::new("module exports no alloc"))?
+        .typed::<i32, i32>(&*caller)?;
+    let ptr = alloc.call(&mut *caller, bytes.len() as i32)?;
+    let memory = memory(caller).ok_or_else(|| wasmi::Error::new("module exports no memory"))?;
+    memory.write(&mut *caller, ptr as usize, bytes)?;
+
+    Ok(((ptr as u32 as i64) << 32) | bytes.len() as i64)
+}
+
+fn http_get(mut caller: Caller<'_, Host>, ptr: i32, len: i32) -> Result<i64, wasmi::Error> {
+    let memory = memory(&caller).ok_or_else(|| wasmi::Error::new("module exports no memory"))?;
+    if len < 0 || len as usize > memory.data_size(&caller) {
+        return Err(wasmi::Error::new("url is out of bounds"));
+    }
+    let mut url = vec![0; len as usize];
+    memory.read(&caller, ptr as usize, &mut url)?;
+    let url = String::from_utf8_lossy(&url).into_owned();
+
+    // what is checked is what gets fetched, as the parser sees it
+    let parsed = Url::parse(&url).ok();
+    let http = match (&caller.data().http, &parsed) {
+        (Some(http), Some(parsed)) if http.allowed.iter().any(|grant| grant.covers(parsed)) => {
+            http.clone()
+        }
+        _ => {
+            log::error!("wasm service denied http get {}", url);
+            return Ok(-1);
+        }
+    };
+    let url = parsed.map_or(url, Url::into_string);
+    match (http.get)(&url) {
+        Ok(body) if body.len() > caller.data().max_body => {
+            log::error!("wasm service http get {} is too big: {}", url, body.len());
+            Ok(-1)
+        }
+        Ok(body) => hand_over(&mut caller, &body),
+        Err(e) => {
+            log::error!("wasm service http get {} failed: {}", url, e);
+            Ok(-1)
+        }
+    }
+}
+
+// gets url, following redirects only to urls allowed covers too; a server
+// that was granted could otherwise send the module anywhere
+fn reqwest_get(allowed: &[Grant], url: &str) -> Result<Vec<u8>, String> {
+    let allowed = allowed.to_vec();
+    let policy = RedirectPolicy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.too_many_redirects()
+        } else if allowed.iter().any(|grant| grant.covers(attempt.url())) {
+            attempt.follow()
+        } else {
+            attempt.stop()
+        }
+    });
+    let client = reqwest::Client::builder()
+        .redirect(policy)
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let mut body = Vec::new();
+    let resp = client
+        .get(url)
+        .send()
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|e| e.to_string())?;
+    // a redirect that was stopped comes back as it is
+    if resp.status().is_redirection() {
+        return Err(format!("redirected out of the grants from {}", url));
+    }
+    resp.take(MAX_BODY + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| e.to_string())?;
+    if body.len() as u64 > MAX_BODY {
+        return Err(format!("body is over {} bytes", MAX_BODY));
+    }
+    Ok(body)
+}
+
+impl WasmService {
+    // wasm holds the module in binary or text form
+    pub fn new(name: &str, wasm: &[u8]) -> io::Result<WasmService> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(invalid)?;
+
+        Ok(WasmService {
+            name: name.to_string(),
+            engine,
+            module,
+            fuel: DEFAULT_FUEL,
+            memory: DEFAULT_MEMORY,
+            http: None,
+        })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(name: &str, path: P) -> io::Result<WasmService> {
+        WasmService::new(name, &fs::read(path)?)
+    }
+
+    // how much fuel, about one per instruction, and how many bytes of
+    // memory a single fetch may use
+    pub fn with_limits(mut self, fuel: u64, memory: usize) -> WasmService {
+        self.fuel = fuel;
+        self.memory = memory;
+        self
+    }
+
+    // lets the module get urls under one of prefixes: the same scheme, host
+    // and port, and a path at or below the prefix's
+    pub fn allow_http(self, prefixes: &[&str]) -> WasmService {
+        let allowed: Vec<Grant> = prefixes.iter().filter_map(|p| Grant::parse(p)).collect();
+        self.allow_http_with(prefixes, move |url| reqwest_get(&allowed, url))
+    }
+
+    // like allow_http, with get doing the requests
+    pub fn allow_http_with<F>(mut self, prefixes: &[&str], get: F) -> WasmService
+    where
+        F: Fn(&str) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+    {
+        let allowed = prefixes
+            .iter()
+            .filter_map(|prefix| {
+                let grant = Grant::parse(prefix);
+                if grant.is_none() {
+                    log::error!("wasm service {} can't be granted {}", self.name, prefix);
+                }
+                grant
+            })
+            .collect();
+        self.http = Some(Http {
+            allowed,
+            get: Arc::new(get),
+        });
+        self
+    }
+
+    fn run(&self, query: Option<&str>) -> Result<Vec<u8>, wasmi::Error> {
+        let host = Host {
+            limits: StoreLimitsBuilder::new()
+                .memory_size(self.memory)
+                .instances(1)
+                .build(),
+            http: self.http.clone(),
+            max_body: self.memory,
+        };
+        let mut store = Store::new(&self.engine, host);
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(self.fuel)?;
+
+        let mut linker = Linker::new(&self.engine);
+        linker.func_wrap("vfs", "http_get", http_get)?;
+        let instance = linker.instantiate_and_start(&mut store, &self.module)?;
+        let fetch = instance.get_typed_func::<(i32, i32), i64>(&store, "fetch")?;
+
+        let query = match query {
+            Some(query) => {
+                let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc")?;
+                let ptr = alloc.call(&mut store, query.len() as i32)?;
+                let memory = instance
+                    .get_memory(&store, "memory")
+                    .ok_or_else(|| wasmi::Error::new("module exports no memory"))?;
+                memory.write(&mut store, ptr as usize, query.as_bytes())?;
+                (ptr, query.len() as i32)
+            }
+            None => (0, -1),
+        };
+        let (ptr, len) = unpack(fetch.call(&mut store, query)?);
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| wasmi::Error::new("module exports no memory"))?;
+        if ptr.saturating_add(len) > memory.data_size(&store) {
+            return Err(wasmi::Error::new("answer is out of bounds"));
+        }
+        let mut answer = vec![0; len];
+        memory.read(&store, ptr, &mut answer)?;
+        Ok(answer)
+    }
+}
+
+impl SingleService for WasmService {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        match self.run(query) {
+            Ok(answer) if answer.is_empty() => vec![],
+            Ok(answer) => String::from_utf8_lossy(&answer)
+                .split('\n')
+                .map(String::from)
+                .collect(),
+            Err(e) => {
+                log::error!("wasm service {} failed on {:?}: {}", self.name, query, e);
+                vec![]
+            }
+        }
+    }
+
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+// a service for every .wasm file in dir, named after the file; none of
+// them get http
+pub fn load_dir<P: AsRef<Path>>(dir: P) -> io::Result<Vec<WasmService>> {
+    let mut services = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) if path.extension().is_some_and(|ext| ext == "wasm") => name.to_string(),
+            _ => continue,
+        };
+        match WasmService::from_file(&name, &path) {
+            Ok(svc) => services.push(svc),
+            Err(e) => log::error!("skipping wasm service {:?}: {}", path, e),
+        }
+    }
+
+    Ok(services)
+}
@@ -0,0 +1,65 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// a fixed set of threads the fuse callbacks hand slow work to; each job
+// sends its own reply, so the session loop can read the next request
+pub struct Workers {
+    jobs: Option<mpsc::Sender<Job>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Workers {
+    pub fn new(count: usize) -> Workers {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        let threads = (0..count)
+            .map(|i| {
+                let queue = queue.clone();
+                thread::Builder::new()
+                    .name(format!("vfs-worker-{}", i))
+                    .spawn(move || loop {
+                        // the queue lock is dropped before the job runs
+                        let job = match queue.lock() {
+                            Ok(queue) => queue.recv(),
+                            Err(_) => break,
+                        };
+                        // a reply dropped by a panicking job goes out as EIO
+                        match job.map(|job| panic::catch_unwind(AssertUnwindSafe(job))) {
+                            Ok(Ok(())) => (),
+                            Ok(Err(_)) => log::error!("job panicked"),
+                            Err(_) => break,
+                        }
+                    })
+                    .expect("could not start worker")
+            })
+            .collect();
+
+        Workers {
+            jobs: Some(jobs),
+            threads,
+        }
+    }
+
+    pub fn run<F: FnOnce() + Send + 'static>(&self, job: F) {
+        if let Some(jobs) = &self.jobs {
+            if jobs.send(Box::new(job)).is_err() {
+                log::error!("workers are gone; dropping job");
+            }
+        }
+    }
+}
+
+impl Drop for Workers {
+    // lets queued jobs finish so every request gets its reply
+    fn drop(&mut self) {
+        self.jobs.take();
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                log::error!("worker died");
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use libc::{EINVAL, EIO};
+use std::time::{Duration, Instant};
+
+use vfs_service::command::CommandService;
+use vfs_service::SingleService;
+
+mod common;
+use common::{mount, scratch};
+
+fn sh(name: &str, script: &str) -> CommandService {
+    CommandService::new(name, "sh").arg("-c").arg(script)
+}
+
+// answers fetches with the query, lists two files and takes no writes
+const ECHO: &str = r#"
+while read line; do
+  case "$line" in
+    *'"op":"list"'*) echo '{"lines":["a","b"]}' ;;
+    *'"op":"write"'*) echo '{"error":"read only"}' ;;
+    *'"query":null'*) echo '{"lines":[]}' ;;
+    *) q=$(echo "$line" | sed 's/.*"query":"\([^"]*\)".*/\1/')
+       echo "{\"lines\":[\"got $q\",\"bye\"]}" ;;
+  esac
+done
+"#;
+
+#[test]
+fn commands_serve_files() {
+    let svc = sh("echo", ECHO);
+    assert_eq!(svc.fetch_data(None), Vec::<String>::new());
+    assert_eq!(svc.fetch_data(Some("10002")), vec!["got 10002", "bye"]);
+
+    let mut h = mount(svc.persistent());
+    h.create("/echo/10002", 0o644).unwrap();
+    assert_eq!(h.read("/echo/10002").unwrap(), b"got 10002\nbye");
+}
+
+#[test]
+fn commands_list_and_take_writes() {
+    let mut h = mount(sh("echo", ECHO));
+    let names = h.readdir("/echo").unwrap();
+    assert!(names.contains(&"a".to_string()) && names.contains(&"b".to_string()));
+    assert_eq!(h.read("/echo/a").unwrap(), b"got a\nbye");
+    assert_eq!(h.write("/echo/a", b"new"), Err(EIO));
+
+    let script = r#"read -r line; printf '%s\n' "$line" >> "$0"; echo '{}'"#;
+    let dir = scratch("command-log");
+    let log = dir.join("log");
+    let svc = sh("notes", script).arg(&log);
+    svc.write_back("todo", b"milk\n").unwrap();
+    let written = std::fs::read_to_string(&log).unwrap();
+    assert_eq!(
+        written,
+        "{\"data\":\"milk\\n\",\"op\":\"write\",\"query\":\"todo\"}\n"
+    );
+}
+
+#[test]
+fn persistent_commands_keep_running() {
+    let count = r#"n=0; while read line; do n=$((n+1)); echo "{\"lines\":[\"$n\"]}"; done"#;
+    let svc = sh("count", count).persistent();
+    assert_eq!(svc.fetch_data(Some("x")), vec!["1"]);
+    assert_eq!(svc.fetch_data(Some("x")), vec!["2"]);
+
+    let svc = sh("count", count);
+    assert_eq!(svc.fetch_data(Some("x")), vec!["1"]);
+    assert_eq!(svc.fetch_data(Some("x")), vec!["1"]);
+}
+
+#[test]
+fn crashed_commands_are_restarted() {
+    // answers once, then dies
+    let svc = sh("once", r#"read line; echo '{"lines":["once"]}'"#).persistent();
+    assert_eq!(svc.fetch_data(Some("x")), vec!["once"]);
+    assert_eq!(svc.fetch_data(Some("x")), vec!["once"]);
+
+    // dies before answering every time
+    let svc = sh("never", "exit 1").persistent();
+    assert!(svc.fetch_data(Some("x")).is_empty());
+    assert!(sh("garbled", "echo nonsense")
+        .fetch_data(Some("x"))
+        .is_empty());
+}
+
+#[test]
+fn writes_are_sent_once_and_as_text() {
+    // logs what it is asked, then dies before answering
+    let script = r#"read -r line; printf '%s\n' "$line" >> "$0"; exit 1"#;
+    let dir = scratch("command-once");
+    let log = dir.join("log");
+    let svc = sh("notes", script).arg(&log).persistent();
+    assert!(svc.write_back("todo", b"milk\n").is_err());
+    assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), 1);
+
+    // fetches are asked again
+    assert!(svc.fetch_data(Some("x")).is_empty());
+    assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), 3);
+
+    let err = svc.write_back("todo", b"\xff\xfe").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(EINVAL));
+    assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), 3);
+}
+
+#[test]
+fn slow_commands_time_out() {
+    let svc = sh("slow", "sleep 30").timeout(Duration::from_millis(200));
+    let started = Instant::now();
+    assert!(svc.fetch_data(Some("x")).is_empty());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
@@ -0,0 +1,65 @@
+// fixtures shared by the tests; each test file uses only some of them
+#![allow(dead_code)]
+use std::ffi::OsStr;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs, process};
+
+use vfs_service::harness::Harness;
+use vfs_service::services::FnService;
+use vfs_service::{ServiceConfig, SingleService};
+
+// a service named weather that answers differently every time it is asked
+pub fn counter() -> Box<FnService> {
+    let calls = AtomicUsize::new(0);
+    Box::new(FnService::new("weather", move |query| {
+        let n = calls.fetch_add(1, Ordering::SeqCst) + 1;
+        vec![
+            format!("{} forecast", query.unwrap_or_default()),
+            format!("answer {}", n),
+        ]
+    }))
+}
+
+// a mount of svc alone
+pub fn mount<S: SingleService + Send + 'static>(svc: S) -> Harness {
+    Harness::with_service(Box::new(svc), ServiceConfig::default())
+}
+
+// a dir of its own for a test, removed with everything in it when
+// dropped, even by a failing test
+pub struct Scratch(PathBuf);
+
+pub fn scratch(name: &str) -> Scratch {
+    let dir = env::temp_dir().join(format!("vfs-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    Scratch(dir)
+}
+
+impl Deref for Scratch {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for Scratch {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<OsStr> for Scratch {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_os_str()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
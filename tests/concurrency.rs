@@ -0,0 +1,111 @@
+use std::ffi::OsStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fuse::FUSE_ROOT_ID;
+use vfs_service::fuse_system::Fs;
+use vfs_service::ops::Creds;
+use vfs_service::services::FnService;
+
+const ROOT: Creds = Creds { uid: 0, gid: 0 };
+
+#[test]
+fn slow_fetches_run_side_by_side() {
+    let svc = FnService::new("slow", |query| {
+        thread::sleep(Duration::from_millis(200));
+        vec![query.unwrap_or_default().to_string()]
+    });
+    let fs = Arc::new(Fs::new(vec![Box::new(svc)]));
+    let dir = fs
+        .lookup_entry(&ROOT, FUSE_ROOT_ID, OsStr::new("slow"))
+        .unwrap()
+        .attr
+        .ino;
+
+    let start = Instant::now();
+    let fetches: Vec<_> = (0..4)
+        .map(|i| {
+            let fs = fs.clone();
+            thread::spawn(move || {
+                let name = format!("q{}", i);
+                let ino = fs
+                    .create_file(&ROOT, dir, OsStr::new(&name), 0o644)
+                    .unwrap()
+                    .attr
+                    .ino;
+                assert_eq!(fs.read_data(&ROOT, ino, 0, 64).unwrap(), name.as_bytes());
+            })
+        })
+        .collect();
+    for fetch in fetches {
+        fetch.join().unwrap();
+    }
+
+    // one after another would take 800ms
+    assert!(start.elapsed() < Duration::from_millis(600));
+}
+
+#[test]
+fn writes_to_different_files_do_not_interfere() {
+    let fs = Arc::new(Fs::new(vec![]));
+    let writers: Vec<_> = (0..8u8)
+        .map(|i| {
+            let fs = fs.clone();
+            thread::spawn(move || {
+                let name = format!("f{}", i);
+                let ino = fs
+                    .create_file(&ROOT, FUSE_ROOT_ID, OsStr::new(&name), 0o644)
+                    .unwrap()
+                    .attr
+                    .ino;
+                for chunk in 0..64 {
+                    fs.write_data(&ROOT, ino, chunk * 16, &[i; 16], 0).unwrap();
+                }
+                ino
+            })
+        })
+        .collect();
+
+    for (i, writer) in writers.into_iter().enumerate() {
+        let ino = writer.join().unwrap();
+        assert_eq!(fs.get_attr(&ROOT, ino).unwrap().size, 1024);
+        let data = fs.read_data(&ROOT, ino, 0, 4096).unwrap();
+        assert!(data.iter().all(|b| *b == i as u8));
+    }
+}
+
+#[test]
+fn refetches_for_truncates_leave_the_store_be() {
+    let svc = FnService::new("slow", |query| {
+        thread::sleep(Duration::from_millis(300));
+        vec![query.unwrap_or_default().to_string()]
+    });
+    let mut fs = Fs::new(vec![Box::new(svc)]);
+    fs.set_memory_budget(0);
+    let fs = Arc::new(fs);
+    let dir = fs
+        .lookup_entry(&ROOT, FUSE_ROOT_ID, OsStr::new("slow"))
+        .unwrap()
+        .attr
+        .ino;
+    let ino = fs
+        .create_file(&ROOT, dir, OsStr::new("query"), 0o644)
+        .unwrap()
+        .attr
+        .ino;
+
+    // the dropped bytes are fetched again before the truncate takes the
+    // store to itself
+    let truncate = {
+        let fs = fs.clone();
+        thread::spawn(move || fs.set_attr(&ROOT, ino, None, None, None, Some(2)))
+    };
+    thread::sleep(Duration::from_millis(50));
+    let start = Instant::now();
+    fs.get_attr(&ROOT, FUSE_ROOT_ID).unwrap();
+    assert!(start.elapsed() < Duration::from_millis(200));
+
+    assert_eq!(truncate.join().unwrap().unwrap().size, 2);
+    assert_eq!(fs.read_data(&ROOT, ino, 0, 64).unwrap(), b"qu");
+}
@@ -0,0 +1,228 @@
+use libc::ENOSPC;
+use std::ffi::OsStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use file_node::ServiceConfig;
+use file_store::content::{DirSpill, CHUNK_SIZE};
+use file_store::fstore::FileStore;
+use vfs_service::services::FnService;
+
+mod common;
+use common::scratch;
+
+fn file(store: &mut FileStore, name: &str, data: &[u8]) -> u64 {
+    let ino = store.touch_file(&1, OsStr::new(name), 0, 0, 0o644).unwrap();
+    store.write(ino, data, 0, 0).unwrap();
+    ino
+}
+
+#[test]
+fn spilled_files_page_back_in() {
+    let dir = scratch("spill");
+    let mut store = FileStore::new();
+    store.set_spill(Box::new(DirSpill::new(&dir).unwrap()));
+    store.set_memory_budget(8);
+
+    let f = file(&mut store, "f", b"sixteen bytes!!!");
+    assert!(!store.is_resident(&f));
+    assert_eq!(store.resident_bytes(), 0);
+    assert!(dir.join(f.to_string()).exists());
+
+    assert_eq!(store.read_file(&f).unwrap(), b"sixteen bytes!!!");
+    assert_eq!(store.attr(&f).unwrap().size, 16);
+    assert_eq!(store.check_invariants(), Ok(()));
+
+    // removing the file removes what was spilled of it
+    store.unlink(&1, OsStr::new("f")).unwrap();
+    assert!(!dir.join(f.to_string()).exists());
+}
+
+#[test]
+fn least_recently_used_files_go_first() {
+    let dir = scratch("lru");
+    let mut store = FileStore::new();
+    store.set_spill(Box::new(DirSpill::new(&dir).unwrap()));
+    store.set_memory_budget(20);
+
+    let a = file(&mut store, "a", b"aaaaaaaaaa");
+    let b = file(&mut store, "b", b"bbbbbbbbbb");
+    let c = file(&mut store, "c", b"cccccccccc");
+    assert!(!store.is_resident(&a));
+
+    // reading a brings it back and pushes out b, now the coldest
+    assert_eq!(store.read_file(&a).unwrap(), b"aaaaaaaaaa");
+    assert!(store.is_resident(&a));
+    assert!(!store.is_resident(&b));
+    assert!(store.is_resident(&c));
+    assert_eq!(store.resident_bytes(), 20);
+}
+
+#[test]
+fn service_content_is_fetched_again_instead_of_spilled() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let svc = FnService::new("echo", move |query| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        vec![query.unwrap_or_default().to_string()]
+    });
+    let mut store = FileStore::new();
+    store.register_service(Box::new(svc), ServiceConfig::default());
+    store.set_memory_budget(0);
+    let echo = store.resolve("/echo").unwrap();
+
+    // as fetched by create_file
+    let q = store
+        .add_fetched(&echo, OsStr::new("q"), b"q".to_vec(), 0, 0, 0o644)
+        .unwrap();
+    assert!(!store.is_resident(&q));
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+    assert_eq!(store.read_file(&q).unwrap(), b"q");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // writing needs the bytes back once more; after that they are no
+    // longer the service's to fetch, and with nowhere to spill they stay put
+    store.write(q, b"mine", 0, 0).unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert!(store.is_resident(&q));
+    assert_eq!(store.read_file(&q).unwrap(), b"mine");
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn refetched_bytes_only_land_on_files_still_dropped() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let svc = FnService::new("echo", move |query| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        vec![query.unwrap_or_default().to_string()]
+    });
+    let mut store = FileStore::new();
+    store.register_service(Box::new(svc), ServiceConfig::default());
+    store.set_memory_budget(0);
+    let echo = store.resolve("/echo").unwrap();
+    let q = store
+        .add_fetched(&echo, OsStr::new("q"), b"q".to_vec(), 0, 0, 0o644)
+        .unwrap();
+    let r = store
+        .add_fetched(&echo, OsStr::new("r"), b"r".to_vec(), 0, 0, 0o644)
+        .unwrap();
+
+    // fetched elsewhere, with the store let go of
+    let refetch = store.dropped(&q).unwrap();
+    store.restore(&q, &refetch, refetch());
+    assert!(store.is_resident(&q));
+    assert_eq!(store.read_file(&q).unwrap(), b"q");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // written while the fetch ran, so the fetch is too late
+    let refetch = store.dropped(&r).unwrap();
+    store.write(r, b"mine", 0, 0).unwrap();
+    store.restore(&r, &refetch, b"late".to_vec());
+    assert_eq!(store.dropped(&r).map(|_| ()), None);
+    assert_eq!(store.read_file(&r).unwrap(), b"mine");
+    assert_eq!(store.check_invariants(), Ok(()));
+}
+
+#[test]
+fn shared_chunks_are_counted_once_when_offloaded() {
+    let dir = scratch("shared");
+    let mut store = FileStore::new();
+    store.set_spill(Box::new(DirSpill::new(&dir).unwrap()));
+    let a = file(&mut store, "a", b"sixteen bytes!!!");
+    let b = file(&mut store, "b", b"sixteen bytes!!!");
+    assert_eq!(store.used_bytes(), 16);
+
+    // spilling a frees nothing while b still holds its chunk
+    store.set_memory_budget(0);
+    assert!(!store.is_resident(&a));
+    assert!(!store.is_resident(&b));
+    assert_eq!(store.used_bytes(), 16);
+    assert_eq!(store.check_invariants(), Ok(()));
+
+    assert_eq!(store.read_file(&a).unwrap(), b"sixteen bytes!!!");
+    assert_eq!(store.check_invariants(), Ok(()));
+    store.unlink(&1, OsStr::new("a")).unwrap();
+    store.unlink(&1, OsStr::new("b")).unwrap();
+    assert_eq!(store.used_bytes(), 0);
+    assert_eq!(store.check_invariants(), Ok(()));
+}
+
+#[test]
+fn equal_files_are_stored_once() {
+    let mut store = FileStore::new();
+    let data: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+    let a = file(&mut store, "a", &data);
+    let used = store.used_bytes();
+    assert_eq!(used, data.len() as u64);
+
+    // a copy made the way cp does it, in pieces
+    let b = store.touch_file(&1, OsStr::new("b"), 0, 0, 0o644).unwrap();
+    for (i, piece) in data.chunks(4096).enumerate() {
+        store.write(b, piece, 0, (i * 4096) as i64).unwrap();
+    }
+    assert_eq!(store.used_bytes(), used);
+    assert_eq!(store.check_invariants(), Ok(()));
+
+    // changing the copy only costs the chunk that changed
+    store.write(b, b"changed", 0, 20_000).unwrap();
+    assert_eq!(store.used_bytes(), used + CHUNK_SIZE as u64);
+    assert_eq!(store.read_file(&a).unwrap(), data);
+    assert_eq!(&store.read(&b, 20_000, 7).unwrap(), b"changed");
+
+    store.unlink(&1, OsStr::new("a")).unwrap();
+    store.unlink(&1, OsStr::new("b")).unwrap();
+    assert_eq!(store.used_bytes(), 0);
+    assert_eq!(store.check_invariants(), Ok(()));
+}
+
+#[test]
+fn writes_and_truncates_across_chunks() {
+    let mut store = FileStore::new();
+    let f = file(&mut store, "f", b"");
+    let mut model = Vec::new();
+    let edits: &[(usize, usize)] = &[
+        (0, 100),
+        (CHUNK_SIZE - 10, 30),
+        (3 * CHUNK_SIZE + 5, 10),
+        (50, 2 * CHUNK_SIZE),
+    ];
+    for (n, (offset, len)) in edits.iter().enumerate() {
+        let data = vec![n as u8 + 1; *len];
+        store.write(f, &data, 0, *offset as i64).unwrap();
+        if model.len() < offset + len {
+            model.resize(offset + len, 0);
+        }
+        model[*offset..offset + len].copy_from_slice(&data);
+        assert_eq!(store.read_file(&f).unwrap(), model);
+    }
+
+    for size in [2 * CHUNK_SIZE + 7, CHUNK_SIZE, 5 * CHUNK_SIZE, 3] {
+        store.truncate(&f, size as u64).unwrap();
+        model.resize(size, 0);
+        assert_eq!(store.read_file(&f).unwrap(), model);
+        assert_eq!(store.check_invariants(), Ok(()));
+    }
+}
+
+#[test]
+fn far_writes_and_truncates_are_refused_up_front() {
+    let mut store = FileStore::new();
+    let f = file(&mut store, "f", b"data");
+    let tib = 1 << 40;
+
+    assert_eq!(store.truncate(&f, tib), Err(ENOSPC));
+    assert_eq!(store.write(f, b"x", 0, tib as i64), Err(ENOSPC));
+    assert_eq!(store.read_file(&f).unwrap(), b"data");
+
+    // a gap that fits costs one chunk of zeros, however long it is
+    let used = store.stats().used_bytes;
+    store.truncate(&f, 64 * CHUNK_SIZE as u64).unwrap();
+    store.write(f, b"end", 0, 128 * CHUNK_SIZE as i64).unwrap();
+    assert!(store.stats().used_bytes - used <= 3 * CHUNK_SIZE as u64);
+    let data = store.read_file(&f).unwrap();
+    assert_eq!(data.len(), 128 * CHUNK_SIZE + 3);
+    assert!(data[4..128 * CHUNK_SIZE].iter().all(|b| *b == 0));
+    assert_eq!(store.check_invariants(), Ok(()));
+}
@@ -0,0 +1,153 @@
+use libc::{EACCES, EINVAL, EPERM, EROFS, EXDEV};
+
+use vfs_service::harness::Harness;
+use vfs_service::services::StaticService;
+use vfs_service::ServiceConfig;
+
+mod common;
+use common::counter;
+
+fn harness() -> Harness {
+    let mut h = Harness::with_service(counter(), ServiceConfig::default());
+    h.fs.enable_control();
+    h
+}
+
+fn read(h: &mut Harness, path: &str) -> String {
+    String::from_utf8(h.read(path).unwrap()).unwrap()
+}
+
+#[test]
+fn control_files_describe_the_mount() {
+    let mut h = harness();
+    h.create("/weather/10002", 0o644).unwrap();
+
+    let mut names = h.readdir("/.vfs").unwrap();
+    names.sort();
+    assert_eq!(names, ["config", "ctl", "services", "stats", "status"]);
+    assert_eq!(h.readdir("/.vfs/services").unwrap(), ["weather"]);
+
+    assert_eq!(
+        read(&mut h, "/.vfs/status"),
+        "weather files=1 read_only=false history=0 access=open\n"
+    );
+    assert!(read(&mut h, "/.vfs/services/weather/status").starts_with("files 1\n"));
+    assert!(read(&mut h, "/.vfs/config").contains("read_only false\n"));
+    assert!(read(&mut h, "/.vfs/stats").contains("services 1\n"));
+    assert_eq!(
+        read(&mut h, "/.vfs/services/weather/ctl"),
+        "refresh\nclear\n"
+    );
+
+    // what they say follows the mount
+    h.create("/weather/10003", 0o644).unwrap();
+    assert!(read(&mut h, "/.vfs/status").starts_with("weather files=2 "));
+}
+
+#[test]
+fn service_ctl_refreshes_and_clears() {
+    let mut h = harness();
+    h.create("/weather/10002", 0o644).unwrap();
+
+    h.write("/.vfs/services/weather/ctl", b"refresh\n").unwrap();
+    assert_eq!(
+        h.read("/weather/10002").unwrap(),
+        b"10002 forecast\nanswer 2"
+    );
+
+    assert_eq!(
+        h.write("/.vfs/services/weather/ctl", b"explode"),
+        Err(EINVAL)
+    );
+    assert_eq!(h.write("/.vfs/stats", b"refresh"), Err(EPERM));
+
+    h.write("/.vfs/services/weather/ctl", b"clear\n").unwrap();
+    assert!(h.readdir("/weather").unwrap().is_empty());
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+#[test]
+fn ctl_drives_the_whole_mount() {
+    let mut h = harness();
+    h.write("/.vfs/ctl", b"read-only on\n").unwrap();
+    assert_eq!(h.mkdir("/a", 0o755).err(), Some(EROFS));
+    assert!(read(&mut h, "/.vfs/config").contains("read_only true\n"));
+
+    // still takes commands on a read only mount
+    h.write("/.vfs/ctl", b"read-only off\nsnapshot before\n")
+        .unwrap();
+    h.mkdir("/a", 0o755).unwrap();
+    assert_eq!(h.readdir("/.snapshots").unwrap(), ["before"]);
+    // snapshots leave the control files out
+    assert!(!h
+        .readdir("/.snapshots/before")
+        .unwrap()
+        .contains(&".vfs".to_string()));
+}
+
+#[test]
+fn a_bad_line_runs_nothing() {
+    let mut h = harness();
+    assert_eq!(
+        h.write("/.vfs/ctl", b"snapshot first\nread-only maybe\n"),
+        Err(EINVAL)
+    );
+    assert!(h.readdir("/.snapshots").is_err());
+    assert!(read(&mut h, "/.vfs/config").contains("read_only false\n"));
+}
+
+#[test]
+fn control_files_stay_put() {
+    let mut h = harness();
+    h.mkdir("/a", 0o755).unwrap();
+
+    assert_eq!(h.unlink("/.vfs/stats"), Err(EROFS));
+    assert_eq!(h.create("/.vfs/mine", 0o644).err(), Some(EROFS));
+    assert_eq!(h.rename("/.vfs/ctl", "/a/ctl"), Err(EROFS));
+    assert_eq!(h.link("/.vfs/ctl", "/a/ctl").err(), Some(EXDEV));
+    assert_eq!(h.rmdir("/.vfs/services/weather"), Err(EROFS));
+}
+
+#[test]
+fn services_come_and_go_with_their_controls() {
+    let mut h = harness();
+    h.fs.set_service_removal(true);
+    h.fs.register_service(
+        Box::new(StaticService::new("planets")),
+        ServiceConfig::default(),
+    );
+    let mut names = h.readdir("/.vfs/services").unwrap();
+    names.sort();
+    assert_eq!(names, ["planets", "weather"]);
+
+    h.rmdir("/planets").unwrap();
+    assert_eq!(h.readdir("/.vfs/services").unwrap(), ["weather"]);
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+#[test]
+fn restricted_services_are_hidden_from_those_they_deny() {
+    let config = ServiceConfig {
+        allow_uids: vec![4242],
+        ..Default::default()
+    };
+    let mut h = Harness::with_service(Box::new(StaticService::new("secret")), config);
+    h.fs.enable_control();
+
+    h.as_user(4242, 4242);
+    assert!(read(&mut h, "/.vfs/status").starts_with("secret "));
+    assert!(read(&mut h, "/.vfs/services/secret/status").contains("allow_uids 4242\n"));
+
+    h.as_user(1000, 1000);
+    assert_eq!(read(&mut h, "/.vfs/status"), "");
+    assert!(h.readdir("/.vfs/services").unwrap().is_empty());
+    assert_eq!(h.read("/.vfs/services/secret/status"), Err(EACCES));
+}
+
+#[test]
+fn the_control_dir_stays_put() {
+    let mut h = harness();
+    assert_eq!(h.rename("/.vfs", "/vfs2"), Err(EPERM));
+    assert_eq!(h.rmdir("/.vfs"), Err(EPERM));
+    assert!(read(&mut h, "/.vfs/status").starts_with("weather "));
+}
@@ -0,0 +1,129 @@
+use libc::{EACCES, ENODATA};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use vfs_service::exec::{Exec, ExecService};
+
+mod common;
+use common::{mount, scratch};
+
+fn sh(script: &str) -> Exec {
+    Exec::new("sh").args(&["-c", script])
+}
+
+#[test]
+fn files_are_command_output() {
+    let svc = ExecService::new("sys")
+        .file("greeting", Exec::new("echo").args(&["hello", "there"]))
+        .others(sh("echo got $0").arg("{}"));
+    let mut h = mount(svc);
+
+    assert!(h.readdir("/sys").unwrap().contains(&"greeting".to_string()));
+    assert_eq!(h.read("/sys/greeting").unwrap(), b"hello there");
+    h.create("/sys/pod-1", 0o644).unwrap();
+    assert_eq!(h.read("/sys/pod-1").unwrap(), b"got pod-1");
+    assert_eq!(h.getxattr("/sys/pod-1", "user.exec.status").unwrap(), b"0");
+}
+
+#[test]
+fn exit_codes_and_stderr_are_xattrs() {
+    let svc = ExecService::new("sys")
+        .file("broken", sh("echo half; echo oops >&2; exit 3"))
+        .file("missing", Exec::new("/no/such/command"));
+    let mut h = mount(svc);
+    h.readdir("/sys").unwrap();
+
+    assert_eq!(h.read("/sys/broken").unwrap(), b"half");
+    assert_eq!(
+        h.listxattr("/sys/broken").unwrap(),
+        vec!["user.exec.status", "user.exec.stderr"]
+    );
+    assert_eq!(h.getxattr("/sys/broken", "user.exec.status").unwrap(), b"3");
+    assert_eq!(
+        h.getxattr("/sys/broken", "user.exec.stderr").unwrap(),
+        b"oops\n"
+    );
+    assert_eq!(h.getxattr("/sys/broken", "user.other"), Err(ENODATA));
+
+    assert_eq!(h.read("/sys/missing").unwrap(), b"");
+    assert_eq!(
+        h.getxattr("/sys/missing", "user.exec.status").unwrap(),
+        b"failed"
+    );
+}
+
+#[test]
+fn names_that_look_like_options_are_refused() {
+    let svc = ExecService::new("sys").others(Exec::new("echo").args(&["got", "{}"]));
+    let mut h = mount(svc);
+
+    h.create("/sys/-n", 0o644).unwrap();
+    assert_eq!(h.read("/sys/-n").unwrap(), b"");
+    assert_eq!(
+        h.getxattr("/sys/-n", "user.exec.status").unwrap(),
+        b"failed"
+    );
+    h.create("/sys/n-", 0o644).unwrap();
+    assert_eq!(h.read("/sys/n-").unwrap(), b"got n-");
+}
+
+#[test]
+fn slow_commands_are_killed() {
+    let slow = Exec::new("sleep")
+        .arg("30")
+        .timeout(Duration::from_millis(200));
+    let mut h = mount(ExecService::new("sys").file("slow", slow));
+
+    let started = Instant::now();
+    h.create("/sys/slow", 0o644).unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(
+        h.getxattr("/sys/slow", "user.exec.status").unwrap(),
+        b"timeout"
+    );
+}
+
+#[test]
+fn output_is_kept_for_its_ttl() {
+    let dir = scratch("exec-ttl");
+    // counts its runs in the file it is given
+    let count = "n=$(cat \"$0\" 2>/dev/null || echo 0); n=$((n+1)); echo $n > \"$0\"; echo $n";
+
+    let svc = ExecService::new("sys")
+        .file(
+            "cached",
+            sh(count)
+                .arg(dir.join("cached"))
+                .ttl(Duration::from_millis(300)),
+        )
+        .file("kept", sh(count).arg(dir.join("kept")));
+    let mut h = mount(svc);
+    h.readdir("/sys").unwrap();
+
+    assert_eq!(h.read("/sys/cached").unwrap(), b"1");
+    assert_eq!(h.read("/sys/cached").unwrap(), b"1");
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(h.read("/sys/cached").unwrap(), b"2");
+
+    // without a ttl only a refresh runs it again
+    assert_eq!(h.read("/sys/kept").unwrap(), b"1");
+    assert_eq!(h.read("/sys/kept").unwrap(), b"1");
+    h.refresh("/sys/kept").unwrap();
+    assert_eq!(h.read("/sys/kept").unwrap(), b"2");
+}
+
+#[test]
+fn stale_files_only_run_again_for_those_who_may_open_them() {
+    let dir = scratch("exec-stale");
+    let count = "n=$(cat \"$0\" 2>/dev/null || echo 0); n=$((n+1)); echo $n > \"$0\"; echo $n";
+    let stale = sh(count)
+        .arg(dir.join("stale"))
+        .ttl(Duration::from_millis(1));
+    let mut h = mount(ExecService::new("sys").file("stale", stale));
+    h.readdir("/sys").unwrap();
+    h.chmod("/sys/stale", 0o600).unwrap();
+    thread::sleep(Duration::from_millis(20));
+
+    assert_eq!(h.as_user(1000, 1000).read("/sys/stale"), Err(EACCES));
+    assert_eq!(h.as_user(0, 0).read("/sys/stale").unwrap(), b"2");
+}
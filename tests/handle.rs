@@ -0,0 +1,192 @@
+use libc::{EEXIST, EINVAL, ENODEV, ENOENT, ESTALE};
+use std::ffi::OsStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+use vfs_service::harness::{Harness, MockReplyDirectory};
+use vfs_service::services::{FnService, StaticService};
+use vfs_service::{ServiceConfig, SingleService};
+
+fn planets(answer: &str) -> Box<StaticService> {
+    Box::new(StaticService::new("planets").with("earth", answer))
+}
+
+#[test]
+fn services_can_be_added_from_another_thread() {
+    let mut h = Harness::new(vec![]);
+    let services = h.fs.services();
+
+    thread::spawn(move || services.add(planets("third"), ServiceConfig::default()))
+        .join()
+        .unwrap()
+        .unwrap();
+
+    h.create("/planets/earth", 0o644).unwrap();
+    assert_eq!(h.read("/planets/earth").unwrap(), b"third");
+    assert_eq!(
+        h.fs.services()
+            .add(planets("again"), ServiceConfig::default()),
+        Err(EEXIST)
+    );
+    assert_eq!(h.fs.services().names(), Ok(vec!["planets".to_string()]));
+}
+
+#[test]
+fn replacing_a_service_drops_what_it_fetched() {
+    let mut h = Harness::new(vec![planets("third")]);
+    h.create("/planets/earth", 0o644).unwrap();
+    let old = h.lookup("/planets").unwrap();
+
+    h.fs.services()
+        .replace(planets("blue"), ServiceConfig::default())
+        .unwrap();
+    assert!(h.readdir("/planets").unwrap().is_empty());
+    h.create("/planets/earth", 0o644).unwrap();
+    assert_eq!(h.read("/planets/earth").unwrap(), b"blue");
+
+    // the kernel can't take the new dir for the one it knew
+    let new = h.lookup("/planets").unwrap();
+    assert!((new.attr.ino, new.generation) != (old.attr.ino, old.generation));
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+// a planets service whose fetches say when they start and wait to be let go
+fn slow() -> (Box<FnService>, Receiver<()>, Sender<()>) {
+    let (started, fetching) = mpsc::channel();
+    let (go, waiting) = mpsc::channel();
+    let waiting = Mutex::new(waiting);
+    let svc = FnService::new("planets", move |_| {
+        let _ = started.send(());
+        let _ = waiting.lock().unwrap().recv();
+        vec!["old".to_string()]
+    });
+    (Box::new(svc), fetching, go)
+}
+
+#[test]
+fn fetches_overtaken_by_a_replace_land_nowhere() {
+    let (svc, fetching, go) = slow();
+    let mut h = Harness::new(vec![svc]);
+    let dir = h.resolve("/planets").unwrap();
+
+    thread::scope(|s| {
+        let create = s.spawn(|| h.fs.create_file(&h.req, dir, OsStr::new("earth"), 0o644));
+        fetching.recv().unwrap();
+        h.fs.services()
+            .replace(planets("blue"), ServiceConfig::default())
+            .unwrap();
+        go.send(()).unwrap();
+        assert_eq!(create.join().unwrap().err(), Some(ESTALE));
+    });
+    assert!(h.readdir("/planets").unwrap().is_empty());
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+#[test]
+fn refreshes_overtaken_by_a_replace_land_nowhere() {
+    let (svc, fetching, go) = slow();
+    let mut h = Harness::new(vec![svc]);
+    go.send(()).unwrap();
+    h.create("/planets/earth", 0o644).unwrap();
+    fetching.recv().unwrap();
+    let ino = h.resolve("/planets/earth").unwrap();
+
+    thread::scope(|s| {
+        let refresh = s.spawn(|| h.fs.refresh_file(&h.req, ino));
+        fetching.recv().unwrap();
+        h.fs.services()
+            .replace(planets("blue"), ServiceConfig::default())
+            .unwrap();
+        go.send(()).unwrap();
+        assert_eq!(refresh.join().unwrap().err(), Some(ESTALE));
+    });
+    assert!(h.readdir("/planets").unwrap().is_empty());
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+// slow, listing the one name it has
+struct Listing(Box<FnService>);
+
+impl SingleService for Listing {
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        self.0.fetch_data(query)
+    }
+
+    fn get_name(&self) -> String {
+        self.0.get_name()
+    }
+
+    fn list(&self) -> Vec<String> {
+        vec!["earth".to_string()]
+    }
+}
+
+#[test]
+fn listings_overtaken_by_a_replace_land_nowhere() {
+    let (svc, fetching, go) = slow();
+    let mut h = Harness::new(vec![Box::new(Listing(svc))]);
+    let dir = h.resolve("/planets").unwrap();
+
+    thread::scope(|s| {
+        let list = s.spawn(|| {
+            let mut reply = MockReplyDirectory::default();
+            h.fs.read_dir(&h.req, dir, 0, &mut reply)
+        });
+        fetching.recv().unwrap();
+        h.fs.services()
+            .replace(planets("blue"), ServiceConfig::default())
+            .unwrap();
+        go.send(()).unwrap();
+        assert_eq!(list.join().unwrap().err(), Some(ESTALE));
+    });
+    assert!(h.readdir("/planets").unwrap().is_empty());
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+#[test]
+fn removing_a_service_takes_its_dir_history_and_controls() {
+    let config = ServiceConfig {
+        history: 2,
+        ..Default::default()
+    };
+    let mut h = Harness::with_service(planets("third"), config);
+    h.fs.enable_control();
+    h.create("/planets/earth", 0o644).unwrap();
+    h.refresh("/planets/earth").unwrap();
+
+    h.fs.services().remove("planets").unwrap();
+    assert_eq!(h.lookup("/planets").err(), Some(ENOENT));
+    assert!(h.readdir("/.vfs/services").unwrap().is_empty());
+    assert_eq!(h.fs.services().remove("planets"), Err(ENOENT));
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+#[test]
+fn handles_outliving_the_mount_fail() {
+    let h = Harness::new(vec![]);
+    let services = h.fs.services();
+    drop(h);
+
+    assert_eq!(services.remove("planets"), Err(ENODEV));
+}
+
+#[test]
+fn failed_replaces_keep_the_old_service() {
+    let mut h = Harness::new(vec![planets("third")]);
+    h.create("/planets/earth", 0o644).unwrap();
+    let svc = Box::new(StaticService::new("planets/2"));
+
+    assert_eq!(
+        h.fs.services().replace(svc, ServiceConfig::default()),
+        Err(EINVAL)
+    );
+    h.mkdir("/moons", 0o755).unwrap();
+    let svc = Box::new(StaticService::new("moons"));
+    assert_eq!(
+        h.fs.services().replace(svc, ServiceConfig::default()),
+        Err(EINVAL)
+    );
+    assert_eq!(h.read("/planets/earth").unwrap(), b"third");
+    assert_eq!(h.check_invariants(), Ok(()));
+}
@@ -0,0 +1,400 @@
+use libc::{EACCES, EIO, EISDIR, ENOENT, ENOSPC, ENOTEMPTY, EPERM, EROFS};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use time::Timespec;
+use vfs_service::harness::{Harness, MockReplyDirectory, MockRequest};
+use vfs_service::{ServiceConfig, SingleService};
+
+struct EchoService {}
+
+impl SingleService for EchoService {
+    fn get_name(&self) -> String {
+        "echo".to_string()
+    }
+
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        vec![format!("you asked for {}", query.unwrap_or("nothing"))]
+    }
+}
+
+fn echo() -> Box<dyn SingleService + Send> {
+    Box::new(EchoService {})
+}
+
+// lists a hundred files and counts what it is asked for
+struct ManyService {
+    fetches: Arc<AtomicUsize>,
+}
+
+impl SingleService for ManyService {
+    fn get_name(&self) -> String {
+        "many".to_string()
+    }
+
+    fn fetch_data(&self, query: Option<&str>) -> Vec<String> {
+        self.fetches.fetch_add(1, Ordering::SeqCst);
+        vec![query.unwrap_or_default().to_string()]
+    }
+
+    fn list(&self) -> Vec<String> {
+        (0..100).map(|i| format!("f{:02}", i)).collect()
+    }
+}
+
+// takes written files back unless it is down, keeping what it took
+#[derive(Default, Clone)]
+struct NotesService {
+    down: Arc<AtomicBool>,
+    taken: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl SingleService for NotesService {
+    fn get_name(&self) -> String {
+        "notes".to_string()
+    }
+
+    fn fetch_data(&self, _query: Option<&str>) -> Vec<String> {
+        vec![]
+    }
+
+    fn write_back(&self, _query: &str, data: &[u8]) -> io::Result<()> {
+        if self.down.load(Ordering::SeqCst) {
+            return Err(io::Error::from_raw_os_error(EIO));
+        }
+        self.taken.lock().unwrap().push(data.to_vec());
+        Ok(())
+    }
+}
+
+#[test]
+fn write_then_read_back() {
+    let mut h = Harness::new(vec![]);
+    h.mkdir("/a", 0o755).unwrap();
+    h.write("/a/b", b"hello world").unwrap();
+
+    assert_eq!(h.read("/a/b").unwrap(), b"hello world");
+    assert_eq!(h.read_at("/a/b", 6, 3).unwrap(), b"wor");
+    assert_eq!(h.readdir("/").unwrap(), vec!["a"]);
+    assert_eq!(h.readdir("/a").unwrap(), vec!["b"]);
+
+    h.write("/a/b", b"bye").unwrap();
+    assert_eq!(h.read("/a/b").unwrap(), b"bye");
+}
+
+#[test]
+fn write_at_offset_and_truncate() {
+    let mut h = Harness::new(vec![]);
+    h.write("/f", b"abcdef").unwrap();
+    h.write_at("/f", 2, b"XY").unwrap();
+    assert_eq!(h.read("/f").unwrap(), b"abXYef");
+
+    h.write_at("/f", 8, b"!").unwrap();
+    assert_eq!(h.read("/f").unwrap(), b"abXYef\0\0!");
+
+    assert_eq!(h.truncate("/f", 3).unwrap().size, 3);
+    assert_eq!(h.read("/f").unwrap(), b"abX");
+}
+
+#[test]
+fn lookups_update_atime() {
+    let mut h = Harness::new(vec![]);
+    h.write("/f", b"x").unwrap();
+    let ino = h.resolve("/f").unwrap();
+    let epoch = Some(Timespec::new(0, 0));
+    h.fs.set_times(&h.req, ino, epoch, None).unwrap();
+    assert_eq!(
+        h.fs.get_attr(&h.req, ino).unwrap().atime,
+        Timespec::new(0, 0)
+    );
+
+    assert!(h.lookup("/f").unwrap().attr.atime.sec > 0);
+    assert!(h.fs.get_attr(&h.req, ino).unwrap().atime.sec > 0);
+}
+
+#[test]
+fn rmdir_and_unlink_semantics() {
+    let mut h = Harness::new(vec![echo()]);
+    h.mkdir("/d", 0o755).unwrap();
+    h.write("/d/f", b"x").unwrap();
+
+    assert_eq!(h.rmdir("/d"), Err(ENOTEMPTY));
+    assert_eq!(h.unlink("/d"), Err(EISDIR));
+    assert_eq!(h.unlink("/d/missing"), Err(ENOENT));
+    assert_eq!(h.rmdir("/echo"), Err(EPERM));
+
+    h.unlink("/d/f").unwrap();
+    h.rmdir("/d").unwrap();
+    assert_eq!(h.resolve("/d"), Err(ENOENT));
+}
+
+#[test]
+fn readdir_reports_dot_and_dotdot() {
+    let mut h = Harness::new(vec![]);
+    h.mkdir("/a", 0o755).unwrap();
+    h.mkdir("/a/b", 0o755).unwrap();
+    let (a, b) = (h.resolve("/a").unwrap(), h.resolve("/a/b").unwrap());
+
+    let entries = h.readdir_entries("/a/b").unwrap();
+    assert_eq!((entries[0].name.to_str(), entries[0].ino), (Some("."), b));
+    assert_eq!((entries[1].name.to_str(), entries[1].ino), (Some(".."), a));
+
+    let root = h.readdir_entries("/").unwrap();
+    assert_eq!((root[1].name.to_str(), root[1].ino), (Some(".."), 1));
+}
+
+#[test]
+fn readdir_pages_survive_changes() {
+    let mut h = Harness::new(vec![]);
+    h.mkdir("/d", 0o755).unwrap();
+    for i in 0..20 {
+        h.write(&format!("/d/f{:02}", i), b"").unwrap();
+    }
+    let ino = h.resolve("/d").unwrap();
+
+    let mut page = MockReplyDirectory {
+        entries: vec![],
+        limit: Some(8),
+    };
+    h.fs.read_dir(&h.req, ino, 0, &mut page).unwrap();
+    let mut seen: Vec<String> = page
+        .entries
+        .iter()
+        .map(|e| e.name.to_string_lossy().into_owned())
+        .collect();
+
+    // drop one entry already listed and one still to come
+    h.unlink("/d/f00").unwrap();
+    h.unlink("/d/f10").unwrap();
+
+    let mut offset = page.entries.last().unwrap().offset;
+    loop {
+        let mut page = MockReplyDirectory {
+            entries: vec![],
+            limit: Some(8),
+        };
+        h.fs.read_dir(&h.req, ino, offset, &mut page).unwrap();
+        match page.entries.last() {
+            Some(last) => offset = last.offset,
+            None => break,
+        }
+        seen.extend(
+            page.entries
+                .iter()
+                .map(|e| e.name.to_string_lossy().into_owned()),
+        );
+    }
+
+    let mut expected: Vec<String> = vec![".".to_string(), "..".to_string()];
+    expected.extend((0..20).filter(|i| *i != 10).map(|i| format!("f{:02}", i)));
+    assert_eq!(seen, expected);
+}
+
+#[test]
+fn readdir_pages_keep_every_hard_link() {
+    let mut h = Harness::new(vec![]);
+    h.mkdir("/d", 0o755).unwrap();
+    h.write("/d/a", b"x").unwrap();
+    h.link("/d/a", "/d/b").unwrap();
+    h.write("/d/c", b"y").unwrap();
+
+    // the first page ends between the two names of one ino
+    h.readdir_limit = Some(3);
+    assert_eq!(h.readdir("/d").unwrap(), vec!["a", "b", "c"]);
+}
+
+#[test]
+fn rename_moves_entries() {
+    let mut h = Harness::new(vec![]);
+    h.mkdir("/src", 0o755).unwrap();
+    h.mkdir("/dst", 0o755).unwrap();
+    h.write("/src/f", b"data").unwrap();
+
+    h.rename("/src/f", "/dst/g").unwrap();
+    assert_eq!(h.resolve("/src/f"), Err(ENOENT));
+    assert_eq!(h.read("/dst/g").unwrap(), b"data");
+}
+
+#[test]
+fn hard_links_share_content() {
+    let mut h = Harness::new(vec![]);
+    h.mkdir("/d", 0o755).unwrap();
+    h.write("/a", b"shared").unwrap();
+
+    assert_eq!(h.link("/a", "/d/b").unwrap().nlink, 2);
+    assert_eq!(h.link("/d", "/e").err(), Some(EPERM));
+    h.write_at("/d/b", 0, b"S").unwrap();
+    assert_eq!(h.read("/a").unwrap(), b"Shared");
+
+    let ino = h.resolve("/a").unwrap();
+    h.unlink("/a").unwrap();
+    assert_eq!(h.getattr("/d/b").unwrap().nlink, 1);
+    assert_eq!(h.path_of(ino).unwrap(), "/d/b");
+}
+
+#[test]
+fn service_files_are_fetched_on_create() {
+    let mut h = Harness::new(vec![echo()]);
+    h.create("/echo/10002", 0o644).unwrap();
+
+    assert_eq!(h.read("/echo/10002").unwrap(), b"you asked for 10002");
+    assert_eq!(h.readdir("/echo").unwrap(), vec!["10002"]);
+}
+
+#[test]
+fn service_dirs_hold_no_dirs_of_users() {
+    let mut h = Harness::new(vec![echo()]);
+    assert_eq!(h.mkdir("/echo/sub", 0o755).err(), Some(EPERM));
+    assert!(h.readdir("/echo").unwrap().is_empty());
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+#[test]
+fn permissions_follow_the_caller() {
+    let mut h = Harness::new(vec![]);
+    h.fs.set_umask(0);
+    h.mkdir("/shared", 0o777).unwrap();
+    h.as_user(1000, 1000).write("/shared/mine", b"x").unwrap();
+
+    let attr = h.getattr("/shared/mine").unwrap();
+    assert_eq!((attr.uid, attr.gid, attr.perm), (1000, 1000, 0o644));
+
+    h.as_user(1001, 1001);
+    assert_eq!(h.read("/shared/mine").unwrap(), b"x");
+    assert_eq!(h.write("/shared/mine", b"y"), Err(EACCES));
+    assert_eq!(h.chmod("/shared/mine", 0o666).err(), Some(EPERM));
+
+    h.as_user(1000, 1000).chmod("/shared/mine", 0o600).unwrap();
+    assert_eq!(h.as_user(1001, 1001).read("/shared/mine"), Err(EACCES));
+}
+
+#[test]
+fn modes_are_kept_as_asked_for() {
+    let mut h = Harness::new(vec![]);
+    h.mkdir("/open", 0o777).unwrap();
+    assert_eq!(h.getattr("/open").unwrap().perm, 0o777);
+    h.create("/f", 0o666).unwrap();
+    assert_eq!(h.getattr("/f").unwrap().perm, 0o666);
+    h.fs.set_umask(0o022);
+    h.mkdir("/d", 0o777).unwrap();
+    assert_eq!(h.getattr("/d").unwrap().perm, 0o755);
+}
+
+#[test]
+fn sticky_dirs_keep_entries_to_their_owners() {
+    let mut h = Harness::new(vec![echo()]);
+    h.fs.set_umask(0);
+    assert_eq!(h.getattr("/echo").unwrap().perm, 0o1777);
+    h.as_user(1000, 1000).create("/echo/a", 0o644).unwrap();
+    h.as_user(1001, 1001).create("/echo/b", 0o644).unwrap();
+    assert_eq!(h.unlink("/echo/a"), Err(EPERM));
+    h.unlink("/echo/b").unwrap();
+
+    h.as_user(0, 0).mkdir("/tmp", 0o1777).unwrap();
+    h.as_user(1000, 1000).write("/tmp/mine", b"x").unwrap();
+    h.as_user(1001, 1001).write("/tmp/theirs", b"y").unwrap();
+    assert_eq!(h.unlink("/tmp/mine"), Err(EPERM));
+    assert_eq!(h.rename("/tmp/mine", "/tmp/taken"), Err(EPERM));
+    assert_eq!(h.rename("/tmp/theirs", "/tmp/mine"), Err(EPERM));
+
+    // the owner of the entry and the owner of the dir still can
+    h.as_user(1000, 1000)
+        .rename("/tmp/mine", "/tmp/moved")
+        .unwrap();
+    h.as_user(0, 0).unlink("/tmp/moved").unwrap();
+    assert_eq!(h.readdir("/tmp").unwrap(), vec!["theirs"]);
+}
+
+#[test]
+fn listings_fetch_a_few_at_a_time_for_those_who_may_list() {
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let many = ManyService {
+        fetches: fetches.clone(),
+    };
+    let mut h = Harness::new(vec![Box::new(many)]);
+    h.chmod("/many", 0o1711).unwrap();
+    assert_eq!(h.as_user(1000, 1000).readdir("/many"), Err(EACCES));
+    assert_eq!(fetches.load(Ordering::SeqCst), 0);
+
+    h.as_user(0, 0);
+    assert_eq!(h.readdir("/many").unwrap().len(), 32);
+    assert_eq!(h.readdir("/many").unwrap().len(), 64);
+    assert_eq!(fetches.load(Ordering::SeqCst), 64);
+}
+
+#[test]
+fn service_acl_denies_other_users() {
+    let config = ServiceConfig {
+        allow_uids: vec![1000],
+        ..Default::default()
+    };
+    let mut h = Harness::with_service(echo(), config);
+    h.as_user(1000, 1000).create("/echo/q", 0o644).unwrap();
+
+    h.as_user(1001, 1001);
+    assert_eq!(h.resolve("/echo"), Err(EACCES));
+    assert!(h.readdir("/").unwrap().is_empty());
+
+    assert_eq!(h.as_user(1000, 1000).readdir("/").unwrap(), vec!["echo"]);
+}
+
+#[test]
+fn writes_stay_to_be_handed_back_until_the_service_takes_them() {
+    let notes = NotesService::default();
+    let config = ServiceConfig {
+        allow_uids: vec![1000],
+        ..Default::default()
+    };
+    let mut h = Harness::with_service(Box::new(notes.clone()), config);
+    h.as_user(1000, 1000).create("/notes/todo", 0o644).unwrap();
+    let ino = h.resolve("/notes/todo").unwrap();
+
+    notes.down.store(true, Ordering::SeqCst);
+    assert_eq!(h.write("/notes/todo", b"milk"), Err(EIO));
+    notes.down.store(false, Ordering::SeqCst);
+    let other = MockRequest {
+        uid: 1001,
+        gid: 1001,
+    };
+    assert_eq!(h.fs.flush_file(&other, ino), Err(EACCES));
+    assert!(notes.taken.lock().unwrap().is_empty());
+
+    h.flush("/notes/todo").unwrap();
+    h.flush("/notes/todo").unwrap();
+    assert_eq!(*notes.taken.lock().unwrap(), vec![b"milk".to_vec()]);
+}
+
+#[test]
+fn read_only_mounts_refuse_changes() {
+    let mut h = Harness::new(vec![echo()]);
+    h.write("/f", b"x").unwrap();
+    h.touch("/f").unwrap();
+    h.fs.set_read_only(true);
+
+    assert_eq!(h.mkdir("/d", 0o755).err(), Some(EROFS));
+    assert_eq!(h.write("/f", b"y"), Err(EROFS));
+    assert_eq!(h.unlink("/f"), Err(EROFS));
+    assert_eq!(h.rename("/f", "/g"), Err(EROFS));
+    assert_eq!(h.touch("/f").err(), Some(EROFS));
+
+    // service files can still be fetched
+    h.create("/echo/q", 0o644).unwrap();
+    assert_eq!(h.read("/echo/q").unwrap(), b"you asked for q");
+}
+
+#[test]
+fn statfs_tracks_usage_and_capacity() {
+    let mut h = Harness::new(vec![]);
+    h.fs.set_capacity(1024);
+    h.write("/f", &[1; 600]).unwrap();
+
+    let stats = h.statfs();
+    assert_eq!(stats.used_bytes, 600);
+    assert_eq!(stats.blocks, 2);
+    assert_eq!(stats.blocks_free, 0);
+
+    // the same bytes again are stored once
+    h.write("/g", &[1; 600]).unwrap();
+    assert_eq!(h.statfs().used_bytes, 600);
+    assert_eq!(h.write("/h", &[2; 600]), Err(ENOSPC));
+}
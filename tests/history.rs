@@ -0,0 +1,126 @@
+use libc::{EACCES, ENOENT, EROFS};
+
+use vfs_service::harness::Harness;
+use vfs_service::ServiceConfig;
+
+mod common;
+use common::counter;
+
+fn harness(history: usize) -> Harness {
+    let config = ServiceConfig {
+        history,
+        ..Default::default()
+    };
+    Harness::with_service(counter(), config)
+}
+
+#[test]
+fn refreshes_keep_earlier_answers() {
+    let mut h = harness(2);
+    h.create("/weather/10002", 0o644).unwrap();
+    h.refresh("/weather/10002").unwrap();
+    h.refresh("/weather/10002").unwrap();
+    assert_eq!(
+        h.read("/weather/10002").unwrap(),
+        b"10002 forecast\nanswer 3"
+    );
+
+    assert_eq!(
+        h.read("/weather/10002@v3").unwrap(),
+        b"10002 forecast\nanswer 3"
+    );
+    assert_eq!(
+        h.read("/weather/10002@v2").unwrap(),
+        b"10002 forecast\nanswer 2"
+    );
+    // only two are kept
+    assert_eq!(h.lookup("/weather/10002@v1").err(), Some(ENOENT));
+    assert_eq!(h.lookup("/weather/10002@vx").err(), Some(ENOENT));
+
+    let kept = h.readdir("/weather/.history/10002").unwrap();
+    assert_eq!(kept.len(), 3);
+    assert!(kept.contains(&"changes".to_string()));
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+#[test]
+fn changes_show_what_the_service_said_differently() {
+    let mut h = harness(5);
+    h.create("/weather/10002", 0o644).unwrap();
+    h.refresh("/weather/10002").unwrap();
+
+    let changes = h.read("/weather/.history/10002/changes").unwrap();
+    let changes = String::from_utf8(changes).unwrap();
+    let lines: Vec<&str> = changes.lines().collect();
+    assert!(lines[0].starts_with("--- v1 "));
+    assert!(lines[1].starts_with("+++ v2 "));
+    assert_eq!(&lines[2..], [" 10002 forecast", "-answer 1", "+answer 2"]);
+}
+
+#[test]
+fn changes_only_cover_kept_versions() {
+    let mut h = harness(2);
+    h.create("/weather/10002", 0o644).unwrap();
+    for _ in 0..3 {
+        h.refresh("/weather/10002").unwrap();
+    }
+
+    let changes = h.read("/weather/.history/10002/changes").unwrap();
+    let changes = String::from_utf8(changes).unwrap();
+    let lines: Vec<&str> = changes.lines().collect();
+    assert!(lines[0].starts_with("--- v3 "));
+    assert!(lines[1].starts_with("+++ v4 "));
+    assert_eq!(&lines[2..], [" 10002 forecast", "-answer 3", "+answer 4"]);
+}
+
+#[test]
+fn history_is_read_only() {
+    let mut h = harness(1);
+    h.create("/weather/q", 0o644).unwrap();
+    let kept = h.readdir("/weather/.history/q").unwrap();
+    let version = kept.iter().find(|name| *name != "changes").unwrap();
+    let path = format!("/weather/.history/q/{}", version);
+
+    assert_eq!(h.write(&path, b"forged"), Err(EROFS));
+    assert_eq!(h.unlink(&path), Err(EROFS));
+    assert_eq!(h.write("/weather/q@v1", b"forged"), Err(EROFS));
+    assert_eq!(h.read(&path).unwrap(), b"q forecast\nanswer 1");
+}
+
+#[test]
+fn no_history_unless_asked_for() {
+    let mut h = harness(0);
+    h.create("/weather/q", 0o644).unwrap();
+    h.refresh("/weather/q").unwrap();
+
+    assert_eq!(h.readdir("/weather").unwrap(), vec!["q"]);
+    assert_eq!(h.read("/weather/q").unwrap(), b"q forecast\nanswer 2");
+}
+
+#[test]
+fn only_those_who_can_write_refresh() {
+    let mut h = harness(5);
+    h.as_user(1000, 1000).create("/weather/q", 0o644).unwrap();
+
+    assert_eq!(
+        h.as_user(1001, 1001).refresh("/weather/q").err(),
+        Some(EACCES)
+    );
+    assert_eq!(h.read("/weather/q").unwrap(), b"q forecast\nanswer 1");
+    h.as_user(1000, 1000).refresh("/weather/q").unwrap();
+    assert_eq!(h.read("/weather/q").unwrap(), b"q forecast\nanswer 2");
+}
+
+#[test]
+fn versions_keep_their_bytes_when_the_file_is_dropped() {
+    let mut h = harness(5);
+    h.fs.set_memory_budget(0);
+    h.create("/weather/q", 0o644).unwrap();
+    h.refresh("/weather/q").unwrap();
+
+    assert_eq!(h.read("/weather/q@v1").unwrap(), b"q forecast\nanswer 1");
+    assert_eq!(h.read("/weather/q@v2").unwrap(), b"q forecast\nanswer 2");
+    let changes = h.read("/weather/.history/q/changes").unwrap();
+    let changes = String::from_utf8(changes).unwrap();
+    assert!(changes.ends_with("-answer 1\n+answer 2\n"));
+}
@@ -0,0 +1,70 @@
+use std::ffi::OsStr;
+
+use file_node::{gen_file_node, ServiceConfig};
+use file_store::fstore::FileStore;
+use vfs_service::services::StaticService;
+
+fn touch(store: &mut FileStore, parent: u64, name: &str) -> u64 {
+    store
+        .add_child(&parent, gen_file_node(), OsStr::new(name), 0, 0, 0o644)
+        .unwrap()
+}
+
+fn generation(store: &FileStore, ino: u64) -> u64 {
+    store.get(&ino).unwrap().generation
+}
+
+#[test]
+fn inos_only_count_up_by_default() {
+    let mut store = FileStore::new();
+    let f = touch(&mut store, 1, "f");
+    store.unlink(&1, OsStr::new("f")).unwrap();
+
+    let g = touch(&mut store, 1, "g");
+    assert!(g > f);
+    assert_eq!(generation(&store, g), 1);
+}
+
+#[test]
+fn recycled_inos_wait_for_forget_and_bump_the_generation() {
+    let mut store = FileStore::new();
+    store.set_ino_reuse(true);
+    let f = touch(&mut store, 1, "f");
+    store.note_lookup(&f);
+    store.unlink(&1, OsStr::new("f")).unwrap();
+
+    // the kernel still holds f
+    let g = touch(&mut store, 1, "g");
+    assert_ne!(g, f);
+
+    store.forget(&f, 1);
+    let h = touch(&mut store, 1, "h");
+    assert_eq!(h, f);
+    assert_eq!(generation(&store, h), 2);
+    assert_eq!(store.check_invariants(), Ok(()));
+}
+
+fn with_services(names: &[&str]) -> FileStore {
+    let mut store = FileStore::new();
+    for name in names {
+        let svc = StaticService::new(name).with("q", "answer");
+        store.register_service(Box::new(svc), ServiceConfig::default());
+    }
+    store
+}
+
+#[test]
+fn service_inos_survive_a_restart() {
+    let mut first = with_services(&["echo", "weather"]);
+    let mut second = with_services(&["weather", "echo"]);
+    let dir = first.resolve("/echo").unwrap();
+    assert_eq!(second.resolve("/echo"), Ok(dir));
+
+    let q = touch(&mut first, dir, "q");
+    assert_eq!(touch(&mut second, dir, "q"), q);
+
+    // fetching again after a delete reuses the ino under a new generation
+    first.unlink(&dir, OsStr::new("q")).unwrap();
+    assert_eq!(touch(&mut first, dir, "q"), q);
+    assert_eq!(generation(&first, q), 2);
+}
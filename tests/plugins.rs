@@ -0,0 +1,134 @@
+#![cfg(feature = "plugins")]
+use libc::EINVAL;
+use std::ffi::c_void;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+use file_node::plugin::shim;
+use vfs_service::harness::Harness;
+use vfs_service::plugins::{self, PluginService};
+use vfs_service::SingleService;
+
+mod common;
+use common::{mount, scratch};
+
+// builds plugins/echo and returns the dir its library is in
+fn echo_plugin_dir() -> PathBuf {
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "-p", "echo_plugin"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let target = std::env::var_os("CARGO_TARGET_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target"));
+    target.join("debug")
+}
+
+fn echo_plugin() -> PathBuf {
+    let name = format!(
+        "{}echo_plugin.{}",
+        std::env::consts::DLL_PREFIX,
+        std::env::consts::DLL_EXTENSION
+    );
+    echo_plugin_dir().join(name)
+}
+
+#[test]
+fn plugins_serve_like_any_other_service() {
+    let plugin = PluginService::load(echo_plugin()).unwrap();
+    assert_eq!(plugin.get_name(), "echo");
+    assert_eq!(plugin.fetch_data(None), vec!["echo "]);
+
+    let mut h = mount(plugin);
+    h.create("/echo/hello", 0o644).unwrap();
+    assert_eq!(h.read("/echo/hello").unwrap(), b"echo hello");
+}
+
+#[test]
+fn plugins_pass_on_the_rest_of_the_service() {
+    let plugin = PluginService::load(echo_plugin()).unwrap();
+    assert_eq!(plugin.list(), vec!["hello"]);
+    assert_eq!(plugin.max_age("hello"), Some(Duration::from_secs(60)));
+    assert_eq!(
+        plugin.attributes("hello"),
+        vec![("user.echoed".to_string(), "hello".to_string())]
+    );
+    plugin.write_back("hello", b"taken").unwrap();
+
+    // dirs keep the library loaded after the service that made them
+    let dirs = plugin.dirs();
+    drop(plugin);
+    assert_eq!(dirs.len(), 1);
+    assert_eq!(dirs[0].get_name(), "loud");
+    assert_eq!(dirs[0].fetch_data(Some("hi")), vec!["ECHO HI"]);
+    assert!(dirs[0].list().is_empty());
+    assert_eq!(dirs[0].max_age("hi"), None);
+
+    let mut h = mount(PluginService::load(echo_plugin()).unwrap());
+    assert_eq!(h.readdir("/echo").unwrap(), vec!["loud", "hello"]);
+    assert_eq!(h.read("/echo/hello").unwrap(), b"echo hello");
+    assert_eq!(h.getxattr("/echo/hello", "user.echoed").unwrap(), b"hello");
+}
+
+#[test]
+fn plugins_load_from_a_dir_and_the_control_files() {
+    assert!(plugins::load_dir(echo_plugin_dir())
+        .unwrap()
+        .iter()
+        .any(|plugin| plugin.get_name() == "echo"));
+
+    let mut h = Harness::new(vec![]);
+    h.fs.enable_control();
+    let command = format!("load {}\n", echo_plugin().display());
+    h.write("/.vfs/ctl", command.as_bytes()).unwrap();
+    h.create("/echo/again", 0o644).unwrap();
+    assert_eq!(h.read("/echo/again").unwrap(), b"echo again");
+
+    assert_eq!(
+        h.write("/.vfs/ctl", b"load /no/such/plugin.so"),
+        Err(EINVAL)
+    );
+}
+
+#[test]
+fn libraries_that_are_not_plugins_are_refused() {
+    let dir = scratch("plugins");
+    let fake = dir.join("fake.so");
+    std::fs::write(&fake, b"not a library").unwrap();
+
+    assert!(PluginService::load(&fake).is_err());
+    assert!(plugins::load_dir(&dir).unwrap().is_empty());
+}
+
+// panics wherever it can
+struct Grumpy;
+
+impl SingleService for Grumpy {
+    fn fetch_data(&self, _: Option<&str>) -> Vec<String> {
+        panic!("no answer")
+    }
+
+    fn get_name(&self) -> String {
+        "grumpy".to_string()
+    }
+}
+
+impl Drop for Grumpy {
+    fn drop(&mut self) {
+        panic!("not going")
+    }
+}
+
+#[test]
+fn plugin_panics_stay_in_the_plugin() {
+    let svc = Box::into_raw(Box::new(Grumpy)) as *mut c_void;
+    unsafe {
+        let answer = shim::fetch::<Grumpy>(svc, std::ptr::null(), 0);
+        assert!(answer.as_slice().is_empty());
+        shim::free_buf(answer);
+        shim::destroy::<Grumpy>(svc);
+    }
+}
@@ -0,0 +1,358 @@
+//! Mounts `Fs` on a temp directory and runs std::fs based POSIX checks
+//! against it. Needs a working FUSE install, so it only builds with
+//! `cargo test --features posix-tests -- --nocapture`.
+//!
+//! Every check runs and lands in a compatibility report (printed, and written
+//! to $POSIX_REPORT when set). The test fails when a check outside
+//! KNOWN_GAPS fails, so regressions show up while gaps stay documented.
+#![cfg(feature = "posix-tests")]
+
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::{env, process};
+
+use vfs_service::fuse_system::Fs;
+
+type Check = fn(&Path) -> Result<(), String>;
+
+// checks we know Fs doesn't pass yet
+const KNOWN_GAPS: &[&str] = &["xattr_roundtrip"];
+
+fn checks() -> Vec<(&'static str, Check)> {
+    vec![
+        ("create_and_read", create_and_read),
+        ("create_exclusive", create_exclusive),
+        ("overwrite_shorter", overwrite_shorter),
+        ("append", append),
+        ("seek_and_write", seek_and_write),
+        ("write_past_end", write_past_end),
+        ("truncate_shrink", truncate_shrink),
+        ("truncate_grow", truncate_grow),
+        ("rename_same_dir", rename_same_dir),
+        ("rename_across_dirs", rename_across_dirs),
+        ("rename_over_existing", rename_over_existing),
+        ("unlink_file", unlink_file),
+        ("unlink_dir_fails", unlink_dir_fails),
+        ("unlink_missing", unlink_missing),
+        ("mkdir_existing", mkdir_existing),
+        ("rmdir_empty", rmdir_empty),
+        ("rmdir_not_empty", rmdir_not_empty),
+        ("readdir_lists_entries", readdir_lists_entries),
+        ("readdir_after_unlink", readdir_after_unlink),
+        ("readdir_many", readdir_many),
+        ("hard_link", hard_link),
+        ("xattr_roundtrip", xattr_roundtrip),
+    ]
+}
+
+macro_rules! ensure {
+    ($cond:expr, $($msg:tt)+) => {
+        if !$cond {
+            return Err(format!($($msg)+));
+        }
+    };
+}
+
+fn io<T>(res: std::io::Result<T>) -> Result<T, String> {
+    res.map_err(|e| e.to_string())
+}
+
+fn expect_kind<T>(res: std::io::Result<T>, kind: ErrorKind) -> Result<(), String> {
+    match res {
+        Ok(_) => Err(format!("expected {:?}, got success", kind)),
+        Err(e) if e.kind() == kind => Ok(()),
+        Err(e) => Err(format!("expected {:?}, got {}", kind, e)),
+    }
+}
+
+fn expect_errno<T>(res: std::io::Result<T>, errno: i32) -> Result<(), String> {
+    match res {
+        Ok(_) => Err(format!("expected errno {}, got success", errno)),
+        Err(e) if e.raw_os_error() == Some(errno) => Ok(()),
+        Err(e) => Err(format!("expected errno {}, got {}", errno, e)),
+    }
+}
+
+fn create_and_read(root: &Path) -> Result<(), String> {
+    let f = root.join("create_and_read");
+    io(fs::write(&f, b"hello"))?;
+    ensure!(io(fs::read(&f))? == b"hello", "content mismatch");
+    ensure!(io(fs::metadata(&f))?.len() == 5, "size mismatch");
+    Ok(())
+}
+
+fn create_exclusive(root: &Path) -> Result<(), String> {
+    let f = root.join("create_exclusive");
+    io(fs::write(&f, b"x"))?;
+    let res = OpenOptions::new().write(true).create_new(true).open(&f);
+    expect_kind(res, ErrorKind::AlreadyExists)
+}
+
+fn overwrite_shorter(root: &Path) -> Result<(), String> {
+    let f = root.join("overwrite_shorter");
+    io(fs::write(&f, b"hello world"))?;
+    io(fs::write(&f, b"hi"))?;
+    ensure!(io(fs::read(&f))? == b"hi", "stale bytes after O_TRUNC");
+    Ok(())
+}
+
+fn append(root: &Path) -> Result<(), String> {
+    let f = root.join("append");
+    io(fs::write(&f, b"abc"))?;
+    let mut file = io(OpenOptions::new().append(true).open(&f))?;
+    io(file.write_all(b"def"))?;
+    drop(file);
+    ensure!(
+        io(fs::read(&f))? == b"abcdef",
+        "append didn't land at the end"
+    );
+    Ok(())
+}
+
+fn seek_and_write(root: &Path) -> Result<(), String> {
+    let f = root.join("seek_and_write");
+    io(fs::write(&f, b"abcdef"))?;
+    let mut file = io(OpenOptions::new().read(true).write(true).open(&f))?;
+    io(file.seek(SeekFrom::Start(2)))?;
+    io(file.write_all(b"XY"))?;
+    io(file.seek(SeekFrom::Start(0)))?;
+    let mut buf = Vec::new();
+    io(file.read_to_end(&mut buf))?;
+    ensure!(buf == b"abXYef", "got {:?}", String::from_utf8_lossy(&buf));
+    Ok(())
+}
+
+fn write_past_end(root: &Path) -> Result<(), String> {
+    let f = root.join("write_past_end");
+    io(fs::write(&f, b"ab"))?;
+    let mut file = io(OpenOptions::new().write(true).open(&f))?;
+    io(file.seek(SeekFrom::Start(4)))?;
+    io(file.write_all(b"z"))?;
+    drop(file);
+    ensure!(io(fs::read(&f))? == b"ab\0\0z", "hole not zero filled");
+    Ok(())
+}
+
+fn truncate_shrink(root: &Path) -> Result<(), String> {
+    let f = root.join("truncate_shrink");
+    io(fs::write(&f, b"abcdef"))?;
+    io(io(OpenOptions::new().write(true).open(&f))?.set_len(3))?;
+    ensure!(io(fs::read(&f))? == b"abc", "truncate kept the wrong bytes");
+    Ok(())
+}
+
+fn truncate_grow(root: &Path) -> Result<(), String> {
+    let f = root.join("truncate_grow");
+    io(fs::write(&f, b"ab"))?;
+    io(io(OpenOptions::new().write(true).open(&f))?.set_len(4))?;
+    ensure!(io(fs::read(&f))? == b"ab\0\0", "extension not zero filled");
+    Ok(())
+}
+
+fn rename_same_dir(root: &Path) -> Result<(), String> {
+    let (from, to) = (root.join("rename_a"), root.join("rename_b"));
+    io(fs::write(&from, b"x"))?;
+    io(fs::rename(&from, &to))?;
+    ensure!(!from.exists(), "source still exists");
+    ensure!(io(fs::read(&to))? == b"x", "content lost");
+    Ok(())
+}
+
+fn rename_across_dirs(root: &Path) -> Result<(), String> {
+    let (src, dst) = (root.join("rename_src"), root.join("rename_dst"));
+    io(fs::create_dir(&src))?;
+    io(fs::create_dir(&dst))?;
+    io(fs::write(src.join("f"), b"x"))?;
+    io(fs::rename(src.join("f"), dst.join("g")))?;
+    ensure!(io(fs::read_dir(&src))?.count() == 0, "source dir not empty");
+    ensure!(io(fs::read(dst.join("g")))? == b"x", "content lost");
+    Ok(())
+}
+
+fn rename_over_existing(root: &Path) -> Result<(), String> {
+    let (from, to) = (root.join("clobber_a"), root.join("clobber_b"));
+    io(fs::write(&from, b"new"))?;
+    io(fs::write(&to, b"old"))?;
+    io(fs::rename(&from, &to))?;
+    ensure!(io(fs::read(&to))? == b"new", "target not replaced");
+    let named = io(fs::read_dir(root))?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name() == "clobber_b")
+        .count();
+    ensure!(named == 1, "{} entries named clobber_b", named);
+    Ok(())
+}
+
+fn unlink_file(root: &Path) -> Result<(), String> {
+    let f = root.join("unlink_file");
+    io(fs::write(&f, b"x"))?;
+    io(fs::remove_file(&f))?;
+    expect_kind(fs::metadata(&f), ErrorKind::NotFound)
+}
+
+fn unlink_dir_fails(root: &Path) -> Result<(), String> {
+    let d = root.join("unlink_dir");
+    io(fs::create_dir(&d))?;
+    expect_errno(fs::remove_file(&d), libc::EISDIR)
+}
+
+fn unlink_missing(root: &Path) -> Result<(), String> {
+    expect_kind(fs::remove_file(root.join("missing")), ErrorKind::NotFound)
+}
+
+fn mkdir_existing(root: &Path) -> Result<(), String> {
+    let d = root.join("mkdir_existing");
+    io(fs::create_dir(&d))?;
+    expect_kind(fs::create_dir(&d), ErrorKind::AlreadyExists)
+}
+
+fn rmdir_empty(root: &Path) -> Result<(), String> {
+    let d = root.join("rmdir_empty");
+    io(fs::create_dir(&d))?;
+    io(fs::remove_dir(&d))?;
+    expect_kind(fs::metadata(&d), ErrorKind::NotFound)
+}
+
+fn rmdir_not_empty(root: &Path) -> Result<(), String> {
+    let d = root.join("rmdir_not_empty");
+    io(fs::create_dir(&d))?;
+    io(fs::write(d.join("f"), b"x"))?;
+    expect_errno(fs::remove_dir(&d), libc::ENOTEMPTY)
+}
+
+fn names(dir: &Path) -> Result<Vec<String>, String> {
+    let mut names = io(fs::read_dir(dir))?
+        .map(|e| e.map(|e| e.file_name().to_string_lossy().into_owned()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+    names.sort();
+    Ok(names)
+}
+
+fn readdir_lists_entries(root: &Path) -> Result<(), String> {
+    let d = root.join("readdir_lists");
+    io(fs::create_dir(&d))?;
+    io(fs::write(d.join("a"), b""))?;
+    io(fs::create_dir(d.join("b")))?;
+    let found = names(&d)?;
+    ensure!(found == ["a", "b"], "listed {:?}", found);
+    Ok(())
+}
+
+fn readdir_after_unlink(root: &Path) -> Result<(), String> {
+    let d = root.join("readdir_unlink");
+    io(fs::create_dir(&d))?;
+    for name in &["a", "b", "c"] {
+        io(fs::write(d.join(name), b""))?;
+    }
+    io(fs::remove_file(d.join("b")))?;
+    let found = names(&d)?;
+    ensure!(found == ["a", "c"], "listed {:?}", found);
+    Ok(())
+}
+
+fn readdir_many(root: &Path) -> Result<(), String> {
+    let d = root.join("readdir_many");
+    io(fs::create_dir(&d))?;
+    let expected: Vec<String> = (0..500).map(|i| format!("file_{:04}", i)).collect();
+    for name in &expected {
+        io(fs::write(d.join(name), b""))?;
+    }
+    let found = names(&d)?;
+    ensure!(
+        found == expected,
+        "listed {} entries, expected {}",
+        found.len(),
+        expected.len()
+    );
+    Ok(())
+}
+
+fn hard_link(root: &Path) -> Result<(), String> {
+    let (a, b) = (root.join("link_a"), root.join("link_b"));
+    io(fs::write(&a, b"shared"))?;
+    io(fs::hard_link(&a, &b))?;
+    ensure!(io(fs::metadata(&a))?.nlink() == 2, "nlink not bumped");
+    io(fs::remove_file(&a))?;
+    ensure!(io(fs::read(&b))? == b"shared", "link lost its content");
+    ensure!(io(fs::metadata(&b))?.nlink() == 1, "nlink not dropped");
+    Ok(())
+}
+
+fn xattr_roundtrip(root: &Path) -> Result<(), String> {
+    let f = root.join("xattr");
+    io(fs::write(&f, b""))?;
+    let path = CString::new(f.as_os_str().as_bytes()).unwrap();
+    let name = CString::new("user.test").unwrap();
+    let value = b"yes";
+    let mut buf = [0u8; 16];
+    let read = unsafe {
+        let set = libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        );
+        if set != 0 {
+            return Err(format!("setxattr: {}", std::io::Error::last_os_error()));
+        }
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+    ensure!(read == value.len() as isize, "getxattr returned {}", read);
+    ensure!(&buf[..value.len()] == value, "xattr value mismatch");
+    Ok(())
+}
+
+fn mountpoint() -> PathBuf {
+    let dir = env::temp_dir().join(format!("vfs-posix-{}", process::id()));
+    fs::create_dir_all(&dir).expect("can't create mountpoint");
+    dir
+}
+
+#[test]
+fn posix_conformance() {
+    let mnt = mountpoint();
+    let session = unsafe { fuse::spawn_mount(Fs::new(vec![]), &mnt, &[]) }.expect("mount failed");
+
+    let results: Vec<(&str, Result<(), String>)> = checks()
+        .into_iter()
+        .map(|(name, check)| (name, check(&mnt)))
+        .collect();
+    drop(session);
+    let _ = fs::remove_dir(&mnt);
+
+    let mut report = String::from("check                      result\n");
+    for (name, res) in &results {
+        let status = match (res, KNOWN_GAPS.contains(name)) {
+            (Ok(_), false) => "pass".to_string(),
+            (Ok(_), true) => "pass (listed as a known gap)".to_string(),
+            (Err(e), true) => format!("known gap: {}", e),
+            (Err(e), false) => format!("FAIL: {}", e),
+        };
+        report.push_str(&format!("{:<26} {}\n", name, status));
+    }
+    let passed = results.iter().filter(|(_, res)| res.is_ok()).count();
+    report.push_str(&format!("{}/{} checks pass\n", passed, results.len()));
+
+    println!("{}", report);
+    if let Ok(path) = env::var("POSIX_REPORT") {
+        fs::write(path, &report).expect("can't write report");
+    }
+
+    let regressions: Vec<&str> = results
+        .iter()
+        .filter(|(name, res)| res.is_err() && !KNOWN_GAPS.contains(name))
+        .map(|(name, _)| *name)
+        .collect();
+    assert!(regressions.is_empty(), "failing checks: {:?}", regressions);
+}
@@ -0,0 +1,57 @@
+use libc::{EEXIST, EINVAL, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, EPERM};
+use vfs_service::harness::Harness;
+use vfs_service::services::StaticService;
+
+#[test]
+fn rename_replaces_an_existing_target() {
+    let mut h = Harness::new(vec![]);
+    h.write("/a", b"new").unwrap();
+    h.write("/b", b"old").unwrap();
+
+    h.rename("/a", "/b").unwrap();
+    assert_eq!(h.read("/b").unwrap(), b"new");
+    assert_eq!(h.lookup("/a").unwrap_err(), ENOENT);
+    assert_eq!(h.readdir("/").unwrap(), vec!["b"]);
+
+    // an empty dir can be replaced by a dir, but not a full one
+    h.mkdir("/d", 0o755).unwrap();
+    h.mkdir("/e", 0o755).unwrap();
+    h.rename("/d", "/e").unwrap();
+    h.mkdir("/f", 0o755).unwrap();
+    h.write("/e/x", b"x").unwrap();
+    assert_eq!(h.rename("/f", "/e"), Err(ENOTEMPTY));
+
+    // renaming onto itself leaves it be
+    h.rename("/b", "/b").unwrap();
+    assert_eq!(h.read("/b").unwrap(), b"new");
+}
+
+#[test]
+fn rename_refuses_mismatched_kinds_and_cycles() {
+    let mut h = Harness::new(vec![]);
+    h.mkdir("/d", 0o755).unwrap();
+    h.mkdir("/d/sub", 0o755).unwrap();
+    h.write("/f", b"f").unwrap();
+
+    assert_eq!(h.rename("/f", "/d"), Err(EISDIR));
+    assert_eq!(h.rename("/d", "/f"), Err(ENOTDIR));
+    assert_eq!(h.rename("/d", "/d/sub/d"), Err(EINVAL));
+    assert_eq!(h.rename("/missing", "/g"), Err(ENOENT));
+    assert_eq!(h.mkdir("/d", 0o755).map(|_| ()), Err(EEXIST));
+
+    // nothing moved
+    assert_eq!(h.readdir("/d").unwrap(), vec!["sub"]);
+    assert_eq!(h.read("/f").unwrap(), b"f");
+}
+
+#[test]
+fn service_dirs_keep_their_name() {
+    let svc = StaticService::new("mv");
+    let mut h = Harness::new(vec![Box::new(svc)]);
+    h.mkdir("/d", 0o755).unwrap();
+
+    assert_eq!(h.rename("/mv", "/d/mv"), Err(EPERM));
+    assert_eq!(h.rename("/mv", "/moved"), Err(EPERM));
+    assert_eq!(h.readdir("/d").unwrap(), Vec::<String>::new());
+    assert_eq!(h.fs.services().remove("mv"), Ok(()));
+}
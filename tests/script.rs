@@ -0,0 +1,91 @@
+#![cfg(feature = "scripting")]
+use vfs_service::script::{self, ScriptService};
+use vfs_service::SingleService;
+
+mod common;
+use common::{mount, scratch};
+
+const GREET: &str = r#"
+fn fetch(query) {
+    if query == () {
+        return ();
+    }
+    let person = parse_json(`{"name": "${query}"}`);
+    ["hello " + person.name, env("VFS_SCRIPT_GREETING")]
+}
+
+fn list() {
+    "ada\ngrace"
+}
+"#;
+
+#[test]
+fn scripts_serve_files() {
+    let dir = scratch("script-serve");
+    let path = dir.join("greet.rhai");
+    std::fs::write(&path, GREET).unwrap();
+    std::env::set_var("VFS_SCRIPT_GREETING", "welcome");
+
+    let svc = ScriptService::load("greet", &path).unwrap();
+    assert!(svc.fetch_data(None).is_empty());
+    assert_eq!(svc.fetch_data(Some("ada")), vec!["hello ada", "welcome"]);
+
+    let mut h = mount(svc);
+    let names = h.readdir("/greet").unwrap();
+    assert!(names.contains(&"ada".to_string()) && names.contains(&"grace".to_string()));
+    assert_eq!(h.read("/greet/grace").unwrap(), b"hello grace\nwelcome");
+
+    assert_eq!(script::load_dir(&dir).unwrap().len(), 1);
+}
+
+#[test]
+fn scripts_get_urls() {
+    let dir = scratch("script-http");
+    let path = dir.join("weather.rhai");
+    std::fs::write(
+        &path,
+        r#"fn fetch(query) { parse_json(http_get("http://api.test/" + query)).sky }"#,
+    )
+    .unwrap();
+
+    let svc = ScriptService::load_with("weather", &path, |url| match url {
+        "http://api.test/today" => Ok(r#"{"sky": "clear"}"#.to_string()),
+        _ => Err("404".to_string()),
+    })
+    .unwrap();
+    assert_eq!(svc.fetch_data(Some("today")), vec!["clear"]);
+    assert!(svc.fetch_data(Some("tomorrow")).is_empty());
+}
+
+#[test]
+fn scripts_reload_when_changed() {
+    let dir = scratch("script-reload");
+    let path = dir.join("version.rhai");
+    std::fs::write(&path, r#"fn fetch(query) { "one" }"#).unwrap();
+    let svc = ScriptService::load("version", &path).unwrap();
+    assert_eq!(svc.fetch_data(Some("x")), vec!["one"]);
+    assert!(svc.list().is_empty());
+
+    std::fs::write(&path, r#"fn fetch(query) { "two" } fn list() { ["x"] }"#).unwrap();
+    assert_eq!(svc.fetch_data(Some("x")), vec!["two"]);
+    assert_eq!(svc.list(), vec!["x"]);
+
+    // a broken edit leaves the last good script serving
+    std::fs::write(&path, "fn fetch(query) {").unwrap();
+    assert_eq!(svc.fetch_data(Some("x")), vec!["two"]);
+}
+
+#[test]
+fn broken_scripts_are_refused() {
+    let dir = scratch("script-broken");
+    let path = dir.join("broken.rhai");
+    std::fs::write(&path, "fn fetch(query) {").unwrap();
+    assert!(ScriptService::load("broken", &path).is_err());
+    assert!(script::load_dir(&dir).unwrap().is_empty());
+    assert!(ScriptService::load("missing", dir.join("missing.rhai")).is_err());
+
+    // scripts that never finish are stopped
+    std::fs::write(&path, "fn fetch(query) { loop {} }").unwrap();
+    let svc = ScriptService::load("spin", &path).unwrap();
+    assert!(svc.fetch_data(Some("x")).is_empty());
+}
@@ -0,0 +1,150 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use vfs_service::harness::Harness;
+use vfs_service::services::{FnService, RecordReplayService, ReplayMode, StaticService};
+use vfs_service::SingleService;
+
+mod common;
+use common::scratch;
+
+#[test]
+fn static_service_serves_fixed_content() {
+    let svc = StaticService::new("planets")
+        .with("mars", "red\ntwo moons")
+        .with("", "pick a planet");
+    let mut h = Harness::new(vec![Box::new(svc)]);
+
+    h.create("/planets/mars", 0o644).unwrap();
+    h.create("/planets/pluto", 0o644).unwrap();
+    assert_eq!(h.read("/planets/mars").unwrap(), b"red\ntwo moons");
+    assert!(h.read("/planets/pluto").unwrap().is_empty());
+}
+
+#[test]
+fn fn_service_calls_the_closure() {
+    let svc = FnService::new("upper", |query| {
+        vec![query.unwrap_or_default().to_uppercase()]
+    });
+    let mut h = Harness::new(vec![Box::new(svc)]);
+
+    h.create("/upper/shout", 0o644).unwrap();
+    assert_eq!(h.read("/upper/shout").unwrap(), b"SHOUT");
+}
+
+#[test]
+fn recordings_replay_without_the_inner_service() {
+    let dir = scratch("recordings");
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let live = FnService::new("live", move |query| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        vec![format!("live answer for {}", query.unwrap_or("?"))]
+    });
+
+    let recorder = RecordReplayService::new(Box::new(live), &dir, ReplayMode::Auto);
+    assert_eq!(
+        recorder.fetch_data(Some("10002")),
+        vec!["live answer for 10002"]
+    );
+    assert_eq!(
+        recorder.fetch_data(Some("10002")),
+        vec!["live answer for 10002"]
+    );
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // too long to name a file after
+    let long = "q".repeat(300);
+    recorder.fetch_data(Some(&long));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    let offline = FnService::new("live", |_| panic!("replay must not go live"));
+    let player = RecordReplayService::new(Box::new(offline), &dir, ReplayMode::Replay);
+    assert_eq!(player.get_name(), "live");
+    assert_eq!(
+        player.fetch_data(Some("10002")),
+        vec!["live answer for 10002"]
+    );
+    assert!(player.fetch_data(Some("other")).is_empty());
+    assert_eq!(
+        player.fetch_data(Some(&long)),
+        vec![format!("live answer for {}", long)]
+    );
+    assert!(player.fetch_data(Some(&"r".repeat(300))).is_empty());
+}
+
+// a service with a bit of everything but fetches
+struct Rich {
+    taken: Arc<AtomicUsize>,
+}
+
+impl SingleService for Rich {
+    fn fetch_data(&self, _query: Option<&str>) -> Vec<String> {
+        vec![]
+    }
+
+    fn get_name(&self) -> String {
+        "rich".to_string()
+    }
+
+    fn list(&self) -> Vec<String> {
+        vec!["a".to_string()]
+    }
+
+    fn write_back(&self, _query: &str, _data: &[u8]) -> io::Result<()> {
+        self.taken.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn max_age(&self, _query: &str) -> Option<Duration> {
+        Some(Duration::from_secs(60))
+    }
+
+    fn attributes(&self, query: &str) -> Vec<(String, String)> {
+        vec![("user.query".to_string(), query.to_string())]
+    }
+
+    fn dirs(&self) -> Vec<Box<dyn SingleService + Send>> {
+        vec![Box::new(StaticService::new("sub").with("q", "from sub"))]
+    }
+}
+
+#[test]
+fn recordings_keep_the_rest_of_the_service() {
+    let dir = scratch("recordings-rest");
+    let taken = Arc::new(AtomicUsize::new(0));
+    let rich = Rich {
+        taken: taken.clone(),
+    };
+    let recorder = RecordReplayService::new(Box::new(rich), &dir, ReplayMode::Record);
+
+    assert_eq!(recorder.list(), vec!["a"]);
+    assert_eq!(recorder.max_age("a"), Some(Duration::from_secs(60)));
+    assert_eq!(
+        recorder.attributes("a"),
+        vec![("user.query".to_string(), "a".to_string())]
+    );
+    recorder.write_back("a", b"x").unwrap();
+    assert_eq!(taken.load(Ordering::SeqCst), 1);
+    let dirs = recorder.dirs();
+    assert_eq!(dirs.len(), 1);
+    assert_eq!(dirs[0].get_name(), "sub");
+    assert_eq!(dirs[0].fetch_data(Some("q")), vec!["from sub"]);
+
+    // replaying never reaches the live service, dirs and all
+    let rich = Rich {
+        taken: taken.clone(),
+    };
+    let player = RecordReplayService::new(Box::new(rich), &dir, ReplayMode::Replay);
+    assert!(player.list().is_empty());
+    assert_eq!(player.max_age("a"), None);
+    assert!(player.attributes("a").is_empty());
+    player.write_back("a", b"x").unwrap();
+    assert_eq!(taken.load(Ordering::SeqCst), 1);
+    let dirs = player.dirs();
+    assert_eq!(dirs.len(), 1);
+    assert_eq!(dirs[0].get_name(), "sub");
+    assert_eq!(dirs[0].fetch_data(Some("q")), vec!["from sub"]);
+}
@@ -0,0 +1,198 @@
+use libc::{EEXIST, EIO, EPERM, EROFS, EXDEV};
+use std::ffi::OsStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fs, thread};
+
+use file_node::ServiceConfig;
+use file_store::content::DirSpill;
+use file_store::fstore::FileStore;
+use vfs_service::harness::Harness;
+use vfs_service::services::{FnService, StaticService};
+
+mod common;
+use common::scratch;
+
+fn harness() -> Harness {
+    let svc = StaticService::new("planets").with("mars", "red");
+    let mut h = Harness::new(vec![Box::new(svc)]);
+    h.fs.enable_snapshots();
+    h
+}
+
+#[test]
+fn snapshots_keep_the_tree_as_it_was() {
+    let mut h = harness();
+    h.mkdir("/scratch", 0o755).unwrap();
+    h.write("/scratch/notes", b"first").unwrap();
+    h.create("/planets/mars", 0o644).unwrap();
+    let used = h.statfs().used_bytes;
+
+    h.mkdir("/.snapshots/before", 0o755).unwrap();
+    assert_eq!(h.statfs().used_bytes, used);
+    h.write("/scratch/notes", b"second").unwrap();
+
+    assert_eq!(
+        h.read("/.snapshots/before/scratch/notes").unwrap(),
+        b"first"
+    );
+    assert_eq!(h.read("/.snapshots/before/planets/mars").unwrap(), b"red");
+    assert_eq!(h.read("/scratch/notes").unwrap(), b"second");
+    let mut names = h.readdir("/.snapshots/before").unwrap();
+    names.sort();
+    assert_eq!(names, vec!["planets", "scratch"]);
+
+    // rolling back is a copy out of the snapshot
+    let old = h.read("/.snapshots/before/scratch/notes").unwrap();
+    h.write("/scratch/notes", &old).unwrap();
+    assert_eq!(h.read("/scratch/notes").unwrap(), b"first");
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+#[test]
+fn snapshots_are_read_only() {
+    let mut h = harness();
+    h.write("/f", b"data").unwrap();
+    h.mkdir("/.snapshots/s", 0o755).unwrap();
+
+    assert_eq!(h.write("/.snapshots/s/f", b"changed"), Err(EROFS));
+    assert_eq!(h.truncate("/.snapshots/s/f", 0).err(), Some(EROFS));
+    assert_eq!(h.unlink("/.snapshots/s/f"), Err(EROFS));
+    assert_eq!(h.mkdir("/.snapshots/s/d", 0o755).err(), Some(EROFS));
+    assert_eq!(h.rename("/.snapshots/s/f", "/g"), Err(EROFS));
+    assert_eq!(h.rename("/f", "/.snapshots/s/g"), Err(EROFS));
+    assert_eq!(h.link("/.snapshots/s/f", "/g").err(), Some(EXDEV));
+    assert_eq!(h.create("/.snapshots/f", 0o644).err(), Some(EROFS));
+    assert_eq!(h.mkdir("/.snapshots/s", 0o755).err(), Some(EEXIST));
+    assert_eq!(h.read("/.snapshots/s/f").unwrap(), b"data");
+}
+
+#[test]
+fn read_only_mounts_take_no_snapshots() {
+    let mut h = harness();
+    h.mkdir("/.snapshots/s", 0o755).unwrap();
+    h.fs.set_read_only(true);
+
+    assert_eq!(h.mkdir("/.snapshots/t", 0o755).err(), Some(EROFS));
+    assert_eq!(h.rmdir("/.snapshots/s"), Err(EROFS));
+    assert_eq!(h.readdir("/.snapshots").unwrap(), vec!["s"]);
+}
+
+#[test]
+fn the_snapshots_dir_stays_put() {
+    let mut h = harness();
+    h.mkdir("/.snapshots/s", 0o755).unwrap();
+    h.rmdir("/.snapshots/s").unwrap();
+
+    assert_eq!(h.rmdir("/.snapshots"), Err(EPERM));
+    assert_eq!(h.rename("/.snapshots", "/old"), Err(EPERM));
+    h.mkdir("/.snapshots/t", 0o755).unwrap();
+    assert_eq!(h.readdir("/.snapshots").unwrap(), vec!["t"]);
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+#[test]
+fn removing_a_snapshot_frees_what_only_it_held() {
+    let mut h = harness();
+    h.write("/f", &[1; 600]).unwrap();
+    h.mkdir("/.snapshots/s", 0o755).unwrap();
+    h.unlink("/f").unwrap();
+    assert_eq!(h.statfs().used_bytes, 600);
+
+    h.rmdir("/.snapshots/s").unwrap();
+    assert_eq!(h.statfs().used_bytes, 0);
+    assert!(h.readdir("/.snapshots").unwrap().is_empty());
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+#[test]
+fn hard_links_stay_linked_in_a_snapshot() {
+    let mut store = FileStore::new();
+    let f = store.touch_file(&1, OsStr::new("f"), 0, 0, 0o644).unwrap();
+    store.link(&f, &1, OsStr::new("g")).unwrap();
+
+    store.snapshot(Some(OsStr::new("s"))).unwrap();
+    let copy = store.resolve("/.snapshots/s/f").unwrap();
+    assert_ne!(copy, f);
+    assert_eq!(store.resolve("/.snapshots/s/g"), Ok(copy));
+    assert_eq!(store.get(&copy).unwrap().attr.nlink, 2);
+    assert_eq!(store.check_invariants(), Ok(()));
+}
+
+#[test]
+fn pruning_keeps_the_newest() {
+    let mut store = FileStore::new();
+    for _ in 0..3 {
+        store.snapshot(None).unwrap();
+    }
+    let names = |store: &FileStore| {
+        let dir = store.snapshots_dir().unwrap();
+        store.dir_entries(&dir).unwrap().len()
+    };
+    assert_eq!(names(&store), 3);
+
+    store.prune_snapshots(1).unwrap();
+    assert_eq!(names(&store), 1);
+    assert_eq!(store.check_invariants(), Ok(()));
+}
+
+#[test]
+fn scheduled_snapshots_appear() {
+    let mut h = Harness::new(vec![]);
+    h.fs.schedule_snapshots(Duration::from_millis(20), Some(2));
+    thread::sleep(Duration::from_millis(200));
+
+    let snapshots = h.readdir("/.snapshots").unwrap();
+    assert_eq!(snapshots.len(), 2);
+    assert_eq!(h.check_invariants(), Ok(()));
+}
+
+#[test]
+fn failed_snapshots_leave_nothing_behind() {
+    let dir = scratch("snapfail");
+    let mut store = FileStore::new();
+    store.set_spill(Box::new(DirSpill::new(&dir).unwrap()));
+    store.set_memory_budget(8);
+    let f = store.touch_file(&1, OsStr::new("f"), 0, 0, 0o644).unwrap();
+    store.write(f, b"sixteen bytes!!!", 0, 0).unwrap();
+    store.snapshot(Some(OsStr::new("s"))).unwrap();
+    let used = store.used_bytes();
+
+    // the spilled bytes are gone, so there is nothing to copy
+    fs::remove_file(dir.join(f.to_string())).unwrap();
+    assert_eq!(store.snapshot(Some(OsStr::new("t"))), Err(EIO));
+    let snapshots = store.snapshots_dir().unwrap();
+    assert_eq!(store.dir_entries(&snapshots).unwrap().len(), 1);
+    assert_eq!(store.used_bytes(), used);
+    assert_eq!(store.check_invariants(), Ok(()));
+}
+
+#[test]
+fn dropped_service_content_is_kept_as_it_was_for_a_snapshot() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let answer = Arc::new(Mutex::new("before"));
+    let now = answer.clone();
+    let svc = FnService::new("echo", move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        vec![now.lock().unwrap().to_string()]
+    });
+    let mut store = FileStore::new();
+    store.register_service(Box::new(svc), ServiceConfig::default());
+    store.set_memory_budget(0);
+    let echo = store.resolve("/echo").unwrap();
+    store
+        .add_fetched(&echo, OsStr::new("q"), b"before".to_vec(), 0, 0, 0o644)
+        .unwrap();
+    assert!(!store.is_resident(&store.resolve("/echo/q").unwrap()));
+
+    // the dropped bytes are fetched once to be kept, and never after
+    store.snapshot(Some(OsStr::new("s"))).unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    *answer.lock().unwrap() = "after";
+    let copy = store.resolve("/.snapshots/s/echo/q").unwrap();
+    assert_eq!(store.read_file(&copy).unwrap(), b"before");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(store.check_invariants(), Ok(()));
+}
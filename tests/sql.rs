@@ -0,0 +1,159 @@
+#![cfg(feature = "sql")]
+use std::path::{Path, PathBuf};
+
+use rusqlite::Connection;
+use vfs_service::harness::Harness;
+use vfs_service::sql::SqlService;
+use vfs_service::ServiceConfig;
+
+mod common;
+use common::{mount, scratch};
+
+// a database in dir
+fn people(dir: &Path) -> PathBuf {
+    let path = dir.join("people.db");
+    let conn = Connection::open(&path).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE users (name TEXT, city TEXT, age INTEGER);
+         INSERT INTO users VALUES ('ada', 'Oslo', 36), ('grace', 'Oslo', 85),
+                                  ('alan', 'Wilmslow, UK', 41);
+         CREATE TABLE pets (name TEXT);",
+    )
+    .unwrap();
+    path
+}
+
+fn sorted(mut names: Vec<String>) -> Vec<String> {
+    names.sort();
+    names
+}
+
+fn read_json(h: &mut Harness, path: &str) -> serde_json::Value {
+    serde_json::from_slice(&h.read(path).unwrap()).unwrap()
+}
+
+#[test]
+fn tables_are_dirs_of_rows() {
+    let dir = scratch("sql-tables");
+    let db = people(&dir);
+    let mut h = mount(SqlService::open("people", &db).unwrap());
+
+    assert_eq!(sorted(h.readdir("/people").unwrap()), vec!["pets", "users"]);
+    assert_eq!(
+        sorted(h.readdir("/people/users").unwrap()),
+        vec!["1.json", "2.json", "3.json"]
+    );
+    let ada = read_json(&mut h, "/people/users/1.json");
+    assert_eq!(ada.get("name").and_then(|v| v.as_str()), Some("ada"));
+    assert_eq!(ada.get("age").and_then(|v| v.as_i64()), Some(36));
+
+    h.create("/people/users/all.csv", 0o644).unwrap();
+    assert_eq!(
+        h.read("/people/users/all.csv").unwrap(),
+        b"name,city,age\nada,Oslo,36\ngrace,Oslo,85\nalan,\"Wilmslow, UK\",41"
+    );
+    h.create("/people/users/9.json", 0o644).unwrap();
+    assert_eq!(h.read("/people/users/9.json").unwrap(), b"");
+}
+
+#[test]
+fn big_tables_and_bad_names_stay_out_of_listings() {
+    let dir = scratch("sql-names");
+    let db = people(&dir);
+    let conn = Connection::open(&db).unwrap();
+    conn.execute_batch(
+        r#"CREATE TABLE "a/b" (x);
+           CREATE TABLE ".." (x);
+           CREATE TABLE ".history" (x);
+           CREATE TABLE log (n INTEGER);
+           WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 150)
+           INSERT INTO log SELECT i FROM n;"#,
+    )
+    .unwrap();
+    let mut h = mount(SqlService::open("people", &db).unwrap());
+
+    assert_eq!(
+        sorted(h.readdir("/people").unwrap()),
+        vec!["log", "pets", "users"]
+    );
+    // the dirs a service gives are listed in full, the files a few at a time
+    let mut listed = h.readdir("/people/log").unwrap();
+    while listed.len() < 100 {
+        listed = h.readdir("/people/log").unwrap();
+    }
+    assert_eq!(listed.len(), 100);
+    h.create("/people/log/150.json", 0o644).unwrap();
+    let row = read_json(&mut h, "/people/log/150.json");
+    assert_eq!(row.get("n").and_then(|v| v.as_i64()), Some(150));
+}
+
+#[test]
+fn named_queries_take_params_from_the_name() {
+    let dir = scratch("sql-queries");
+    let db = people(&dir);
+    let queries = db.with_extension("queries");
+    std::fs::write(
+        &queries,
+        "# who lives where\nby_city = SELECT name FROM users WHERE city = ?1 ORDER BY name\n",
+    )
+    .unwrap();
+    let svc = SqlService::open("people", &db)
+        .unwrap()
+        .queries_from(&queries)
+        .unwrap()
+        .query("oldest", "SELECT name, max(age) AS age FROM users")
+        .query("forget", "DELETE FROM users");
+    let mut h = mount(svc);
+
+    // only the queries without params are listed
+    let names = h.readdir("/people").unwrap();
+    assert!(names.contains(&"oldest.json".to_string()));
+    assert!(!names.iter().any(|name| name.starts_with("by_city")));
+    let oldest = read_json(&mut h, "/people/oldest.json");
+    assert_eq!(oldest.as_array().map(Vec::len), Some(1));
+
+    h.create("/people/by_city:Oslo.csv", 0o644).unwrap();
+    assert_eq!(
+        h.read("/people/by_city:Oslo.csv").unwrap(),
+        b"name\nada\ngrace"
+    );
+
+    // the database is only read, so listing forget did nothing
+    assert_eq!(h.read("/people/forget.json").unwrap(), b"");
+    h.create("/people/users/all.csv", 0o644).unwrap();
+    assert_eq!(
+        h.read("/people/users/all.csv")
+            .unwrap()
+            .split(|&b| b == b'\n')
+            .count(),
+        4
+    );
+
+    std::fs::write(&queries, "not a query\n").unwrap();
+    assert!(SqlService::open("people", &db)
+        .unwrap()
+        .queries_from(&queries)
+        .is_err());
+}
+
+#[test]
+fn databases_come_and_go_with_their_tables() {
+    assert!(SqlService::open("missing", "/no/such/dir/missing.db").is_err());
+
+    let dir = scratch("sql-remove");
+    let db = people(&dir);
+    let mut h = Harness::new(vec![]);
+    h.fs.services()
+        .add(
+            Box::new(SqlService::open("people", &db).unwrap()),
+            ServiceConfig::default(),
+        )
+        .unwrap();
+    h.read("/people/users/2.json").unwrap_err();
+    h.readdir("/people/users").unwrap();
+    assert!(!h.read("/people/users/2.json").unwrap().is_empty());
+
+    h.fs.services().remove("people").unwrap();
+    assert!(h.lookup("/people").is_err());
+    h.check_invariants().unwrap();
+}
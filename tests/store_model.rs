@@ -0,0 +1,400 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+
+use file_node::{gen_file_node, NodeData};
+use file_store::fstore::FileStore;
+use libc::{c_int, EEXIST, EINVAL, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, EPERM};
+use proptest::prelude::*;
+
+const NAMES: &[&str] = &["a", "b", "c"];
+
+// the reference model: every path ("" is root) and what lives there. files
+// are indices into contents so hard links can share them
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Node {
+    Dir,
+    File(usize),
+}
+
+#[derive(Debug, Default)]
+struct Model {
+    tree: BTreeMap<String, Node>,
+    contents: Vec<Vec<u8>>,
+}
+
+// how a path looks from outside: a dir, or a file's content plus every path
+// naming the same file
+#[derive(Debug, PartialEq)]
+enum Seen {
+    Dir,
+    File(Vec<u8>, Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Mkdir(String, String),
+    Create(String, String),
+    Write(String, u8, Vec<u8>),
+    Rename(String, String, String),
+    Link(String, String, String),
+    Unlink(String),
+    Rmdir(String),
+    Clear(String),
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+fn split(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    }
+}
+
+fn is_within(path: &str, dir: &str) -> bool {
+    path == dir || path.starts_with(&format!("{}/", dir))
+}
+
+impl Model {
+    fn new() -> Model {
+        let mut model = Model::default();
+        model.tree.insert(String::new(), Node::Dir);
+        model
+    }
+
+    // mirrors FileStore::resolve
+    fn resolve(&self, path: &str) -> Result<Node, c_int> {
+        let (mut node, mut cur) = (Node::Dir, String::new());
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if let Node::File(_) = node {
+                return Err(ENOTDIR);
+            }
+            cur = join(&cur, name);
+            node = *self.tree.get(&cur).ok_or(ENOENT)?;
+        }
+
+        Ok(node)
+    }
+
+    fn add(&mut self, dir: &str, name: &str, node: Node) -> Result<(), c_int> {
+        if let Node::File(_) = self.resolve(dir)? {
+            return Err(ENOTDIR);
+        }
+        let path = join(dir, name);
+        if self.tree.contains_key(&path) {
+            return Err(EEXIST);
+        }
+
+        self.tree.insert(path, node);
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str, dir: bool) -> Result<(), c_int> {
+        self.resolve(split(path).0)?;
+        match self.tree.get(path) {
+            Some(Node::Dir) if !dir => return Err(EISDIR),
+            Some(Node::Dir) if self.tree.keys().any(|p| p != path && is_within(p, path)) => {
+                return Err(ENOTEMPTY)
+            }
+            Some(Node::File(_)) if dir => return Err(ENOTDIR),
+            Some(_) => (),
+            None => return Err(ENOENT),
+        }
+
+        self.tree.remove(path);
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to_dir: &str, to_name: &str) -> Result<(), c_int> {
+        self.resolve(split(from).0)?;
+        let dest = self.resolve(to_dir)?;
+        let node = *self.tree.get(from).ok_or(ENOENT)?;
+        if let Node::File(_) = dest {
+            return Err(ENOTDIR);
+        }
+        if is_within(to_dir, from) {
+            return Err(EINVAL);
+        }
+
+        let to = join(to_dir, to_name);
+        match (node, self.tree.get(&to).copied()) {
+            // two names for the same file: nothing to do
+            _ if to == from => return Ok(()),
+            (Node::File(a), Some(Node::File(b))) if a == b => return Ok(()),
+            (Node::Dir, Some(Node::File(_))) => return Err(ENOTDIR),
+            (Node::File(_), Some(Node::Dir)) => return Err(EISDIR),
+            (_, Some(_)) => self.remove(&to, node == Node::Dir)?,
+            (_, None) => (),
+        }
+
+        let moved: Vec<String> = self
+            .tree
+            .keys()
+            .filter(|p| is_within(p, from))
+            .cloned()
+            .collect();
+        for path in moved {
+            let node = self.tree.remove(&path).unwrap();
+            self.tree
+                .insert(format!("{}{}", to, &path[from.len()..]), node);
+        }
+        Ok(())
+    }
+
+    fn link(&mut self, from: &str, to_dir: &str, to_name: &str) -> Result<(), c_int> {
+        let node = self.resolve(from)?;
+        self.resolve(to_dir)?;
+        if node == Node::Dir {
+            return Err(EPERM);
+        }
+
+        self.add(to_dir, to_name, node)
+    }
+
+    fn apply(&mut self, op: &Op) -> Result<(), c_int> {
+        match op {
+            Op::Mkdir(dir, name) => self.add(dir, name, Node::Dir),
+            Op::Create(dir, name) => {
+                self.add(dir, name, Node::File(self.contents.len()))?;
+                self.contents.push(vec![]);
+                Ok(())
+            }
+            Op::Write(path, offset, data) => match self.resolve(path)? {
+                Node::File(id) => {
+                    let content = &mut self.contents[id];
+                    let end = *offset as usize + data.len();
+                    if content.len() < end {
+                        content.resize(end, 0);
+                    }
+                    content[*offset as usize..end].copy_from_slice(data);
+                    Ok(())
+                }
+                Node::Dir => Err(EISDIR),
+            },
+            Op::Rename(from, to_dir, to_name) => self.rename(from, to_dir, to_name),
+            Op::Link(from, to_dir, to_name) => self.link(from, to_dir, to_name),
+            Op::Unlink(path) => self.remove(path, false),
+            Op::Rmdir(path) => self.remove(path, true),
+            Op::Clear(path) => {
+                if let Node::File(id) = self.resolve(path)? {
+                    self.contents[id].clear();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn seen(&self) -> BTreeMap<String, Seen> {
+        let files: Vec<(String, usize)> = self
+            .tree
+            .iter()
+            .filter_map(|(path, node)| match node {
+                Node::File(id) => Some((path.clone(), *id)),
+                Node::Dir => None,
+            })
+            .collect();
+
+        self.tree
+            .iter()
+            .map(|(path, node)| match node {
+                Node::Dir => (path.clone(), Seen::Dir),
+                Node::File(id) => {
+                    let names = files.iter().filter(|(_, f)| f == id);
+                    let names = names.map(|(p, _)| p.clone()).collect();
+                    (path.clone(), Seen::File(self.contents[*id].clone(), names))
+                }
+            })
+            .collect()
+    }
+}
+
+fn store_apply(store: &mut FileStore, op: &Op) -> Result<(), c_int> {
+    match op {
+        Op::Mkdir(dir, name) => {
+            let parent = store.resolve(dir)?;
+            store.create_dir(parent, OsStr::new(name), 0o755, 0, 0)?;
+        }
+        Op::Create(dir, name) => {
+            let parent = store.resolve(dir)?;
+            store.add_child(&parent, gen_file_node(), OsStr::new(name), 0, 0, 0o644)?;
+        }
+        Op::Write(path, offset, data) => {
+            let ino = store.resolve(path)?;
+            store.write(ino, data, 0, *offset as i64)?;
+        }
+        Op::Rename(from, to_dir, to_name) => {
+            let (dir, name) = split(from);
+            let parent = store.resolve(dir)?;
+            let newparent = store.resolve(to_dir)?;
+            store.rename(&parent, OsStr::new(name), newparent, OsStr::new(to_name))?;
+        }
+        Op::Link(from, to_dir, to_name) => {
+            let ino = store.resolve(from)?;
+            let newparent = store.resolve(to_dir)?;
+            store.link(&ino, &newparent, OsStr::new(to_name))?;
+        }
+        Op::Unlink(path) => {
+            let (dir, name) = split(path);
+            let parent = store.resolve(dir)?;
+            store.unlink(&parent, OsStr::new(name))?;
+        }
+        Op::Rmdir(path) => {
+            let (dir, name) = split(path);
+            let parent = store.resolve(dir)?;
+            store.rmdir(&parent, OsStr::new(name))?;
+        }
+        Op::Clear(path) => {
+            let ino = store.resolve(path)?;
+            store.clear_file(&ino);
+        }
+    }
+
+    Ok(())
+}
+
+// every path reachable from root, with the ino it names
+fn walk(store: &FileStore, ino: u64, path: &str, out: &mut BTreeMap<String, u64>) {
+    out.insert(path.to_string(), ino);
+    if let Some(entries) = store.dir_entries(&ino) {
+        for (child, name) in entries {
+            walk(store, child, &join(path, &name.to_string_lossy()), out);
+        }
+    }
+}
+
+fn seen(store: &FileStore) -> BTreeMap<String, Seen> {
+    let mut paths = BTreeMap::new();
+    walk(store, fuse::FUSE_ROOT_ID, "", &mut paths);
+
+    paths
+        .iter()
+        .map(
+            |(path, ino)| match &store.get(ino).expect("dangling child").data {
+                NodeData::File(_) => {
+                    let names = paths.iter().filter(|(_, other)| *other == ino);
+                    let names = names.map(|(p, _)| p.clone()).collect();
+                    (
+                        path.clone(),
+                        Seen::File(store.read_file(ino).expect("file without content"), names),
+                    )
+                }
+                _ => (path.clone(), Seen::Dir),
+            },
+        )
+        .collect()
+}
+
+fn name() -> impl Strategy<Value = String> {
+    prop::sample::select(NAMES).prop_map(|name| name.to_string())
+}
+
+fn path(min: usize) -> impl Strategy<Value = String> {
+    prop::collection::vec(name(), min..3).prop_map(|names| names.join("/"))
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (path(0), name()).prop_map(|(dir, name)| Op::Mkdir(dir, name)),
+        (path(0), name()).prop_map(|(dir, name)| Op::Create(dir, name)),
+        (path(0), 0u8..8, prop::collection::vec(any::<u8>(), 0..8))
+            .prop_map(|(path, offset, data)| Op::Write(path, offset, data)),
+        (path(1), path(0), name()).prop_map(|(from, dir, name)| Op::Rename(from, dir, name)),
+        (path(1), path(0), name()).prop_map(|(from, dir, name)| Op::Link(from, dir, name)),
+        path(1).prop_map(Op::Unlink),
+        path(1).prop_map(Op::Rmdir),
+        path(0).prop_map(Op::Clear),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn store_matches_model(ops in prop::collection::vec(op(), 1..60), reuse in any::<bool>()) {
+        let mut store = FileStore::new();
+        store.set_ino_reuse(reuse);
+        let mut model = Model::new();
+
+        for op in &ops {
+            let expected = model.apply(op);
+            let got = store_apply(&mut store, op);
+            prop_assert_eq!(got, expected, "{:?}", op);
+
+            if let Err(problems) = store.check_invariants() {
+                panic!("after {:?}: {:?}", op, problems);
+            }
+            prop_assert_eq!(seen(&store), model.seen(), "{:?}", op);
+        }
+    }
+}
+
+#[test]
+fn rename_into_a_file_keeps_the_node() {
+    let mut store = FileStore::new();
+    let root = fuse::FUSE_ROOT_ID;
+    let f = store
+        .add_child(&root, gen_file_node(), OsStr::new("f"), 0, 0, 0o644)
+        .unwrap();
+    store
+        .add_child(&root, gen_file_node(), OsStr::new("g"), 0, 0, 0o644)
+        .unwrap();
+
+    assert_eq!(
+        store.rename(&root, OsStr::new("g"), f, OsStr::new("g")),
+        Err(ENOTDIR)
+    );
+    assert!(store.resolve("/g").is_ok());
+    assert_eq!(store.check_invariants(), Ok(()));
+}
+
+#[test]
+fn paths_resolve_both_ways() {
+    let mut store = FileStore::new();
+    let a = store
+        .create_dir(1, OsStr::new("a"), 0o755, 0, 0)
+        .unwrap()
+        .id;
+    let b = store
+        .create_dir(a, OsStr::new("b"), 0o755, 0, 0)
+        .unwrap()
+        .id;
+    let c = store
+        .add_child(&b, gen_file_node(), OsStr::new("c"), 0, 0, 0o644)
+        .unwrap();
+
+    assert_eq!(store.resolve("/a/b/c"), Ok(c));
+    assert_eq!(store.resolve("/a/b/../b/./c"), Ok(c));
+    assert_eq!(store.resolve("/a/b/c/d"), Err(ENOTDIR));
+    assert_eq!(store.resolve("/a/x"), Err(ENOENT));
+    assert_eq!(store.full_path(&c), Some("/a/b/c".into()));
+    assert_eq!(store.full_path(&1), Some("/".into()));
+
+    store
+        .rename(&b, OsStr::new("c"), a, OsStr::new("d"))
+        .unwrap();
+    assert_eq!(store.full_path(&c), Some("/a/d".into()));
+}
+
+#[test]
+fn check_invariants_reports_dangling_and_orphaned_inodes() {
+    let mut store = FileStore::new();
+    let root = fuse::FUSE_ROOT_ID;
+    let f = store
+        .add_child(&root, gen_file_node(), OsStr::new("f"), 0, 0, 0o644)
+        .unwrap();
+    let g = store
+        .add_child(&root, gen_file_node(), OsStr::new("g"), 0, 0, 0o644)
+        .unwrap();
+
+    // each half of an unlink on its own leaves the store inconsistent
+    store.remove(&f);
+    store.remove_child(&root, OsStr::new("g"));
+
+    let problems = store.check_invariants().unwrap_err();
+    assert!(problems.contains(&format!("dir {} has dangling child {}", root, f)));
+    assert!(problems.contains(&format!("orphaned inode {}", g)));
+}
@@ -0,0 +1,236 @@
+#![cfg(feature = "wasm")]
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use vfs_service::wasm::WasmService;
+use vfs_service::SingleService;
+
+mod common;
+use common::mount;
+
+// a bump allocator every module below starts with
+const ALLOC: &str = r#"
+  (memory (export "memory") 1)
+  (global $next (mut i32) (i32.const 1024))
+  (func (export "alloc") (param $len i32) (result i32)
+    (local $at i32)
+    (local.set $at (global.get $next))
+    (global.set $next (i32.add (global.get $next) (local.get $len)))
+    (local.get $at))
+"#;
+
+fn module(body: &str) -> Vec<u8> {
+    format!("(module {} {})", ALLOC, body).into_bytes()
+}
+
+// answers with the query itself
+fn echo() -> Vec<u8> {
+    module(
+        r#"
+        (func (export "fetch") (param $ptr i32) (param $len i32) (result i64)
+          (if (i32.lt_s (local.get $len) (i32.const 0))
+            (then (return (i64.const 0))))
+          (i64.or
+            (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+            (i64.extend_i32_u (local.get $len))))
+        "#,
+    )
+}
+
+// gets the query as a url, answering "denied" when it can't
+fn fetcher() -> Vec<u8> {
+    // imports have to come before everything else
+    format!(
+        r#"(module
+          (import "vfs" "http_get" (func $http_get (param i32 i32) (result i64)))
+          {}
+          (data (i32.const 0) "denied")
+          (func (export "fetch") (param $ptr i32) (param $len i32) (result i64)
+            (local $got i64)
+            (local.set $got (call $http_get (local.get $ptr) (local.get $len)))
+            (if (result i64) (i64.eq (local.get $got) (i64.const -1))
+              (then (i64.const 6))
+              (else (local.get $got)))))"#,
+        ALLOC
+    )
+    .into_bytes()
+}
+
+#[test]
+fn modules_serve_files() {
+    let svc = WasmService::new("echo", &echo()).unwrap();
+    assert_eq!(svc.fetch_data(None), Vec::<String>::new());
+
+    let mut h = mount(svc);
+    h.create("/echo/10002", 0o644).unwrap();
+    assert_eq!(h.read("/echo/10002").unwrap(), b"10002");
+}
+
+#[test]
+fn fuel_and_memory_are_limited() {
+    let spin = module(
+        r#"
+        (func (export "fetch") (param i32 i32) (result i64)
+          (loop $forever (br $forever))
+          (i64.const 0))
+        "#,
+    );
+    let svc = WasmService::new("spin", &spin)
+        .unwrap()
+        .with_limits(10_000, 1 << 20);
+    assert!(svc.fetch_data(Some("q")).is_empty());
+
+    // says so when it could grow by 64 pages, traps when it couldn't
+    let hungry = module(
+        r#"
+        (data (i32.const 0) "grew")
+        (func (export "fetch") (param i32 i32) (result i64)
+          (if (i32.lt_s (memory.grow (i32.const 64)) (i32.const 0))
+            (then unreachable))
+          (i64.const 4))
+        "#,
+    );
+    let svc = WasmService::new("hungry", &hungry).unwrap();
+    assert_eq!(svc.fetch_data(Some("q")), vec!["grew"]);
+    let svc = svc.with_limits(10_000, 1 << 20);
+    assert!(svc.fetch_data(Some("q")).is_empty());
+
+    // a fetch that went wrong leaves nothing behind for the next one
+    let svc = WasmService::new("echo", &echo())
+        .unwrap()
+        .with_limits(10_000, 1 << 20);
+    assert_eq!(svc.fetch_data(Some("q")), vec!["q"]);
+}
+
+#[test]
+fn http_takes_a_grant() {
+    let svc = WasmService::new("web", &fetcher()).unwrap();
+    assert_eq!(svc.fetch_data(Some("http://api.test/a")), vec!["denied"]);
+
+    let svc = svc.allow_http_with(&["http://api.test/"], |url| {
+        Ok(format!("got {}\nbye", url).into_bytes())
+    });
+    assert_eq!(
+        svc.fetch_data(Some("http://api.test/a")),
+        vec!["got http://api.test/a", "bye"]
+    );
+    assert_eq!(
+        svc.fetch_data(Some("http://elsewhere.test/")),
+        vec!["denied"]
+    );
+}
+
+#[test]
+fn grants_are_matched_by_url_not_by_text() {
+    let svc = WasmService::new("web", &fetcher())
+        .unwrap()
+        .allow_http_with(&["https://api.example.com/v1"], |url| {
+            Ok(format!("got {}", url).into_bytes())
+        });
+    let denied = vec!["denied"];
+    for url in [
+        "https://api.example.com.evil.net/v1/x",
+        "https://api.example.com@evil.net/v1/x",
+        "https://api.example.com:8443/v1/x",
+        "http://api.example.com/v1/x",
+        "https://api.example.com/v10",
+        "https://api.example.com/v1/../admin",
+    ] {
+        assert_eq!(svc.fetch_data(Some(url)), denied, "{}", url);
+    }
+
+    assert_eq!(
+        svc.fetch_data(Some("https://API.example.com:443/v1/x")),
+        vec!["got https://api.example.com/v1/x"]
+    );
+    assert_eq!(
+        svc.fetch_data(Some("https://api.example.com/v1")),
+        vec!["got https://api.example.com/v1"]
+    );
+}
+
+// answers every request on a local port, noting down the paths asked for.
+// paths starting /to are redirected to the rest of the path
+fn web_server() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let seen = asked.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut line = String::new();
+            let mut reader = BufReader::new(&stream);
+            reader.read_line(&mut line).unwrap();
+            let path = line.split(' ').nth(1).unwrap_or_default().to_string();
+            // the headers, up to the blank line ending them
+            line.clear();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            seen.lock().unwrap().push(path.clone());
+
+            let reply = match path.strip_prefix("/to") {
+                Some(to) => format!(
+                    "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    to
+                ),
+                None => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    path.len(),
+                    path
+                ),
+            };
+            stream.write_all(reply.as_bytes()).unwrap();
+        }
+    });
+
+    (base, asked)
+}
+
+#[test]
+fn redirects_are_only_followed_within_the_grants() {
+    let (base, asked) = web_server();
+    let svc = WasmService::new("web", &fetcher())
+        .unwrap()
+        .allow_http(&[&format!("{}/to", base), &format!("{}/ok", base)]);
+
+    assert_eq!(
+        svc.fetch_data(Some(&format!("{}/to/ok/there", base))),
+        vec!["/ok/there"]
+    );
+    assert_eq!(
+        svc.fetch_data(Some(&format!("{}/to/secret", base))),
+        vec!["denied"]
+    );
+    assert_eq!(
+        *asked.lock().unwrap(),
+        vec!["/to/ok/there", "/ok/there", "/to/secret"]
+    );
+}
+
+#[test]
+fn http_bodies_bigger_than_the_module_are_refused() {
+    let svc = WasmService::new("web", &fetcher())
+        .unwrap()
+        .with_limits(1_000_000, 1 << 20)
+        .allow_http_with(&["http://api.test/"], |_| Ok(vec![b'x'; 2 << 20]));
+    assert_eq!(svc.fetch_data(Some("http://api.test/big")), vec!["denied"]);
+}
+
+#[test]
+fn modules_get_nothing_else() {
+    let sneaky = format!(
+        r#"(module
+          (import "env" "system" (func $system (param i32 i32) (result i32)))
+          {}
+          (func (export "fetch") (param i32 i32) (result i64) (i64.const 0)))"#,
+        ALLOC
+    );
+    let svc = WasmService::new("sneaky", sneaky.as_bytes()).unwrap();
+    assert!(svc.fetch_data(Some("rm -rf /")).is_empty());
+
+    assert!(WasmService::new("broken", b"(module (func").is_err());
+}